@@ -22,11 +22,9 @@ async fn main() {
     let mut characteristics = vec![];
     loop {
         match state {
-            State::BluetoothNotReady => {
-                if adapter.is_ready() {
-                    adapter.start_scan().unwrap();
-                    state = State::Scan;
-                }
+            State::BluetoothNotReady if adapter.is_ready() => {
+                adapter.start_scan().unwrap();
+                state = State::Scan;
             }
             State::Connected(ref mut connection) => {
                 let mut done = false;
@@ -43,10 +41,19 @@ async fn main() {
                                 received_data.pop_back();
                             }
                         }
-                        Message::Disconnected => {
-                            info!("Disconnected!");
+                        Message::Disconnected(reason) => {
+                            info!("Disconnected: {:?}", reason);
                             done = true;
                         }
+                        Message::Reconnecting { attempt, reason } => {
+                            info!("Reconnecting, attempt {}: {}", attempt, reason);
+                        }
+                        Message::Reconnected => info!("Reconnected"),
+                        Message::ConnectFailed(err) => {
+                            info!("Connect failed: {}", err);
+                            done = true;
+                        }
+                        Message::Error(err) => info!("Error: {}", err),
                         _ => {}
                     }
                 }
@@ -63,7 +70,23 @@ async fn main() {
         clear_background(WHITE);
 
         match state {
-            State::BluetoothNotReady => root_ui().label(None, "Bluetooth initializing"),
+            State::BluetoothNotReady => {
+                root_ui().label(None, &format!("Bluetooth: {:?}", adapter.state()));
+                if adapter.permission_status() != bt::PermissionStatus::Granted
+                    && root_ui().button(None, "Grant permissions")
+                {
+                    if let Err(err) = adapter.request_permissions() {
+                        info!("Can't request permissions: {}", err);
+                    }
+                }
+                if adapter.state() == bt::AdapterState::PoweredOff
+                    && root_ui().button(None, "Enable Bluetooth")
+                {
+                    if let Err(err) = adapter.request_enable() {
+                        info!("Can't request enabling Bluetooth: {}", err);
+                    }
+                }
+            }
             State::Scan => {
                 root_ui().label(None, "Devices:");
 
@@ -72,7 +95,7 @@ async fn main() {
                     .walk_devices(|device| {
                         if widgets::Button::new(format!("{:?} {:?}", device.address, device.name))
                             .size(vec2(400., 50.))
-                            .ui(&mut *root_ui())
+                            .ui(&mut root_ui())
                         {
                             device_id = Some(device.id());
                         }
@@ -80,33 +103,28 @@ async fn main() {
                     .unwrap();
 
                 if let Some(device_id) = device_id {
-                    let connection = adapter.connect(device_id.clone()).unwrap();
+                    let mut connection = adapter.connect(device_id.clone()).unwrap();
+                    connection.set_reconnect_policy(Some(bt::ReconnectPolicy::default()));
                     state = State::Connected(connection);
                 }
             }
             State::Connected(ref mut connection) => {
                 for characteristic in &characteristics {
-                    widgets::Label::new(format!("{:?}", &characteristic.id)).ui(&mut *root_ui());
+                    widgets::Label::new(format!("{:?}", &characteristic.id)).ui(&mut root_ui());
 
-                    widgets::Label::new(format!(
-                        "write: {:?}, read: {:?}, notify: {:?}, indicate: {:?}",
-                        characteristic.write,
-                        characteristic.read,
-                        characteristic.notify,
-                        characteristic.indicate
-                    ))
-                    .ui(&mut *root_ui());
+                    widgets::Label::new(format!("{:?}", characteristic.properties))
+                        .ui(&mut root_ui());
 
                     if widgets::Button::new("write 1")
                         .size(vec2(100., 50.))
-                        .ui(&mut *root_ui())
+                        .ui(&mut root_ui())
                     {
                         characteristic.send_bytes(&[0x01], true).unwrap();
                     }
                     root_ui().same_line(110.);
                     if widgets::Button::new("write Uxx")
                         .size(vec2(100., 50.))
-                        .ui(&mut *root_ui())
+                        .ui(&mut root_ui())
                     {
                         characteristic.send_string("Uxx").unwrap();
                     }
@@ -114,22 +132,26 @@ async fn main() {
 
                     if widgets::Button::new("notify")
                         .size(vec2(100., 50.))
-                        .ui(&mut *root_ui())
+                        .ui(&mut root_ui())
                     {
-                        characteristic.set_notification(true).unwrap();
+                        if let Err(err) = characteristic.set_notification(true) {
+                            info!("set_notification: {:?}", err);
+                        }
                     }
                     root_ui().same_line(330.);
                     if widgets::Button::new("indicate")
                         .size(vec2(100., 50.))
-                        .ui(&mut *root_ui())
+                        .ui(&mut root_ui())
                     {
-                        characteristic.set_indication(true).unwrap();
+                        if let Err(err) = characteristic.set_indication(true) {
+                            info!("set_indication: {:?}", err);
+                        }
                     }
                 }
                 if widgets::Button::new("disconnect")
                     .position(vec2(screen_width() - 200., screen_height() - 50.))
                     .size(vec2(200., 50.))
-                    .ui(&mut *root_ui())
+                    .ui(&mut root_ui())
                 {
                     connection.disconnect().unwrap();
                 }
@@ -139,7 +161,7 @@ async fn main() {
         for (n, data) in received_data.iter().enumerate() {
             widgets::Label::new(format!("{:?}", data))
                 .position(vec2(450., n as f32 * 20.))
-                .ui(&mut *root_ui());
+                .ui(&mut root_ui());
         }
         next_frame().await;
     }
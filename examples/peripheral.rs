@@ -0,0 +1,92 @@
+//! Act as a GATT server: advertise a counter characteristic and notify subscribed centrals.
+
+use macroquad::{
+    prelude::*,
+    ui::{root_ui, widgets},
+};
+use quad_bt::{self as bt, ServerEvent};
+use std::collections::VecDeque;
+
+const SERVICE_UUID: &str = "6e400001-b5a3-f393-e0a9-e50e24dcca9e";
+const COUNTER_UUID: &str = "6e400003-b5a3-f393-e0a9-e50e24dcca9e";
+const COMMAND_UUID: &str = "6e400002-b5a3-f393-e0a9-e50e24dcca9e";
+
+#[macroquad::main("BT Peripheral")]
+async fn main() {
+    let mut adapter = bt::Adapter::new().unwrap();
+    let mut server = None;
+    let mut counter = 0u8;
+    let mut log = VecDeque::new();
+
+    loop {
+        if server.is_none() && adapter.is_ready() {
+            let services = vec![bt::LocalService::new(
+                SERVICE_UUID,
+                vec![
+                    bt::LocalCharacteristic {
+                        read: true,
+                        notify: true,
+                        permissions: bt::Permissions::READ,
+                        value: vec![counter],
+                        ..bt::LocalCharacteristic::new(COUNTER_UUID)
+                    },
+                    bt::LocalCharacteristic {
+                        write: true,
+                        write_without_response: true,
+                        permissions: bt::Permissions::WRITE,
+                        ..bt::LocalCharacteristic::new(COMMAND_UUID)
+                    },
+                ],
+            )];
+            let mut new_server = adapter.open_gatt_server(services).unwrap();
+            new_server.start_advertising().unwrap();
+            server = Some(new_server);
+        }
+
+        if let Some(ref mut server) = server {
+            while let Ok(Some(event)) = server.try_recv() {
+                info!("Server event: {:?}", event);
+                if let ServerEvent::Written { value, .. } = &event {
+                    log.push_front(format!("written: {:?}", value));
+                } else {
+                    log.push_front(format!("{:?}", event));
+                }
+                if log.len() > 20 {
+                    log.pop_back();
+                }
+            }
+        }
+
+        clear_background(WHITE);
+
+        match server {
+            None => {
+                root_ui().label(None, &format!("Bluetooth: {:?}", adapter.state()));
+                if adapter.state() == bt::AdapterState::PoweredOff
+                    && root_ui().button(None, "Enable Bluetooth")
+                {
+                    if let Err(err) = adapter.request_enable() {
+                        info!("Can't request enabling Bluetooth: {}", err);
+                    }
+                }
+            }
+            Some(ref mut server) => {
+                root_ui().label(None, &format!("Counter: {}", counter));
+                if widgets::Button::new("increment and notify")
+                    .size(vec2(200., 50.))
+                    .ui(&mut root_ui())
+                {
+                    counter = counter.wrapping_add(1);
+                    server.notify(COUNTER_UUID, &[counter]).unwrap();
+                }
+            }
+        }
+
+        for (n, line) in log.iter().enumerate() {
+            widgets::Label::new(line.as_str())
+                .position(vec2(450., n as f32 * 20.))
+                .ui(&mut root_ui());
+        }
+        next_frame().await;
+    }
+}
@@ -8,6 +8,7 @@ import android.content.ComponentName;
 import android.content.ServiceConnection;
 import android.bluetooth.BluetoothManager;
 import android.bluetooth.BluetoothAdapter;
+import android.bluetooth.BluetoothDevice;
 import android.bluetooth.BluetoothGattCharacteristic;
 import android.os.IBinder;
 import android.widget.TextView;
@@ -24,12 +25,14 @@ import java.util.TimerTask;
 
 import quadbt.BluetoothLeService;
 import quadbt.QuadBT;
+import quadbt.QuadBTPermissions;
 
 //% END
 
 //% MAIN_ACTIVITY_BODY
 
 public static BluetoothLeService bluetoothService;
+private QuadBTPermissions permissions;
 
 // service can be binded only after receiving permission
 // BUT pause can arrive before that
@@ -43,24 +46,24 @@ private static IntentFilter makeGattUpdateIntentFilter() {
     intentFilter.addAction(BluetoothLeService.ACTION_GATT_DISCONNECTED);
     intentFilter.addAction(BluetoothLeService.ACTION_GATT_SERVICES_DISCOVERED);
     intentFilter.addAction(BluetoothLeService.ACTION_DATA_AVAILABLE);
+    intentFilter.addAction(BluetoothAdapter.ACTION_STATE_CHANGED);
+    intentFilter.addAction(BluetoothDevice.ACTION_BOND_STATE_CHANGED);
     return intentFilter;
 }
 
-private boolean checkPermissions() {
-    return ContextCompat.checkSelfPermission(this, Manifest.permission.ACCESS_FINE_LOCATION) == PackageManager.PERMISSION_GRANTED;
-}
-
 private void bindBluetoothService() {
     BluetoothManager bluetoothManager = (BluetoothManager)this.getSystemService(Context.BLUETOOTH_SERVICE);
     BluetoothAdapter bluetoothAdapter = bluetoothManager.getAdapter();
+    if (bluetoothAdapter == null) {
+        QuadBT.onAdapterStateChanged(QuadBT.STATE_UNSUPPORTED);
+        return;
+    }
 
+    QuadBT.activity = this;
     QuadBT.bluetoothAdapter = bluetoothAdapter;
     QuadBT.bluetoothLeScanner = bluetoothAdapter.getBluetoothLeScanner();
 
-    if (!bluetoothAdapter.isEnabled()) {
-        Intent enableBtIntent = new Intent(BluetoothAdapter.ACTION_REQUEST_ENABLE);
-        startActivityForResult(enableBtIntent, 2);
-    } 
+    // turning Bluetooth on is asked for by Adapter::request_enable
     registerReceiver(gattUpdateReceiver, makeGattUpdateIntentFilter());
     
     Intent gattServiceIntent = new Intent(this, BluetoothLeService.class);
@@ -72,23 +75,20 @@ public void onRequestPermissionsResult(
         int requestCode,
         String permissions[],
         int[] grantResults) {
-    if (requestCode == 1) {
-        if (grantResults.length > 0 && grantResults[0] == PackageManager.PERMISSION_GRANTED) {
+    if (requestCode == QuadBTPermissions.REQUEST_CODE) {
+        QuadBT.onPermissionsResult();
+        if (permissions.granted()) {
             bindBluetoothService();
         }
     }
 }
 
-// @Override
-// protected void onActivityResult(int requestCode, int resultCode, Intent data) {
-//      if (requestCode == 2) {
-//          // bluetooth enabled
-//      }
-//  }
-
-private void requestPermissions() {
-    ActivityCompat.requestPermissions(this, new String[]{
-            Manifest.permission.ACCESS_FINE_LOCATION}, 1);
+@Override
+protected void onActivityResult(int requestCode, int resultCode, Intent data) {
+    super.onActivityResult(requestCode, resultCode, data);
+    if (requestCode == QuadBT.REQUEST_ENABLE) {
+        QuadBT.onEnableResult(resultCode == RESULT_OK);
+    }
 }
 
 private ServiceConnection serviceConnection = new ServiceConnection() {
@@ -122,12 +122,17 @@ private final BroadcastReceiver gattUpdateReceiver = new BroadcastReceiver() {
             if (BluetoothLeService.ACTION_GATT_CONNECTED.equals(action)) {
                 QuadBT.onGattConnected();
             } else if (BluetoothLeService.ACTION_GATT_DISCONNECTED.equals(action)) {
-                QuadBT.onGattDisconnected();
+                QuadBT.onGattDisconnected(intent.getIntExtra(BluetoothLeService.EXTRA_STATUS, 0));
             } else if (BluetoothLeService.ACTION_GATT_SERVICES_DISCOVERED.equals(action)) {
                 QuadBT.servicesDiscovered(bluetoothService.getSupportedGattServices());
             } else if (BluetoothLeService.ACTION_DATA_AVAILABLE.equals(action)) {
                 byte[] data = intent.getByteArrayExtra(BluetoothLeService.EXTRA_DATA);
                 QuadBT.onDataAvailable(data);
+            } else if (BluetoothAdapter.ACTION_STATE_CHANGED.equals(action)) {
+                QuadBT.onAdapterStateChanged(intent.getIntExtra(BluetoothAdapter.EXTRA_STATE, BluetoothAdapter.ERROR));
+            } else if (BluetoothDevice.ACTION_BOND_STATE_CHANGED.equals(action)) {
+                BluetoothDevice device = intent.getParcelableExtra(BluetoothDevice.EXTRA_DEVICE);
+                QuadBT.onBondStateChanged(device.getAddress(), intent.getIntExtra(BluetoothDevice.EXTRA_BOND_STATE, BluetoothDevice.BOND_NONE));
             }
         }
     };
@@ -137,10 +142,11 @@ private final BroadcastReceiver gattUpdateReceiver = new BroadcastReceiver() {
 
 //% MAIN_ACTIVITY_ON_RESUME
 
-if (!checkPermissions()) {
-    requestPermissions();
-} else {
+// the permissions are asked for by Adapter::request_permissions
+if (permissions.granted()) {
     bindBluetoothService();
+} else {
+    QuadBT.onAdapterStateChanged(QuadBT.STATE_UNAUTHORIZED);
 }
 
 //% END
@@ -155,4 +161,7 @@ if (serviceBinded) {
 
 //% MAIN_ACTIVITY_ON_CREATE
 
+permissions = new QuadBTPermissions(this);
+QuadBT.onPermissionsCreated(permissions);
+
 //% END
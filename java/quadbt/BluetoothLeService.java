@@ -19,6 +19,7 @@ import android.os.Binder;
 import android.os.IBinder;
 import android.util.Log;
 
+import java.util.Arrays;
 import java.util.List;
 import java.util.UUID;
 
@@ -46,10 +47,15 @@ public class BluetoothLeService extends Service {
             "quadbt.ACTION_DATA_AVAILABLE";
     public final static String EXTRA_DATA =
             "quadbt.EXTRA_DATA";
+    public final static String EXTRA_STATUS =
+            "quadbt.EXTRA_STATUS";
+
+    private final static UUID CLIENT_CONFIGURATION_UUID =
+            UUID.fromString("00002902-0000-1000-8000-00805f9b34fb");
+
+    // requestMtu() was called, the next onMtuChanged completes it
+    private boolean mMtuRequested = false;
 
-    final Object mLock = new Object();
-    boolean writen = false;
-    
     private final BluetoothGattCallback mGattCallback = new BluetoothGattCallback() {
         @Override
         public void onConnectionStateChange(BluetoothGatt gatt, int status, int newState) {
@@ -67,8 +73,10 @@ public class BluetoothLeService extends Service {
                 mBluetoothGatt.close();
                 intentAction = ACTION_GATT_DISCONNECTED;
                 mConnectionState = STATE_DISCONNECTED;
-                Log.w("SAPP", "Disconnected from GATT server.");
-                broadcastUpdate(intentAction);
+                Log.w("SAPP", "Disconnected from GATT server: " + status);
+                final Intent intent = new Intent(intentAction);
+                intent.putExtra(EXTRA_STATUS, status);
+                sendBroadcast(intent);
             }
         }
 
@@ -82,6 +90,12 @@ public class BluetoothLeService extends Service {
             }
         }
 
+        // the peripheral sent a Service Changed indication, the cached services are stale
+        @Override
+        public void onServiceChanged(BluetoothGatt gatt) {
+            QuadBT.onServiceChanged();
+        }
+
         @Override
         public void onCharacteristicRead(BluetoothGatt gatt,
                                          BluetoothGattCharacteristic characteristic,
@@ -89,6 +103,7 @@ public class BluetoothLeService extends Service {
             if (status == BluetoothGatt.GATT_SUCCESS) {
                 broadcastUpdate(ACTION_DATA_AVAILABLE, characteristic);
             }
+            QuadBT.onOperationComplete(status);
         }
 
         @Override
@@ -100,32 +115,77 @@ public class BluetoothLeService extends Service {
             } else {
                 Log.w("SAPP", "char write NOT OK: " + status);
             }
-
-            synchronized (mLock) {
-                writen = true;
-                mLock.notifyAll();
-            }
+            QuadBT.onOperationComplete(status);
         }
 
         @Override
         public void onDescriptorWrite(BluetoothGatt gatt,
-                                       BluetoothGattDescriptor characteristic,
+                                       BluetoothGattDescriptor descriptor,
                                        int status) {
             if (status == BluetoothGatt.GATT_SUCCESS) {
                 Log.w("SAPP", "Descriptor write success!");
+                if (CLIENT_CONFIGURATION_UUID.equals(descriptor.getUuid())) {
+                    boolean enabled = !Arrays.equals(descriptor.getValue(),
+                                                     BluetoothGattDescriptor.DISABLE_NOTIFICATION_VALUE);
+                    boolean indication = Arrays.equals(descriptor.getValue(),
+                                                       BluetoothGattDescriptor.ENABLE_INDICATION_VALUE);
+                    QuadBT.onSubscriptionChanged(descriptor.getCharacteristic().getUuid().toString(), enabled, indication);
+                }
             } else {
                 Log.e("SAPP", "Descriptor write error: " + status);
             }
-
-            synchronized (mLock) {
-                mLock.notifyAll();
-            }
+            QuadBT.onOperationComplete(status);
         }
         @Override
         public void onCharacteristicChanged(BluetoothGatt gatt,
                                             BluetoothGattCharacteristic characteristic) {
             broadcastUpdate(ACTION_DATA_AVAILABLE, characteristic);
         }
+
+        @Override
+        public void onReliableWriteCompleted(BluetoothGatt gatt, int status) {
+            QuadBT.onReliableWriteCompleted(status);
+            QuadBT.onOperationComplete(status);
+        }
+
+        @Override
+        public void onMtuChanged(BluetoothGatt gatt, int mtu, int status) {
+            if (status != BluetoothGatt.GATT_SUCCESS) {
+                Log.w("SAPP", "onMtuChanged received: " + status);
+            }
+            // on failure mtu is the one still in use
+            QuadBT.onMtuChanged(mtu);
+            // the peripheral may start an exchange on its own
+            if (mMtuRequested) {
+                mMtuRequested = false;
+                QuadBT.onOperationComplete(status);
+            }
+        }
+
+        @Override
+        public void onReadRemoteRssi(BluetoothGatt gatt, int rssi, int status) {
+            if (status == BluetoothGatt.GATT_SUCCESS) {
+                QuadBT.onRssiRead(rssi);
+            }
+            QuadBT.onOperationComplete(status);
+        }
+
+        @Override
+        public void onPhyUpdate(BluetoothGatt gatt, int txPhy, int rxPhy, int status) {
+            if (status != BluetoothGatt.GATT_SUCCESS) {
+                Log.w("SAPP", "onPhyUpdate received: " + status);
+            }
+            // on failure the PHYs are the ones still in use
+            QuadBT.onPhyChanged(txPhy, rxPhy);
+        }
+
+        // Hidden API, called on Android 8 and later all the same.
+        // interval in units of 1.25 ms, timeout in units of 10 ms
+        public void onConnectionUpdated(BluetoothGatt gatt, int interval, int latency, int timeout, int status) {
+            if (status == BluetoothGatt.GATT_SUCCESS) {
+                QuadBT.onConnectionUpdated(interval, latency, timeout);
+            }
+        }
     };
 
     private void broadcastUpdate(final String action) {
@@ -223,6 +283,30 @@ public class BluetoothLeService extends Service {
         mBluetoothGatt.disconnect();
     }
 
+    // Devices with a GATT connection to any app.
+    public List<BluetoothDevice> getConnectedDevices() {
+        return mBluetoothManager.getConnectedDevices(BluetoothProfile.GATT);
+    }
+
+    // Pair with the connected device, after it asked for an encrypted link.
+    public boolean bondConnected() {
+        if (mBluetoothGatt == null) {
+            return false;
+        }
+        BluetoothDevice device = mBluetoothGatt.getDevice();
+        return device.getBondState() == BluetoothDevice.BOND_NONE && device.createBond();
+    }
+
+    // Drop the connection without waiting for onConnectionStateChange,
+    // a pending connect does not always report one.
+    public void cancel() {
+        if (mBluetoothGatt == null) {
+            return;
+        }
+        mBluetoothGatt.disconnect();
+        close();
+    }
+
     public void close() {
         if (mBluetoothGatt == null) {
             return;
@@ -231,106 +315,131 @@ public class BluetoothLeService extends Service {
         mBluetoothGatt = null;
     }
     
-    public void writeCharacteristic(BluetoothGattCharacteristic characteristic, String data) {
-        writen = false;
-
-        characteristic.setValue(data);
-        mBluetoothGatt.writeCharacteristic(characteristic);
+    // All the operations below are asynchronous, each completion is reported
+    // with QuadBT.onOperationComplete. false means the operation did not start.
 
-        try {
-            synchronized (mLock) {
-                while (!writen) {
-                    mLock.wait();
-                }
-            }
-        } catch (final InterruptedException e) {
-            Log.e("SAPP", "setCharacterIndication: can't wait for the descriptor valye " + e);
+    public boolean writeCharacteristic(BluetoothGattCharacteristic characteristic, byte[] data, boolean verify) {
+        if (mBluetoothAdapter == null || mBluetoothGatt == null) {
+            Log.w("SAPP", "BluetoothAdapter not initialized");
+            return false;
         }
-    }
-
-    public void writeCharacteristic(BluetoothGattCharacteristic characteristic, byte[] data, boolean verify) {
-        writen = false;
         if (verify) {
             characteristic.setWriteType(BluetoothGattCharacteristic.WRITE_TYPE_DEFAULT);
         } else {
             characteristic.setWriteType(BluetoothGattCharacteristic.WRITE_TYPE_NO_RESPONSE);
         }
         characteristic.setValue(data);
-        mBluetoothGatt.writeCharacteristic(characteristic);
+        return mBluetoothGatt.writeCharacteristic(characteristic);
+    }
 
-        try {
-            synchronized (mLock) {
-                while (!writen) {
-                    mLock.wait();
-                }
-            }
-        } catch (final InterruptedException e) {
-            Log.e("SAPP", "setCharacterIndication: can't wait for the descriptor valye " + e);
+    public boolean readCharacteristic(BluetoothGattCharacteristic characteristic) {
+        if (mBluetoothAdapter == null || mBluetoothGatt == null) {
+            Log.w("SAPP", "BluetoothAdapter not initialized");
+            return false;
         }
+        Log.w("SAPP", "read Characteristic " + characteristic.getUuid());
+
+        return mBluetoothGatt.readCharacteristic(characteristic);
     }
 
-    public void readCharacteristic(BluetoothGattCharacteristic characteristic) {
+    public boolean requestMtu(int mtu) {
         if (mBluetoothAdapter == null || mBluetoothGatt == null) {
             Log.w("SAPP", "BluetoothAdapter not initialized");
-            return;
+            return false;
         }
-        Log.w("SAPP", "read Characteristic " + characteristic.getUuid());
+        mMtuRequested = mBluetoothGatt.requestMtu(mtu);
+        return mMtuRequested;
+    }
 
-        mBluetoothGatt.readCharacteristic(characteristic);
+    public boolean readRemoteRssi() {
+        if (mBluetoothAdapter == null || mBluetoothGatt == null) {
+            Log.w("SAPP", "BluetoothAdapter not initialized");
+            return false;
+        }
+        return mBluetoothGatt.readRemoteRssi();
     }
 
-    public void setCharacteristicNotification(BluetoothGattCharacteristic characteristic, boolean enabled) {
+    public boolean requestConnectionPriority(int priority) {
         if (mBluetoothAdapter == null || mBluetoothGatt == null) {
             Log.w("SAPP", "BluetoothAdapter not initialized");
-            return;
+            return false;
         }
-        mBluetoothGatt.setCharacteristicNotification(characteristic, enabled);
+        return mBluetoothGatt.requestConnectionPriority(priority);
+    }
 
-        BluetoothGattDescriptor descriptor = characteristic.getDescriptor(UUID.fromString("00002902-0000-1000-8000-00805f9b34fb"));
-        descriptor.setValue(BluetoothGattDescriptor.ENABLE_NOTIFICATION_VALUE);
-        mBluetoothGatt.writeDescriptor(descriptor);
+    public boolean setPreferredPhy(int txPhy, int rxPhy, int phyOptions) {
+        if (mBluetoothAdapter == null || mBluetoothGatt == null) {
+            Log.w("SAPP", "BluetoothAdapter not initialized");
+            return false;
+        }
+        mBluetoothGatt.setPreferredPhy(txPhy, rxPhy, phyOptions);
+        return true;
+    }
 
-        try {
-            synchronized (mLock) {
-                while (true) {
-                    mLock.wait();
-                    byte[] value = descriptor.getValue();
-                    if (value != null && value.length == 2 && value[0] == 1 && value[1] == 0) {
-                        break;
-                    }
-                }
+    // Discover the services again. With clearCache the cached services
+    // are dropped first, BluetoothGatt.refresh is hidden API for that.
+    public boolean rediscoverServices(boolean clearCache) {
+        if (mBluetoothAdapter == null || mBluetoothGatt == null) {
+            Log.w("SAPP", "BluetoothAdapter not initialized");
+            return false;
+        }
+        if (clearCache) {
+            try {
+                mBluetoothGatt.getClass().getMethod("refresh").invoke(mBluetoothGatt);
+            } catch (Exception e) {
+                Log.w("SAPP", "refresh failed: " + e);
             }
-        } catch (final InterruptedException e) {
-			Log.e("SAPP", "setCharacterIndication: can't wait for the descriptor valye " + e);
-		}
+        }
+        return mBluetoothGatt.discoverServices();
+    }
+
+    public boolean beginReliableWrite() {
+        if (mBluetoothAdapter == null || mBluetoothGatt == null) {
+            Log.w("SAPP", "BluetoothAdapter not initialized");
+            return false;
+        }
+        return mBluetoothGatt.beginReliableWrite();
     }
 
-    public void setCharacteristicIndication(BluetoothGattCharacteristic characteristic, boolean enabled) {
+    public boolean executeReliableWrite() {
         if (mBluetoothAdapter == null || mBluetoothGatt == null) {
             Log.w("SAPP", "BluetoothAdapter not initialized");
+            return false;
+        }
+        return mBluetoothGatt.executeReliableWrite();
+    }
+
+    // synchronous, there is no callback for an abort
+    public void abortReliableWrite() {
+        if (mBluetoothGatt == null) {
             return;
         }
-        mBluetoothGatt.setCharacteristicNotification(characteristic, enabled);
+        mBluetoothGatt.abortReliableWrite();
+    }
 
-        BluetoothGattDescriptor descriptor = characteristic.getDescriptor(UUID.fromString("00002902-0000-1000-8000-00805f9b34fb"));
-        descriptor.setValue(BluetoothGattDescriptor.ENABLE_INDICATION_VALUE);
-        mBluetoothGatt.writeDescriptor(descriptor);
+    public boolean setCharacteristicNotification(BluetoothGattCharacteristic characteristic, boolean enabled) {
+        return writeClientConfiguration(characteristic, enabled, BluetoothGattDescriptor.ENABLE_NOTIFICATION_VALUE);
+    }
 
-        try {
-            synchronized (mLock) {
-                while (true) {
-                    mLock.wait();
-                    byte[] value = descriptor.getValue();
-                    if (value != null && value.length == 2 && value[0] == 2 && value[1] == 0) {
-                        break;
-                    }
-                }
-            }
-        } catch (final InterruptedException e) {
-			Log.e("SAPP", "setCharacterIndication: can't wait for the descriptor valye " + e);
-		}
+    public boolean setCharacteristicIndication(BluetoothGattCharacteristic characteristic, boolean enabled) {
+        return writeClientConfiguration(characteristic, enabled, BluetoothGattDescriptor.ENABLE_INDICATION_VALUE);
+    }
 
-        Log.w("SAPP", "setCharacteristicIndication: very well!");
+    private boolean writeClientConfiguration(BluetoothGattCharacteristic characteristic, boolean enabled, byte[] enableValue) {
+        if (mBluetoothAdapter == null || mBluetoothGatt == null) {
+            Log.w("SAPP", "BluetoothAdapter not initialized");
+            return false;
+        }
+        BluetoothGattDescriptor descriptor = characteristic.getDescriptor(CLIENT_CONFIGURATION_UUID);
+        if (descriptor == null) {
+            Log.w("SAPP", "no client configuration descriptor for " + characteristic.getUuid());
+            return false;
+        }
+        if (!mBluetoothGatt.setCharacteristicNotification(characteristic, enabled)) {
+            return false;
+        }
+        descriptor.setValue(enabled ? enableValue : BluetoothGattDescriptor.DISABLE_NOTIFICATION_VALUE);
+        return mBluetoothGatt.writeDescriptor(descriptor);
     }
 
     public List<BluetoothGattService> getSupportedGattServices() {
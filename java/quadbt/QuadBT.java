@@ -1,5 +1,7 @@
 package quadbt;
 
+import android.app.Activity;
+import android.content.Intent;
 import android.os.Handler;
 import android.bluetooth.BluetoothManager;
 import android.bluetooth.BluetoothGatt;
@@ -9,25 +11,59 @@ import android.bluetooth.BluetoothGattCallback;
 import android.bluetooth.BluetoothGattService;
 import android.bluetooth.BluetoothDevice;
 import android.bluetooth.BluetoothAdapter;
+import android.bluetooth.le.AdvertiseCallback;
+import android.bluetooth.le.AdvertiseData;
+import android.bluetooth.le.AdvertiseSettings;
+import android.bluetooth.le.BluetoothLeAdvertiser;
 import android.bluetooth.le.BluetoothLeScanner;
 import android.bluetooth.le.ScanCallback;
+import android.bluetooth.le.ScanRecord;
 import android.bluetooth.le.ScanResult;
+import android.os.Looper;
+import android.os.ParcelUuid;
 import android.util.Log;
+import java.util.ArrayList;
 import java.util.List;
 
 import TARGET_PACKAGE_NAME.MainActivity;
 
 public class QuadBT  {
+    public static final int REQUEST_ENABLE = 2;
+
+    public static Activity activity;
     public static BluetoothAdapter bluetoothAdapter;
     public static BluetoothLeScanner bluetoothLeScanner;
     private static BluetoothLeService bluetoothService;
+    private BluetoothLeAdvertiser advertiser;
+    private final Handler handler = new Handler(Looper.getMainLooper());
+
+    // onAdapterStateChanged codes besides BluetoothAdapter.STATE_*
+    public static final int STATE_UNSUPPORTED = -1;
+    public static final int STATE_UNAUTHORIZED = -2;
 
     native static void onServiceConnected();
+    public native static void onAdapterStateChanged(int state);
+    public native static void onPermissionsCreated(QuadBTPermissions permissions);
+    public native static void onPermissionsResult();
+    public native static void onEnableResult(boolean enabled);
+    public native static void onBondStateChanged(String address, int state);
     public native static void onGattConnected();
-    public native static void onGattDisconnected();
-    native void onDeviceFound(BluetoothDevice device);
+    public native static void onGattDisconnected(int status);
+    native void onDeviceFound(BluetoothDevice device, byte[] scanRecord);
     native static void onCharacteristicDiscovered(BluetoothGattCharacteristic characteristic);
+    native static void onServicesDiscovered();
+    native static void onServiceChanged();
     public native static void onDataAvailable(byte[] data);
+    native static void onMtuChanged(int mtu);
+    native static void onRssiRead(int rssi);
+    native static void onPhyChanged(int txPhy, int rxPhy);
+    native static void onConnectionUpdated(int interval, int latency, int timeout);
+    native static void onOperationComplete(int status);
+    native static void onReliableWriteCompleted(int status);
+    native static void onSubscriptionChanged(String uuid, boolean enabled, boolean indication);
+    native static void onAdvertisingStarted();
+    native static void onAdvertisingStopped();
+    native static void onAdvertisingFailed(int errorCode);
 
     private ScanCallback leScanCallback =
         new ScanCallback() {
@@ -36,10 +72,38 @@ public class QuadBT  {
                 super.onScanResult(callbackType, result);
 
                 BluetoothDevice device = result.getDevice();
-                onDeviceFound(device);
+                ScanRecord record = result.getScanRecord();
+                onDeviceFound(device, record == null ? null : record.getBytes());
             }
         };
 
+    private final Runnable advertisingTimeout = new Runnable() {
+            @Override
+            public void run() {
+                advertiser = null;
+                onAdvertisingStopped();
+            }
+        };
+
+    private AdvertiseCallback advertiseCallback =
+        new AdvertiseCallback() {
+            @Override
+            public void onStartSuccess(AdvertiseSettings settingsInEffect) {
+                onAdvertisingStarted();
+            }
+
+            @Override
+            public void onStartFailure(int errorCode) {
+                handler.removeCallbacks(advertisingTimeout);
+                advertiser = null;
+                onAdvertisingFailed(errorCode);
+            }
+        };
+
+    static BluetoothLeService getService() {
+        return bluetoothService;
+    }
+
     public static void connectService(BluetoothLeService service) {
         bluetoothService = service;
         onServiceConnected();
@@ -52,6 +116,7 @@ public class QuadBT  {
                 onCharacteristicDiscovered(characteristic);
             }
         }
+        onServicesDiscovered();
     }
 
     public QuadBT() {
@@ -61,38 +126,176 @@ public class QuadBT  {
         return this.bluetoothAdapter.isEnabled();
     }
 
+    // answered in MainActivity.onActivityResult
+    public void requestEnable() {
+        activity.runOnUiThread(new Runnable() {
+            @Override
+            public void run() {
+                Intent enableBtIntent = new Intent(BluetoothAdapter.ACTION_REQUEST_ENABLE);
+                activity.startActivityForResult(enableBtIntent, REQUEST_ENABLE);
+            }
+        });
+    }
+
     public void startScan() {
         bluetoothLeScanner = this.bluetoothAdapter.getBluetoothLeScanner();
         bluetoothLeScanner.startScan(leScanCallback);
     }
 
-    public void connect(String address) {
-        bluetoothService.connect(address);
+    public QuadBTServer createServer() {
+        return new QuadBTServer();
+    }
+
+    public boolean connect(String address) {
+        return bluetoothService.connect(address);
     }
 
     public void disconnect() {
         bluetoothService.disconnect();
     }
 
-    public void setCharacteristicNotification(BluetoothGattCharacteristic characteristic, boolean enabled) {
-        bluetoothService.setCharacteristicNotification(characteristic, enabled);
+    public void cancel() {
+        bluetoothService.cancel();
     }
 
-    public void setCharacteristicIndication(BluetoothGattCharacteristic characteristic, boolean enabled) {
-        bluetoothService.setCharacteristicIndication(characteristic, enabled);
+    public boolean bondConnected() {
+        return bluetoothService.bondConnected();
     }
 
-    public void writeCharacteristicString(BluetoothGattCharacteristic characteristic, String data) {
-        assert characteristic != null;
-        assert data != null;
+    // a device by its stored address, in range or not
+    public BluetoothDevice remoteDevice(String address) {
+        return bluetoothAdapter.getRemoteDevice(address);
+    }
+
+    public String[] connectedDevices() {
+        List<String> addresses = new ArrayList<String>();
+        for (BluetoothDevice device : bluetoothService.getConnectedDevices()) {
+            addresses.add(device.getAddress());
+        }
+        return addresses.toArray(new String[0]);
+    }
+
+    // LE devices only, classic ones can not be connected to
+    public String[] bondedDevices() {
+        List<String> addresses = new ArrayList<String>();
+        for (BluetoothDevice device : bluetoothAdapter.getBondedDevices()) {
+            if (device.getType() != BluetoothDevice.DEVICE_TYPE_CLASSIC) {
+                addresses.add(device.getAddress());
+            }
+        }
+        return addresses.toArray(new String[0]);
+    }
+
+    // BluetoothDevice.removeBond is hidden API, there is no public way
+    public boolean removeBond(String address) {
+        BluetoothDevice device = bluetoothAdapter.getRemoteDevice(address);
+        try {
+            return (Boolean) device.getClass().getMethod("removeBond").invoke(device);
+        } catch (Exception e) {
+            Log.w("SAPP", "removeBond failed: " + e);
+            return false;
+        }
+    }
+
+    public boolean readCharacteristic(BluetoothGattCharacteristic characteristic) {
+        return bluetoothService.readCharacteristic(characteristic);
+    }
+
+    public boolean requestMtu(int mtu) {
+        return bluetoothService.requestMtu(mtu);
+    }
+
+    public boolean readRemoteRssi() {
+        return bluetoothService.readRemoteRssi();
+    }
+
+    public boolean requestConnectionPriority(int priority) {
+        return bluetoothService.requestConnectionPriority(priority);
+    }
+
+    public boolean setPreferredPhy(int txPhy, int rxPhy, int phyOptions) {
+        return bluetoothService.setPreferredPhy(txPhy, rxPhy, phyOptions);
+    }
+
+    public boolean rediscoverServices(boolean clearCache) {
+        return bluetoothService.rediscoverServices(clearCache);
+    }
+
+    public boolean beginReliableWrite() {
+        return bluetoothService.beginReliableWrite();
+    }
+
+    public boolean executeReliableWrite() {
+        return bluetoothService.executeReliableWrite();
+    }
+
+    public void abortReliableWrite() {
+        bluetoothService.abortReliableWrite();
+    }
+
+    public void startAdvertising(boolean connectable, int mode, int txPower, int timeoutMillis,
+                                 boolean includeName, boolean includeTxPower, String[] serviceUuids,
+                                 int[] manufacturerIds, byte[][] manufacturerData,
+                                 String[] serviceDataUuids, byte[][] serviceData) {
+        if (advertiser != null) {
+            onAdvertisingFailed(AdvertiseCallback.ADVERTISE_FAILED_ALREADY_STARTED);
+            return;
+        }
+        advertiser = this.bluetoothAdapter.getBluetoothLeAdvertiser();
+        if (advertiser == null) {
+            onAdvertisingFailed(AdvertiseCallback.ADVERTISE_FAILED_FEATURE_UNSUPPORTED);
+            return;
+        }
+
+        AdvertiseSettings settings = new AdvertiseSettings.Builder()
+            .setConnectable(connectable)
+            .setAdvertiseMode(mode)
+            .setTxPowerLevel(txPower)
+            .setTimeout(timeoutMillis)
+            .build();
+
+        AdvertiseData.Builder data = new AdvertiseData.Builder()
+            .setIncludeDeviceName(includeName)
+            .setIncludeTxPowerLevel(includeTxPower);
+        for (String uuid : serviceUuids) {
+            data.addServiceUuid(ParcelUuid.fromString(uuid));
+        }
+        for (int i = 0; i < manufacturerIds.length; i++) {
+            data.addManufacturerData(manufacturerIds[i], manufacturerData[i]);
+        }
+        for (int i = 0; i < serviceDataUuids.length; i++) {
+            data.addServiceData(ParcelUuid.fromString(serviceDataUuids[i]), serviceData[i]);
+        }
+
+        // the advertiser stops on its own after the timeout, but does not report it
+        if (timeoutMillis > 0) {
+            handler.postDelayed(advertisingTimeout, timeoutMillis);
+        }
+        advertiser.startAdvertising(settings, data.build(), advertiseCallback);
+    }
+
+    public void stopAdvertising() {
+        if (advertiser == null) {
+            return;
+        }
+        handler.removeCallbacks(advertisingTimeout);
+        advertiser.stopAdvertising(advertiseCallback);
+        advertiser = null;
+        onAdvertisingStopped();
+    }
+
+    public boolean setCharacteristicNotification(BluetoothGattCharacteristic characteristic, boolean enabled) {
+        return bluetoothService.setCharacteristicNotification(characteristic, enabled);
+    }
 
-        bluetoothService.writeCharacteristic(characteristic, data);
+    public boolean setCharacteristicIndication(BluetoothGattCharacteristic characteristic, boolean enabled) {
+        return bluetoothService.setCharacteristicIndication(characteristic, enabled);
     }
 
-    public void writeCharacteristicBytes(BluetoothGattCharacteristic characteristic, byte[] data, boolean verify) {
+    public boolean writeCharacteristicBytes(BluetoothGattCharacteristic characteristic, byte[] data, boolean verify) {
         assert characteristic != null;
         assert data != null;
 
-        bluetoothService.writeCharacteristic(characteristic, data, verify);
+        return bluetoothService.writeCharacteristic(characteristic, data, verify);
     }
 }
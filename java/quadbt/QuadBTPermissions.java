@@ -0,0 +1,57 @@
+package quadbt;
+
+import android.app.Activity;
+import android.content.pm.PackageManager;
+import android.os.Build;
+import android.Manifest;
+
+import androidx.core.app.ActivityCompat;
+import androidx.core.content.ContextCompat;
+
+// Runtime permissions Bluetooth needs, asked for by Adapter::request_permissions.
+public class QuadBTPermissions {
+    public static final int REQUEST_CODE = 1;
+
+    private final Activity activity;
+
+    public QuadBTPermissions(Activity activity) {
+        this.activity = activity;
+    }
+
+    private static String[] required() {
+        if (Build.VERSION.SDK_INT >= Build.VERSION_CODES.S) {
+            return new String[]{
+                Manifest.permission.BLUETOOTH_SCAN,
+                Manifest.permission.BLUETOOTH_CONNECT,
+                Manifest.permission.BLUETOOTH_ADVERTISE};
+        }
+        return new String[]{Manifest.permission.ACCESS_FINE_LOCATION};
+    }
+
+    public boolean granted() {
+        for (String permission : required()) {
+            if (ContextCompat.checkSelfPermission(activity, permission) != PackageManager.PERMISSION_GRANTED) {
+                return false;
+            }
+        }
+        return true;
+    }
+
+    public boolean shouldShowRationale() {
+        for (String permission : required()) {
+            if (ActivityCompat.shouldShowRequestPermissionRationale(activity, permission)) {
+                return true;
+            }
+        }
+        return false;
+    }
+
+    public void request() {
+        activity.runOnUiThread(new Runnable() {
+            @Override
+            public void run() {
+                ActivityCompat.requestPermissions(activity, required(), REQUEST_CODE);
+            }
+        });
+    }
+}
@@ -0,0 +1,226 @@
+package quadbt;
+
+import android.bluetooth.BluetoothDevice;
+import android.bluetooth.BluetoothGatt;
+import android.bluetooth.BluetoothGattCharacteristic;
+import android.bluetooth.BluetoothGattDescriptor;
+import android.bluetooth.BluetoothGattServer;
+import android.bluetooth.BluetoothGattServerCallback;
+import android.bluetooth.BluetoothGattService;
+import android.bluetooth.BluetoothManager;
+import android.bluetooth.BluetoothProfile;
+import android.bluetooth.le.AdvertiseCallback;
+import android.bluetooth.le.AdvertiseData;
+import android.bluetooth.le.AdvertiseSettings;
+import android.bluetooth.le.BluetoothLeAdvertiser;
+import android.content.Context;
+import android.os.ParcelUuid;
+import android.util.Log;
+
+import java.util.ArrayList;
+import java.util.HashMap;
+import java.util.List;
+import java.util.UUID;
+
+public class QuadBTServer {
+    private static final UUID CCCD_UUID = UUID.fromString("00002902-0000-1000-8000-00805f9b34fb");
+
+    private BluetoothGattServer gattServer;
+    private BluetoothLeAdvertiser advertiser;
+    private BluetoothGattService currentService;
+    private BluetoothGattCharacteristic currentCharacteristic;
+    // services are added one by one, the next one only after onServiceAdded
+    private final List<BluetoothGattService> pendingServices = new ArrayList<BluetoothGattService>();
+    private final HashMap<String, BluetoothDevice> devices = new HashMap<String, BluetoothDevice>();
+
+    native void onConnectionStateChange(String address, boolean connected);
+    native void onReadRequest(BluetoothDevice device, String address, int requestId, String service, String characteristic, int offset);
+    native void onWriteRequest(BluetoothDevice device, String address, int requestId, String service, String characteristic, byte[] value, boolean preparedWrite, boolean responseNeeded, int offset);
+    native void onExecuteWrite(BluetoothDevice device, String address, int requestId, boolean execute);
+    native void onSubscriptionChange(String address, String characteristic, boolean subscribed);
+    native void onAdvertisingStarted();
+    native void onAdvertisingFailed(int errorCode);
+
+    private final BluetoothGattServerCallback callback = new BluetoothGattServerCallback() {
+        @Override
+        public void onConnectionStateChange(BluetoothDevice device, int status, int newState) {
+            boolean connected = newState == BluetoothProfile.STATE_CONNECTED;
+            synchronized (devices) {
+                if (connected) {
+                    devices.put(device.getAddress(), device);
+                } else {
+                    devices.remove(device.getAddress());
+                }
+            }
+            QuadBTServer.this.onConnectionStateChange(device.getAddress(), connected);
+        }
+
+        @Override
+        public void onServiceAdded(int status, BluetoothGattService service) {
+            if (status != BluetoothGatt.GATT_SUCCESS) {
+                Log.e("SAPP", "GATT service not added: " + status);
+            }
+            addNextService();
+        }
+
+        @Override
+        public void onCharacteristicReadRequest(BluetoothDevice device, int requestId, int offset,
+                                                BluetoothGattCharacteristic characteristic) {
+            QuadBTServer.this.onReadRequest(device, device.getAddress(), requestId,
+                                            characteristic.getService().getUuid().toString(),
+                                            characteristic.getUuid().toString(), offset);
+        }
+
+        @Override
+        public void onCharacteristicWriteRequest(BluetoothDevice device, int requestId,
+                                                 BluetoothGattCharacteristic characteristic,
+                                                 boolean preparedWrite, boolean responseNeeded,
+                                                 int offset, byte[] value) {
+            QuadBTServer.this.onWriteRequest(device, device.getAddress(), requestId,
+                                             characteristic.getService().getUuid().toString(),
+                                             characteristic.getUuid().toString(),
+                                             value, preparedWrite, responseNeeded, offset);
+        }
+
+        @Override
+        public void onExecuteWrite(BluetoothDevice device, int requestId, boolean execute) {
+            QuadBTServer.this.onExecuteWrite(device, device.getAddress(), requestId, execute);
+        }
+
+        @Override
+        public void onDescriptorReadRequest(BluetoothDevice device, int requestId, int offset,
+                                            BluetoothGattDescriptor descriptor) {
+            byte[] value = descriptor.getValue();
+            if (value == null) {
+                value = new byte[0];
+            }
+            if (offset > value.length) {
+                gattServer.sendResponse(device, requestId, BluetoothGatt.GATT_INVALID_OFFSET, offset, null);
+                return;
+            }
+            byte[] tail = new byte[value.length - offset];
+            System.arraycopy(value, offset, tail, 0, tail.length);
+            gattServer.sendResponse(device, requestId, BluetoothGatt.GATT_SUCCESS, offset, tail);
+        }
+
+        @Override
+        public void onDescriptorWriteRequest(BluetoothDevice device, int requestId,
+                                             BluetoothGattDescriptor descriptor,
+                                             boolean preparedWrite, boolean responseNeeded,
+                                             int offset, byte[] value) {
+            if (CCCD_UUID.equals(descriptor.getUuid())) {
+                boolean subscribed = value != null && value.length > 0 && value[0] != 0;
+                QuadBTServer.this.onSubscriptionChange(device.getAddress(),
+                                                       descriptor.getCharacteristic().getUuid().toString(),
+                                                       subscribed);
+            } else {
+                descriptor.setValue(value);
+            }
+            if (responseNeeded) {
+                gattServer.sendResponse(device, requestId, BluetoothGatt.GATT_SUCCESS, offset, value);
+            }
+        }
+    };
+
+    private final AdvertiseCallback advertiseCallback = new AdvertiseCallback() {
+        @Override
+        public void onStartSuccess(AdvertiseSettings settingsInEffect) {
+            onAdvertisingStarted();
+        }
+
+        @Override
+        public void onStartFailure(int errorCode) {
+            onAdvertisingFailed(errorCode);
+        }
+    };
+
+    public QuadBTServer() {
+    }
+
+    public void addService(String uuid, boolean primary) {
+        currentService = new BluetoothGattService(UUID.fromString(uuid),
+                                                  primary ? BluetoothGattService.SERVICE_TYPE_PRIMARY
+                                                          : BluetoothGattService.SERVICE_TYPE_SECONDARY);
+        pendingServices.add(currentService);
+    }
+
+    public void addCharacteristic(String uuid, int properties, int permissions, byte[] value) {
+        currentCharacteristic = new BluetoothGattCharacteristic(UUID.fromString(uuid), properties, permissions);
+        currentCharacteristic.setValue(value);
+        currentService.addCharacteristic(currentCharacteristic);
+    }
+
+    public void addDescriptor(String uuid, int permissions, byte[] value) {
+        BluetoothGattDescriptor descriptor = new BluetoothGattDescriptor(UUID.fromString(uuid), permissions);
+        descriptor.setValue(value);
+        currentCharacteristic.addDescriptor(descriptor);
+    }
+
+    public boolean open() {
+        BluetoothManager manager = (BluetoothManager) QuadBT.getService().getSystemService(Context.BLUETOOTH_SERVICE);
+        gattServer = manager.openGattServer(QuadBT.getService(), callback);
+        if (gattServer == null) {
+            Log.e("SAPP", "Unable to open GATT server.");
+            return false;
+        }
+        addNextService();
+        return true;
+    }
+
+    private void addNextService() {
+        if (!pendingServices.isEmpty()) {
+            gattServer.addService(pendingServices.remove(0));
+        }
+    }
+
+    public void close() {
+        stopAdvertising();
+        if (gattServer != null) {
+            gattServer.close();
+            gattServer = null;
+        }
+    }
+
+    public void startAdvertising(String[] serviceUuids) {
+        advertiser = QuadBT.bluetoothAdapter.getBluetoothLeAdvertiser();
+        if (advertiser == null) {
+            onAdvertisingFailed(AdvertiseCallback.ADVERTISE_FAILED_FEATURE_UNSUPPORTED);
+            return;
+        }
+
+        AdvertiseSettings settings = new AdvertiseSettings.Builder()
+            .setConnectable(true)
+            .build();
+        AdvertiseData.Builder data = new AdvertiseData.Builder()
+            .setIncludeDeviceName(true);
+        for (String uuid : serviceUuids) {
+            data.addServiceUuid(ParcelUuid.fromString(uuid));
+        }
+        advertiser.startAdvertising(settings, data.build(), advertiseCallback);
+    }
+
+    public void stopAdvertising() {
+        if (advertiser != null) {
+            advertiser.stopAdvertising(advertiseCallback);
+            advertiser = null;
+        }
+    }
+
+    public void sendResponse(BluetoothDevice device, int requestId, int status, int offset, byte[] value) {
+        gattServer.sendResponse(device, requestId, status, offset, value);
+    }
+
+    public boolean notify(String address, String service, String characteristic, byte[] value, boolean confirm) {
+        BluetoothDevice device;
+        synchronized (devices) {
+            device = devices.get(address);
+        }
+        if (gattServer == null || device == null) {
+            return false;
+        }
+        BluetoothGattCharacteristic target = gattServer.getService(UUID.fromString(service))
+            .getCharacteristic(UUID.fromString(characteristic));
+        target.setValue(value);
+        return gattServer.notifyCharacteristicChanged(device, target, confirm);
+    }
+}
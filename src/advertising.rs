@@ -0,0 +1,371 @@
+//! Advertisement payloads, both for broadcasting and as seen by a scan.
+
+#[cfg(not(any(target_os = "android", target_os = "ios", target_os = "macos")))]
+use crate::error::BluetoothError;
+
+use std::time::Duration;
+
+/// Legacy advertising PDU payload limit.
+#[cfg(not(any(target_os = "android", target_os = "ios", target_os = "macos")))]
+const MAX_LEGACY_ADVERTISEMENT_LEN: usize = 31;
+
+/// AD structure types, Bluetooth Assigned Numbers 2.3.
+#[cfg(not(any(target_os = "android", target_os = "ios", target_os = "macos")))]
+const AD_FLAGS: u8 = 0x01;
+const AD_INCOMPLETE_UUIDS_16: u8 = 0x02;
+const AD_COMPLETE_UUIDS_16: u8 = 0x03;
+const AD_INCOMPLETE_UUIDS_32: u8 = 0x04;
+const AD_COMPLETE_UUIDS_32: u8 = 0x05;
+const AD_INCOMPLETE_UUIDS_128: u8 = 0x06;
+const AD_COMPLETE_UUIDS_128: u8 = 0x07;
+const AD_SHORT_NAME: u8 = 0x08;
+const AD_COMPLETE_NAME: u8 = 0x09;
+const AD_TX_POWER: u8 = 0x0a;
+const AD_SERVICE_DATA_16: u8 = 0x16;
+const AD_SERVICE_DATA_32: u8 = 0x20;
+const AD_SERVICE_DATA_128: u8 = 0x21;
+const AD_MANUFACTURER_DATA: u8 = 0xff;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AdvertisementData {
+    /// On Android the adapter name is advertised when this is set,
+    /// the OS does not allow an arbitrary name.
+    pub local_name: Option<String>,
+    pub service_uuids: Vec<String>,
+    /// Company identifier and its payload.
+    pub manufacturer_data: Vec<(u16, Vec<u8>)>,
+    /// Service UUID and its payload.
+    pub service_data: Vec<(String, Vec<u8>)>,
+    /// On Android only presence matters, the OS fills in the actual level.
+    pub tx_power_level: Option<i8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AdvertisingMode {
+    LowPower,
+    Balanced,
+    LowLatency,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TxPower {
+    UltraLow,
+    Low,
+    Medium,
+    High,
+}
+
+/// How to advertise. iOS ignores everything but the payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AdvertisingSettings {
+    pub connectable: bool,
+    pub mode: AdvertisingMode,
+    pub tx_power: TxPower,
+    /// Stop advertising after this long, `None` advertises until stopped.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for AdvertisingSettings {
+    fn default() -> AdvertisingSettings {
+        AdvertisingSettings {
+            connectable: false,
+            mode: AdvertisingMode::Balanced,
+            tx_power: TxPower::Medium,
+            timeout: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AdvertisingError {
+    DataTooLarge,
+    TooManyAdvertisers,
+    AlreadyStarted,
+    /// The platform can not advertise this, e.g. manufacturer data on iOS.
+    FeatureUnsupported,
+    Internal(i32),
+}
+
+fn base_uuid(short: u32) -> String {
+    format!("{:08x}-0000-1000-8000-00805f9b34fb", short)
+}
+
+/// 128 bit UUID from its little endian on-air representation.
+fn uuid_from_le(bytes: &[u8]) -> String {
+    let b: Vec<u8> = bytes.iter().rev().cloned().collect();
+    format!(
+        "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+        b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+    )
+}
+
+/// On-air form of a UUID: 2 bytes for the Bluetooth base UUIDs, 16 otherwise.
+#[cfg(not(any(target_os = "android", target_os = "ios", target_os = "macos")))]
+fn uuid_to_le(uuid: &str) -> Option<Vec<u8>> {
+    let hex = uuid.replace('-', "");
+    if hex.len() == 4 {
+        return u16::from_str_radix(&hex, 16)
+            .ok()
+            .map(|short| short.to_le_bytes().to_vec());
+    }
+    if hex.len() != 32 {
+        return None;
+    }
+    let bytes = (0..16)
+        .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    if hex[..4] == *"0000" && hex[8..].eq_ignore_ascii_case("00001000800000805f9b34fb") {
+        return Some(vec![bytes[3], bytes[2]]);
+    }
+    Some(bytes.into_iter().rev().collect())
+}
+
+fn uuids(data: &[u8], size: usize) -> impl Iterator<Item = String> + '_ {
+    data.chunks_exact(size).map(move |chunk| match size {
+        2 => base_uuid(u16::from_le_bytes([chunk[0], chunk[1]]) as u32),
+        4 => base_uuid(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])),
+        _ => uuid_from_le(chunk),
+    })
+}
+
+impl AdvertisementData {
+    /// Encode as AD structures, the way a controller would put it on air.
+    /// Fails with `InvalidArgument` when it does not fit a legacy PDU.
+    #[cfg(not(any(target_os = "android", target_os = "ios", target_os = "macos")))]
+    pub(crate) fn encode(&self, connectable: bool) -> Result<Vec<u8>, BluetoothError> {
+        fn push(bytes: &mut Vec<u8>, ty: u8, payload: &[u8]) -> Result<(), BluetoothError> {
+            if bytes.len() + 2 + payload.len() > MAX_LEGACY_ADVERTISEMENT_LEN {
+                return Err(BluetoothError::InvalidArgument);
+            }
+            bytes.push(payload.len() as u8 + 1);
+            bytes.push(ty);
+            bytes.extend_from_slice(payload);
+            Ok(())
+        }
+
+        let mut bytes = vec![];
+        if connectable {
+            // LE General Discoverable, BR/EDR not supported
+            push(&mut bytes, AD_FLAGS, &[0x06])?;
+        }
+        let uuids = self
+            .service_uuids
+            .iter()
+            .filter_map(|uuid| uuid_to_le(uuid))
+            .collect::<Vec<_>>();
+        let short = uuids.iter().filter(|u| u.len() == 2).flatten().cloned();
+        let short = short.collect::<Vec<u8>>();
+        if !short.is_empty() {
+            push(&mut bytes, AD_COMPLETE_UUIDS_16, &short)?;
+        }
+        let long = uuids.iter().filter(|u| u.len() == 16).flatten().cloned();
+        let long = long.collect::<Vec<u8>>();
+        if !long.is_empty() {
+            push(&mut bytes, AD_COMPLETE_UUIDS_128, &long)?;
+        }
+        if let Some(ref name) = self.local_name {
+            push(&mut bytes, AD_COMPLETE_NAME, name.as_bytes())?;
+        }
+        if let Some(tx_power) = self.tx_power_level {
+            push(&mut bytes, AD_TX_POWER, &[tx_power as u8])?;
+        }
+        for (uuid, data) in &self.service_data {
+            if let Some(mut payload) = uuid_to_le(uuid) {
+                let ty = if payload.len() == 2 {
+                    AD_SERVICE_DATA_16
+                } else {
+                    AD_SERVICE_DATA_128
+                };
+                payload.extend_from_slice(data);
+                push(&mut bytes, ty, &payload)?;
+            }
+        }
+        for (company, data) in &self.manufacturer_data {
+            let mut payload = company.to_le_bytes().to_vec();
+            payload.extend_from_slice(data);
+            push(&mut bytes, AD_MANUFACTURER_DATA, &payload)?;
+        }
+        Ok(bytes)
+    }
+
+    /// Decode the raw AD structures of an advertising or scan response packet.
+    /// Malformed trailing structures are ignored.
+    pub fn parse(mut bytes: &[u8]) -> AdvertisementData {
+        let mut data = AdvertisementData::default();
+
+        while let Some((&len, rest)) = bytes.split_first() {
+            let len = len as usize;
+            if len == 0 || len > rest.len() {
+                break;
+            }
+            let (ty, payload) = (rest[0], &rest[1..len]);
+            bytes = &rest[len..];
+
+            match ty {
+                AD_INCOMPLETE_UUIDS_16 | AD_COMPLETE_UUIDS_16 => {
+                    data.service_uuids.extend(uuids(payload, 2))
+                }
+                AD_INCOMPLETE_UUIDS_32 | AD_COMPLETE_UUIDS_32 => {
+                    data.service_uuids.extend(uuids(payload, 4))
+                }
+                AD_INCOMPLETE_UUIDS_128 | AD_COMPLETE_UUIDS_128 => {
+                    data.service_uuids.extend(uuids(payload, 16))
+                }
+                AD_SHORT_NAME | AD_COMPLETE_NAME
+                    if data.local_name.is_none() || ty == AD_COMPLETE_NAME =>
+                {
+                    data.local_name = Some(String::from_utf8_lossy(payload).into_owned());
+                }
+                AD_TX_POWER if payload.len() == 1 => data.tx_power_level = Some(payload[0] as i8),
+                AD_SERVICE_DATA_16 | AD_SERVICE_DATA_32 | AD_SERVICE_DATA_128 => {
+                    let size = match ty {
+                        AD_SERVICE_DATA_16 => 2,
+                        AD_SERVICE_DATA_32 => 4,
+                        _ => 16,
+                    };
+                    if payload.len() >= size {
+                        let uuid = uuids(&payload[..size], size).next().unwrap();
+                        data.service_data.push((uuid, payload[size..].to_vec()));
+                    }
+                }
+                AD_MANUFACTURER_DATA if payload.len() >= 2 => {
+                    let company = u16::from_le_bytes([payload[0], payload[1]]);
+                    data.manufacturer_data
+                        .push((company, payload[2..].to_vec()));
+                }
+                _ => {}
+            }
+        }
+
+        data
+    }
+}
+
+#[cfg(all(
+    test,
+    not(any(target_os = "android", target_os = "ios", target_os = "macos"))
+))]
+mod tests {
+    use super::*;
+
+    const BATTERY: &str = "0000180f-0000-1000-8000-00805f9b34fb";
+    const UART: &str = "6e400001-b5a3-f393-e0a9-e50e24dcca9e";
+
+    fn round_trip(data: &AdvertisementData) -> AdvertisementData {
+        AdvertisementData::parse(&data.encode(false).unwrap())
+    }
+
+    #[test]
+    fn flags() {
+        let data = AdvertisementData::default();
+        assert_eq!(data.encode(true).unwrap(), [2, AD_FLAGS, 0x06]);
+        assert_eq!(data.encode(false).unwrap(), []);
+        assert_eq!(AdvertisementData::parse(&[2, AD_FLAGS, 0x06]), data);
+    }
+
+    #[test]
+    fn uuids_16() {
+        let data = AdvertisementData {
+            service_uuids: vec!["180f".to_string(), BATTERY.to_string()],
+            ..Default::default()
+        };
+        let bytes = data.encode(false).unwrap();
+        assert_eq!(bytes, [5, AD_COMPLETE_UUIDS_16, 0x0f, 0x18, 0x0f, 0x18]);
+        assert_eq!(AdvertisementData::parse(&bytes).service_uuids, [BATTERY; 2]);
+    }
+
+    #[test]
+    fn uuids_128() {
+        let data = AdvertisementData {
+            service_uuids: vec![UART.to_string()],
+            ..Default::default()
+        };
+        let bytes = data.encode(false).unwrap();
+        assert_eq!(bytes[..2], [17, AD_COMPLETE_UUIDS_128]);
+        assert_eq!(bytes[2], 0x9e);
+        assert_eq!(round_trip(&data), data);
+    }
+
+    #[test]
+    fn manufacturer_data() {
+        let data = AdvertisementData {
+            manufacturer_data: vec![(0x004c, vec![1, 2, 3])],
+            ..Default::default()
+        };
+        let bytes = data.encode(false).unwrap();
+        assert_eq!(bytes, [6, AD_MANUFACTURER_DATA, 0x4c, 0x00, 1, 2, 3]);
+        assert_eq!(round_trip(&data), data);
+    }
+
+    #[test]
+    fn service_data() {
+        let data = AdvertisementData {
+            service_data: vec![
+                (BATTERY.to_string(), vec![0x64]),
+                (UART.to_string(), vec![]),
+            ],
+            ..Default::default()
+        };
+        let bytes = data.encode(false).unwrap();
+        assert_eq!(bytes[..5], [4, AD_SERVICE_DATA_16, 0x0f, 0x18, 0x64]);
+        assert_eq!(bytes[5..7], [17, AD_SERVICE_DATA_128]);
+        assert_eq!(round_trip(&data), data);
+    }
+
+    #[test]
+    fn name_and_tx_power() {
+        let data = AdvertisementData {
+            local_name: Some("quad".to_string()),
+            tx_power_level: Some(-8),
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&data), data);
+    }
+
+    #[test]
+    fn truncated() {
+        let name = [5, AD_COMPLETE_NAME, b'q', b'u', b'a', b'd'];
+        let mut bytes = name.to_vec();
+        bytes.extend_from_slice(&[4, AD_MANUFACTURER_DATA, 0x4c]);
+        let data = AdvertisementData::parse(&bytes);
+        assert_eq!(data.local_name.as_deref(), Some("quad"));
+        assert!(data.manufacturer_data.is_empty());
+
+        assert_eq!(AdvertisementData::parse(&name[..3]), Default::default());
+        assert_eq!(
+            AdvertisementData::parse(&[0, 2, AD_FLAGS]),
+            Default::default()
+        );
+        assert_eq!(
+            AdvertisementData::parse(&[1, AD_MANUFACTURER_DATA]),
+            Default::default()
+        );
+    }
+
+    #[test]
+    fn too_large() {
+        let data = AdvertisementData {
+            manufacturer_data: vec![(0x004c, vec![0; 300])],
+            ..Default::default()
+        };
+        assert_eq!(data.encode(false), Err(BluetoothError::InvalidArgument));
+
+        // 3 bytes of flags and 2 of header leave 26 for the name
+        let mut data = AdvertisementData {
+            local_name: Some("n".repeat(26)),
+            ..Default::default()
+        };
+        assert_eq!(
+            data.encode(true).unwrap().len(),
+            MAX_LEGACY_ADVERTISEMENT_LEN
+        );
+        data.local_name = Some("n".repeat(27));
+        assert_eq!(data.encode(true), Err(BluetoothError::InvalidArgument));
+    }
+}
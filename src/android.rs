@@ -6,51 +6,102 @@ use miniquad::{
 };
 
 use once_cell::sync::Lazy;
-use std::{collections::HashMap, fmt, sync::Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use std::sync::mpsc::{self, Receiver, Sender};
 
+use crate::advertising::{
+    AdvertisementData, AdvertisingError, AdvertisingMode, AdvertisingSettings, TxPower,
+};
+use crate::error::BluetoothError;
+use crate::event::{AdapterEvent, AdapterState, BondState};
+use crate::gatt::{
+    self, CharacteristicProperties, ConnectionPriority, Phy, WriteType, DEFAULT_MTU,
+};
+use crate::peripheral::{
+    self, Access, AttError, LocalService, Permissions, ReadRequest, ServerEvent, ServerState,
+    WriteRequest, CCCD_UUID,
+};
+use crate::permission::{PermissionFlow, PermissionProvider, PermissionStatus};
+use crate::queue::{Operation, OperationQueue};
+use crate::reconnect::{ReconnectPolicy, Reconnector};
+use crate::rssi::RssiMonitor;
+use crate::state::{ConnectionState, StateEvent};
+use crate::timeout::{Deadline, Timeouts};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceId(String);
+
+impl DeviceId {
+    /// A stored id, as `DeviceId::as_str` gave it in an earlier session.
+    /// `Adapter::connect` takes it without scanning first.
+    pub fn new(id: &str) -> DeviceId {
+        DeviceId(id.to_string())
+    }
+
+    /// The device's Bluetooth address, the same in every session.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// JNI global reference, deleted on drop.
 #[derive(Debug)]
-pub enum BluetoothError {
-    AdapterNotReady,
-    DeviceUnavailable,
-    DeviceDisconnected,
+struct GlobalRef(ndk_sys::jobject);
+
+impl GlobalRef {
+    unsafe fn new(env: *mut ndk_sys::JNIEnv, object: ndk_sys::jobject) -> GlobalRef {
+        GlobalRef(ndk_utils::new_global_ref!(env, object))
+    }
 }
 
-impl fmt::Display for BluetoothError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+impl Clone for GlobalRef {
+    fn clone(&self) -> GlobalRef {
+        unsafe { GlobalRef::new(android::attach_jni_env(), self.0) }
     }
 }
-impl std::error::Error for BluetoothError {}
 
-#[derive(Clone, Debug)]
-pub struct DeviceId(String);
+impl Drop for GlobalRef {
+    fn drop(&mut self) {
+        unsafe {
+            let env = android::attach_jni_env();
+            (**env).DeleteGlobalRef.unwrap()(env, self.0);
+        }
+    }
+}
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Device {
-    object: ndk_sys::jobject,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    object: GlobalRef,
     pub address: String,
     // same string as an address, but java
     // to avoid jni string creation all the time
-    address_j: ndk_sys::jobject,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    address_j: GlobalRef,
     pub name: Option<String>,
+    /// Payload of the last advertisement seen.
+    pub advertisement: AdvertisementData,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    seen: Instant,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Characteristic {
-    characteristic: ndk_sys::jobject,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    characteristic: GlobalRef,
     pub id: String,
-    /// PROPERTY_WRITE
-    pub write: bool,
-    ///  PROPERTY_READ
-    pub read: bool,
-    /// PROPERTY_NOTIFY
-    pub notify: bool,
-    /// PROPERTY_INDICATE
-    pub indicate: bool,
-    /// PROPERTY_BROADCAST
-    pub broadcast: bool,
+    pub properties: CharacteristicProperties,
+    /// `GlobalData::discovery` the characteristic was reported in.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    discovery: u64,
 }
 
 impl Device {
@@ -58,110 +109,282 @@ impl Device {
         DeviceId(self.address.clone())
     }
 
+    /// Pair and bond with the device, progress arrives as
+    /// `AdapterEvent::BondStateChanged`.
+    pub fn bond(&self) -> Result<(), BluetoothError> {
+        match self.bond_state()? {
+            BondState::Bonded => return Ok(()),
+            BondState::Bonding => return Err(BluetoothError::OperationInProgress),
+            BondState::None => {}
+        }
+        unsafe {
+            let env = android::attach_jni_env();
+
+            let started = ndk_utils::call_bool_method!(env, self.object.0, "createBond", "()Z");
+            take_exception(env)?;
+            if started == 0 {
+                return Err(BluetoothError::DeviceUnavailable);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn bond_state(&self) -> Result<BondState, BluetoothError> {
+        unsafe {
+            let env = android::attach_jni_env();
+
+            let state = ndk_utils::call_int_method!(env, self.object.0, "getBondState", "()I");
+            take_exception(env)?;
+            Ok(bond_state(state))
+        }
+    }
+
     fn update_name(&mut self, env: *mut ndk_sys::JNIEnv) {
         if self.name.is_some() {
             return;
         }
 
         unsafe {
-            let name =
-                ndk_utils::call_object_method!(env, self.object, "getName", "()Ljava/lang/String;");
+            let name = ndk_utils::call_object_method!(
+                env,
+                self.object.0,
+                "getName",
+                "()Ljava/lang/String;"
+            );
             if !name.is_null() {
                 self.name = Some(ndk_utils::get_utf_str!(env, name).to_string());
+                ((**env).DeleteLocalRef.unwrap())(env, name);
             }
         }
     }
 }
 
 impl Characteristic {
+    /// Queue the string as a write without response, see `send_bytes`.
     pub fn send_string(&self, data: &str) -> Result<(), BluetoothError> {
-        let env = unsafe { android::attach_jni_env() };
-        let mut globals = GLOBALS.lock().unwrap();
+        self.send_bytes(data.as_bytes(), false)
+    }
 
-        let data = std::ffi::CString::new(data).unwrap();
-        let string = unsafe { ((**env).NewStringUTF.unwrap())(env, data.as_ptr()) };
-        unsafe {
-            ndk_utils::call_void_method!(
-                env,
-                globals.quad_bt,
-                "writeCharacteristicString",
-                "(Landroid/bluetooth/BluetoothGattCharacteristic;Ljava/lang/String;)V",
-                self.characteristic,
-                string
-            );
+    /// Queue a write, `verify` asks for a write with response.
+    pub fn send_bytes(&self, data: &[u8], verify: bool) -> Result<(), BluetoothError> {
+        let write_type = WriteType::from_verify(verify);
+        self.properties.require(write_type.required_property())?;
+        let mtu = GLOBALS.lock().unwrap().mtu;
+        if data.len() > gatt::max_write_len(mtu, write_type) {
+            return Err(BluetoothError::PayloadTooLarge);
         }
 
-        Ok(())
+        enqueue(Operation::Write(self.clone(), data.to_vec(), write_type))
     }
 
-    pub fn send_bytes(&self, data: &[u8], verify: bool) -> Result<(), BluetoothError> {
-        info!("send_bytes: {:?} {:?} {:?}", self.id, data, verify);
-        let env = unsafe { android::attach_jni_env() };
-        let mut globals = GLOBALS.lock().unwrap();
-
-        unsafe {
-            let array = (**env).NewByteArray.unwrap()(env, data.len() as _);
-            assert!(!array.is_null());
-            assert!((**env).GetArrayLength.unwrap()(env, array) == data.len() as i32);
-            let temp = (**env).GetPrimitiveArrayCritical.unwrap()(env, array, std::ptr::null_mut());
-            std::ptr::copy_nonoverlapping(data.as_ptr(), temp as _, data.len());
-            (**env).ReleasePrimitiveArrayCritical.unwrap()(env, array, temp, 0);
-
-            ndk_utils::call_void_method!(
-                env,
-                globals.quad_bt,
-                "writeCharacteristicBytes",
-                "(Landroid/bluetooth/BluetoothGattCharacteristic;[BZ)V",
-                self.characteristic,
-                ndk_utils::new_local_ref!(env, array),
-                verify as i32
-            );
-        }
+    /// Request the current value, it arrives as `Message::Data`.
+    pub fn read_value(&self) -> Result<(), BluetoothError> {
+        self.properties.require(CharacteristicProperties::READ)?;
+        enqueue(Operation::Read(self.clone()))
+    }
 
-        Ok(())
+    /// Write a value longer than a single packet with response, as prepared writes.
+    /// Android splits the value into prepared writes itself.
+    ///
+    /// An attribute holds at most `MAX_ATTRIBUTE_LEN` (512) bytes, longer data
+    /// fails with `BluetoothError::PayloadTooLarge`: send it in frames, see `codec::Framed`.
+    pub fn write_long(&self, data: &[u8]) -> Result<(), BluetoothError> {
+        self.send_bytes(data, true)
     }
 
+    /// Enable or disable notifications, confirmed by `Message::SubscriptionChanged`.
     pub fn set_notification(&self, notify: bool) -> Result<(), BluetoothError> {
-        let globals = GLOBALS.lock().unwrap();
-        let env = unsafe { android::attach_jni_env() };
+        self.properties.require(CharacteristicProperties::NOTIFY)?;
+        enqueue(Operation::Subscribe {
+            characteristic: self.clone(),
+            enable: notify,
+            indicate: false,
+        })
+    }
 
-        unsafe {
-            ndk_utils::call_void_method!(
+    /// Enable or disable indications, confirmed by `Message::SubscriptionChanged`.
+    pub fn set_indication(&self, notify: bool) -> Result<(), BluetoothError> {
+        self.properties
+            .require(CharacteristicProperties::INDICATE)?;
+        enqueue(Operation::Subscribe {
+            characteristic: self.clone(),
+            enable: notify,
+            indicate: true,
+        })
+    }
+}
+
+fn enqueue(operation: Operation) -> Result<(), BluetoothError> {
+    let env = unsafe { android::attach_jni_env() };
+    let mut globals = GLOBALS.lock().unwrap();
+
+    if globals.quad_bt.is_null() {
+        return Err(BluetoothError::AdapterNotReady);
+    }
+    globals.state.require_ready()?;
+    if operation
+        .characteristic()
+        .is_some_and(|characteristic| characteristic.discovery != globals.discovery)
+    {
+        return Err(BluetoothError::InvalidHandle);
+    }
+    globals.queue.push(operation)?;
+    unsafe { pump_queue(env, &mut globals) };
+
+    Ok(())
+}
+
+/// Android allows one outstanding GATT operation, writes without response
+/// included: the next one starts from `onOperationComplete`.
+unsafe fn pump_queue(env: *mut ndk_sys::JNIEnv, globals: &mut GlobalData) {
+    while let Some(operation) = globals.queue.start() {
+        let quad_bt = globals.quad_bt;
+        let started = with_local_frame(env, || start_operation(env, quad_bt, &operation));
+        if !started {
+            // a Java exception, or BluetoothGatt refused it: not connected or busy
+            let err = take_exception(env).err().unwrap_or_else(|| {
+                BluetoothError::Platform("GATT operation failed to start".to_string())
+            });
+            info!("GATT operation failed to start: {}", err);
+            if let Some(ref tx) = globals.tx {
+                let _ = tx.send(Message::Error(err));
+            }
+        }
+        // beginning and aborting a reliable write have no completion callback
+        let synchronous = matches!(
+            operation,
+            Operation::BeginReliableWrite | Operation::AbortReliableWrite
+        );
+        if started && !synchronous {
+            return;
+        }
+        globals.queue.complete();
+    }
+}
+
+unsafe fn start_operation(
+    env: *mut ndk_sys::JNIEnv,
+    quad_bt: ndk_sys::jobject,
+    operation: &Operation,
+) -> bool {
+    let started = match operation {
+        Operation::Read(characteristic) => ndk_utils::call_bool_method!(
+            env,
+            quad_bt,
+            "readCharacteristic",
+            "(Landroid/bluetooth/BluetoothGattCharacteristic;)Z",
+            characteristic.characteristic.0
+        ),
+        Operation::Write(characteristic, value, write_type) => {
+            let array = new_byte_array(env, value);
+            ndk_utils::call_bool_method!(
                 env,
-                globals.quad_bt,
-                "setCharacteristicNotification",
-                "(Landroid/bluetooth/BluetoothGattCharacteristic;Z)V",
-                self.characteristic,
-                notify as i32
-            );
+                quad_bt,
+                "writeCharacteristicBytes",
+                "(Landroid/bluetooth/BluetoothGattCharacteristic;[BZ)Z",
+                characteristic.characteristic.0,
+                array,
+                (*write_type == WriteType::WithResponse) as i32
+            )
+        }
+        Operation::Subscribe {
+            characteristic,
+            enable,
+            indicate,
+        } => {
+            let method = if *indicate {
+                "setCharacteristicIndication"
+            } else {
+                "setCharacteristicNotification"
+            };
+            ndk_utils::call_bool_method!(
+                env,
+                quad_bt,
+                method,
+                "(Landroid/bluetooth/BluetoothGattCharacteristic;Z)Z",
+                characteristic.characteristic.0,
+                *enable as i32
+            )
+        }
+        Operation::RequestMtu(mtu) => {
+            ndk_utils::call_bool_method!(env, quad_bt, "requestMtu", "(I)Z", *mtu as i32)
+        }
+        Operation::ReadRssi => {
+            ndk_utils::call_bool_method!(env, quad_bt, "readRemoteRssi", "()Z")
+        }
+        Operation::BeginReliableWrite => {
+            ndk_utils::call_bool_method!(env, quad_bt, "beginReliableWrite", "()Z")
         }
+        Operation::ExecuteReliableWrite => {
+            ndk_utils::call_bool_method!(env, quad_bt, "executeReliableWrite", "()Z")
+        }
+        Operation::AbortReliableWrite => {
+            ndk_utils::call_void_method!(env, quad_bt, "abortReliableWrite", "()V");
+            1
+        }
+    };
+    started != 0
+}
 
-        Ok(())
-    }
+/// `quadbt/QuadBTPermissions` of the activity, `None` until MainActivity is created.
+struct AndroidPermissions(Option<GlobalRef>);
 
-    pub fn set_indication(&self, notify: bool) -> Result<(), BluetoothError> {
-        let globals = GLOBALS.lock().unwrap();
-        let env = unsafe { android::attach_jni_env() };
+impl PermissionProvider for AndroidPermissions {
+    fn granted(&self) -> bool {
+        let permissions = match self.0 {
+            Some(ref permissions) => permissions,
+            None => return false,
+        };
+        unsafe {
+            let env = android::attach_jni_env();
+            ndk_utils::call_bool_method!(env, permissions.0, "granted", "()Z") != 0
+        }
+    }
 
+    fn should_show_rationale(&self) -> bool {
+        let permissions = match self.0 {
+            Some(ref permissions) => permissions,
+            None => return false,
+        };
         unsafe {
-            ndk_utils::call_void_method!(
-                env,
-                globals.quad_bt,
-                "setCharacteristicIndication",
-                "(Landroid/bluetooth/BluetoothGattCharacteristic;Z)V",
-                self.characteristic,
-                notify as i32
-            );
+            let env = android::attach_jni_env();
+            ndk_utils::call_bool_method!(env, permissions.0, "shouldShowRationale", "()Z") != 0
         }
+    }
 
-        Ok(())
+    fn request(&mut self) -> Result<(), BluetoothError> {
+        let permissions = self.0.as_ref().ok_or(BluetoothError::AdapterNotReady)?;
+        unsafe {
+            let env = android::attach_jni_env();
+            ndk_utils::call_void_method!(env, permissions.0, "request", "()V");
+            take_exception(env)
+        }
     }
 }
+
 struct GlobalData {
     quad_bt: ndk_sys::jobject,
     devices: HashMap<String, Device>,
     tx: Option<Sender<Message>>,
     rx: Option<Receiver<Vec<u8>>>,
+    adapter_tx: Option<Sender<AdapterEvent>>,
+    adapter_state: AdapterState,
+    permissions: PermissionFlow<AndroidPermissions>,
+    // `Adapter::request_enable` waits for the user to answer the prompt
+    enable_requested: bool,
+    server: Option<Arc<Mutex<ServerState>>>,
+    // ATT_MTU of the connection
+    mtu: usize,
+    queue: OperationQueue,
+    state: ConnectionState,
+    timeouts: Timeouts,
+    deadline: Option<Deadline>,
+    /// Bumped by every `Adapter::connect`, a dropped `Connection` only
+    /// tears down the link while it is still the current one.
+    generation: u64,
+    /// Bumped whenever the services change, `Characteristic`s from an
+    /// earlier discovery are stale.
+    discovery: u64,
 }
 
 unsafe impl Send for GlobalData {}
@@ -173,21 +396,264 @@ static GLOBALS: Lazy<Mutex<GlobalData>> = Lazy::new(|| {
         devices: HashMap::new(),
         tx: None,
         rx: None,
+        adapter_tx: None,
+        adapter_state: AdapterState::Unknown,
+        permissions: PermissionFlow::new(AndroidPermissions(None)),
+        enable_requested: false,
+        server: None,
+        mtu: DEFAULT_MTU,
+        queue: OperationQueue::new(),
+        state: ConnectionState::default(),
+        timeouts: Timeouts::default(),
+        deadline: None,
+        generation: 0,
+        discovery: 0,
     };
     Mutex::new(data)
 });
 
-const PROPERTY_BROADCAST: i32 = 0x00000001;
 const PROPERTY_READ: i32 = 0x00000002;
+const PROPERTY_WRITE_NO_RESPONSE: i32 = 0x00000004;
 const PROPERTY_WRITE: i32 = 0x00000008;
 const PROPERTY_NOTIFY: i32 = 0x00000010;
 const PROPERTY_INDICATE: i32 = 0x00000020;
 
+const PERMISSION_READ: i32 = 0x00000001;
+const PERMISSION_READ_ENCRYPTED: i32 = 0x00000002;
+const PERMISSION_READ_ENCRYPTED_MITM: i32 = 0x00000004;
+const PERMISSION_WRITE: i32 = 0x00000010;
+const PERMISSION_WRITE_ENCRYPTED: i32 = 0x00000020;
+const PERMISSION_WRITE_ENCRYPTED_MITM: i32 = 0x00000040;
+
+const GATT_SUCCESS: i32 = 0;
+
+fn permissions_to_java(permissions: Permissions) -> i32 {
+    let read = match permissions.read {
+        Access::None => 0,
+        Access::Open => PERMISSION_READ,
+        Access::Encrypted => PERMISSION_READ_ENCRYPTED,
+        Access::EncryptedMitm => PERMISSION_READ_ENCRYPTED_MITM,
+    };
+    let write = match permissions.write {
+        Access::None => 0,
+        Access::Open => PERMISSION_WRITE,
+        Access::Encrypted => PERMISSION_WRITE_ENCRYPTED,
+        Access::EncryptedMitm => PERMISSION_WRITE_ENCRYPTED_MITM,
+    };
+    read | write
+}
+
+/// Run `f` in a local reference frame of its own. Rust threads attached to the
+/// VM never return to Java, the locals `f` creates are only freed with the frame.
+unsafe fn with_local_frame<T>(env: *mut ndk_sys::JNIEnv, f: impl FnOnce() -> T) -> T {
+    // a capacity hint, the frame grows as needed
+    assert!(((**env).PushLocalFrame.unwrap())(env, 16) == 0);
+    let res = f();
+    ((**env).PopLocalFrame.unwrap())(env, std::ptr::null_mut());
+    res
+}
+
+unsafe fn new_byte_array(env: *mut ndk_sys::JNIEnv, data: &[u8]) -> ndk_sys::jobject {
+    let array = (**env).NewByteArray.unwrap()(env, data.len() as _);
+    assert!(!array.is_null());
+    assert!((**env).GetArrayLength.unwrap()(env, array) == data.len() as i32);
+    let temp = (**env).GetPrimitiveArrayCritical.unwrap()(env, array, std::ptr::null_mut());
+    std::ptr::copy_nonoverlapping(data.as_ptr(), temp as _, data.len());
+    (**env).ReleasePrimitiveArrayCritical.unwrap()(env, array, temp, 0);
+    array
+}
+
+unsafe fn get_byte_array(env: *mut ndk_sys::JNIEnv, array: ndk_sys::jobject) -> Vec<u8> {
+    if array.is_null() {
+        return vec![];
+    }
+    let len = ((**env).GetArrayLength.unwrap())(env, array);
+    let elements = ((**env).GetByteArrayElements.unwrap())(env, array, std::ptr::null_mut());
+    let data = std::slice::from_raw_parts(elements as *mut u8, len as usize).to_vec();
+    ((**env).ReleaseByteArrayElements.unwrap())(env, array, elements, 0);
+    data
+}
+
+unsafe fn get_string_array(env: *mut ndk_sys::JNIEnv, array: ndk_sys::jobject) -> Vec<String> {
+    if array.is_null() {
+        return vec![];
+    }
+    let len = ((**env).GetArrayLength.unwrap())(env, array);
+    (0..len)
+        .map(|i| {
+            let string = ((**env).GetObjectArrayElement.unwrap())(env, array, i);
+            let res = ndk_utils::get_utf_str!(env, string).to_string();
+            ((**env).DeleteLocalRef.unwrap())(env, string);
+            res
+        })
+        .collect()
+}
+
+unsafe fn new_string(env: *mut ndk_sys::JNIEnv, string: &str) -> ndk_sys::jobject {
+    let string = std::ffi::CString::new(string).unwrap();
+    ((**env).NewStringUTF.unwrap())(env, string.as_ptr())
+}
+
+unsafe fn new_object_array(
+    env: *mut ndk_sys::JNIEnv,
+    class: &[u8],
+    objects: impl ExactSizeIterator<Item = ndk_sys::jobject>,
+) -> ndk_sys::jobject {
+    let class = (**env).FindClass.unwrap()(env, class.as_ptr() as _);
+    let array =
+        (**env).NewObjectArray.unwrap()(env, objects.len() as _, class, std::ptr::null_mut());
+    for (i, object) in objects.enumerate() {
+        (**env).SetObjectArrayElement.unwrap()(env, array, i as _, object);
+    }
+    array
+}
+
+unsafe fn new_string_array(env: *mut ndk_sys::JNIEnv, strings: &[String]) -> ndk_sys::jobject {
+    new_object_array(
+        env,
+        b"java/lang/String\0",
+        strings.iter().map(|string| new_string(env, string)),
+    )
+}
+
+/// Clear a pending Java exception and turn it into an error.
+unsafe fn take_exception(env: *mut ndk_sys::JNIEnv) -> Result<(), BluetoothError> {
+    if (**env).ExceptionCheck.unwrap()(env) == 0 {
+        return Ok(());
+    }
+    with_local_frame(env, || {
+        let exception = (**env).ExceptionOccurred.unwrap()(env);
+        (**env).ExceptionClear.unwrap()(env);
+
+        // missing BLUETOOTH_SCAN/BLUETOOTH_CONNECT on Android 12+
+        let class = b"java/lang/SecurityException\0";
+        let security = (**env).FindClass.unwrap()(env, class.as_ptr() as _);
+        if (**env).IsInstanceOf.unwrap()(env, exception, security) != 0 {
+            return Err(BluetoothError::PermissionDenied);
+        }
+        let description =
+            ndk_utils::call_object_method!(env, exception, "toString", "()Ljava/lang/String;");
+        Err(BluetoothError::Platform(
+            ndk_utils::get_utf_str!(env, description).to_owned(),
+        ))
+    })
+}
+
+// From `AdvertiseCallback.ADVERTISE_FAILED_*` codes
+fn advertising_error(code: i32) -> AdvertisingError {
+    match code {
+        1 => AdvertisingError::DataTooLarge,
+        2 => AdvertisingError::TooManyAdvertisers,
+        3 => AdvertisingError::AlreadyStarted,
+        5 => AdvertisingError::FeatureUnsupported,
+        code => AdvertisingError::Internal(code),
+    }
+}
+
+fn send_adapter_event(event: AdapterEvent) {
+    if let Some(ref tx) = GLOBALS.lock().unwrap().adapter_tx {
+        let _ = tx.send(event);
+    }
+}
+
+fn set_adapter_state(state: AdapterState) {
+    let mut globals = GLOBALS.lock().unwrap();
+    if globals.adapter_state == state {
+        return;
+    }
+    globals.adapter_state = state;
+    if let Some(ref tx) = globals.adapter_tx {
+        let _ = tx.send(AdapterEvent::StateChanged(state));
+    }
+}
+
+// `BluetoothAdapter.STATE_*`, and the QuadBT.STATE_* codes for what
+// MainActivity finds out before the service is bound
+fn adapter_state(state: i32) -> AdapterState {
+    match state {
+        10 => AdapterState::PoweredOff,
+        11 | 13 => AdapterState::Resetting,
+        12 => AdapterState::PoweredOn,
+        -1 => AdapterState::Unsupported,
+        -2 => AdapterState::Unauthorized,
+        _ => AdapterState::Unknown,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onAdapterStateChanged(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    state: ndk_sys::jint,
+) {
+    set_adapter_state(adapter_state(state));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onPermissionsCreated(
+    env: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    permissions: ndk_sys::jobject,
+) {
+    GLOBALS.lock().unwrap().permissions.provider.0 = Some(GlobalRef::new(env, permissions));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onPermissionsResult(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+) {
+    let status = GLOBALS.lock().unwrap().permissions.on_result();
+    send_adapter_event(AdapterEvent::PermissionsResult(status));
+    // once granted the state follows from the service MainActivity binds
+    if status != PermissionStatus::Granted {
+        set_adapter_state(AdapterState::Unauthorized);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onEnableResult(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    enabled: ndk_sys::jboolean,
+) {
+    let mut globals = GLOBALS.lock().unwrap();
+    globals.enable_requested = false;
+    // the state itself follows with ACTION_STATE_CHANGED
+    if let Some(ref tx) = globals.adapter_tx {
+        let _ = tx.send(AdapterEvent::EnableResult(enabled != 0));
+    }
+}
+
+// `BluetoothDevice.BOND_*`
+fn bond_state(state: i32) -> BondState {
+    match state {
+        11 => BondState::Bonding,
+        12 => BondState::Bonded,
+        _ => BondState::None,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onBondStateChanged(
+    env: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    address: ndk_sys::jobject,
+    state: ndk_sys::jint,
+) {
+    let address = ndk_utils::get_utf_str!(env, address);
+    send_adapter_event(AdapterEvent::BondStateChanged {
+        device: DeviceId(address.to_string()),
+        state: bond_state(state),
+    });
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn Java_quadbt_QuadBT_onDeviceFound(
     env: *mut ndk_sys::JNIEnv,
     _: ndk_sys::jobject,
     device: ndk_sys::jobject,
+    scan_record: ndk_sys::jobject,
 ) {
     let mut globals = GLOBALS.lock().unwrap();
 
@@ -196,31 +662,60 @@ pub unsafe extern "C" fn Java_quadbt_QuadBT_onDeviceFound(
 
     let device_addr = ndk_utils::get_utf_str!(env, device_addr_j);
 
+    let advertisement = AdvertisementData::parse(&get_byte_array(env, scan_record));
+
     globals.devices.insert(
         device_addr.to_string(),
         Device {
             address: device_addr.to_string(),
-            address_j: ndk_utils::new_global_ref!(env, device_addr_j),
-            name: None,
-            object: ndk_utils::new_global_ref!(env, device),
+            address_j: GlobalRef::new(env, device_addr_j),
+            name: advertisement.local_name.clone(),
+            advertisement,
+            object: GlobalRef::new(env, device),
+            seen: Instant::now(),
         },
     );
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onAdvertisingStarted(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+) {
+    send_adapter_event(AdapterEvent::AdvertisingStarted);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onAdvertisingStopped(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+) {
+    send_adapter_event(AdapterEvent::AdvertisingStopped);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onAdvertisingFailed(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    error_code: ndk_sys::jint,
+) {
+    send_adapter_event(AdapterEvent::AdvertisingFailed(advertising_error(
+        error_code,
+    )));
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn Java_quadbt_QuadBT_onDataAvailable(
     env: *mut ndk_sys::JNIEnv,
     _: ndk_sys::jobject,
     array: ndk_sys::jobject,
 ) {
-    let len = ((**env).GetArrayLength.unwrap())(env, array);
-    let elements = ((**env).GetByteArrayElements.unwrap())(env, array, std::ptr::null_mut());
-    let data = std::slice::from_raw_parts(elements as *mut u8, len as usize);
+    let data = get_byte_array(env, array);
 
     let mut globals = GLOBALS.lock().unwrap();
 
     if let Some(ref mut tx) = globals.tx {
-        tx.send(Message::Data(data.to_vec())).unwrap();
+        let _ = tx.send(Message::Data(data));
     }
 }
 
@@ -236,94 +731,790 @@ pub unsafe extern "C" fn Java_quadbt_QuadBT_onCharacteristicDiscovered(
 
     let properties: i32 = ndk_utils::call_int_method!(env, characteristic, "getProperties", "()I");
 
-    let mut globals = GLOBALS.lock().unwrap();
+    let globals = GLOBALS.lock().unwrap();
 
-    if let Some(ref mut tx) = globals.tx {
-        tx.send(Message::CharacteristicDiscovered(Characteristic {
+    if let Some(ref tx) = globals.tx {
+        let _ = tx.send(Message::CharacteristicDiscovered(Characteristic {
             id: uuid.to_owned(),
-            characteristic: ndk_utils::new_global_ref!(env, characteristic),
-            write: (properties & PROPERTY_WRITE) != 0,
-            broadcast: (properties & PROPERTY_BROADCAST) != 0,
-            read: (properties & PROPERTY_READ) != 0,
-            notify: (properties & PROPERTY_NOTIFY) != 0,
-            indicate: (properties & PROPERTY_INDICATE) != 0,
-        }))
-        .unwrap();
+            characteristic: GlobalRef::new(env, characteristic),
+            properties: CharacteristicProperties::from_bits_truncate(properties as u16),
+            discovery: globals.discovery,
+        }));
+    }
+}
+
+/// Invalidate the characteristics of the connection and discover them again,
+/// `clear_cache` drops the services Android cached first.
+unsafe fn rediscover_services(
+    env: *mut ndk_sys::JNIEnv,
+    globals: &mut GlobalData,
+    clear_cache: bool,
+) -> Result<(), BluetoothError> {
+    globals.discovery += 1;
+    if let Some(ref tx) = globals.tx {
+        let _ = tx.send(Message::ServicesChanged);
+        for _ in 0..globals.queue.drop_characteristic_operations() {
+            let _ = tx.send(Message::Error(BluetoothError::InvalidHandle));
+        }
+    }
+    let _ = globals.state.transition(StateEvent::DiscoveryStarted);
+    globals.deadline = Some(Deadline::new(globals.timeouts.discovery, true));
+
+    let started = ndk_utils::call_bool_method!(
+        env,
+        globals.quad_bt,
+        "rediscoverServices",
+        "(Z)Z",
+        clear_cache as i32
+    );
+    take_exception(env)?;
+    if started == 0 {
+        return Err(BluetoothError::DeviceDisconnected);
     }
+    Ok(())
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn Java_quadbt_QuadBT_onGattConnected() {
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onServiceChanged(
+    env: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+) {
     let mut globals = GLOBALS.lock().unwrap();
-    if let Some(ref mut tx) = globals.tx {
-        tx.send(Message::Connected).unwrap();
+    if !globals.state.is_active() {
+        return;
+    }
+    if let Err(err) = rediscover_services(env, &mut globals, false) {
+        info!("service discovery failed to start: {}", err);
     }
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn Java_quadbt_QuadBT_onGattDisconnected() {
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onMtuChanged(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    mtu: ndk_sys::jint,
+) {
     let mut globals = GLOBALS.lock().unwrap();
+    globals.mtu = mtu as usize;
     if let Some(ref mut tx) = globals.tx {
-        tx.send(Message::Disconnected).unwrap();
+        let _ = tx.send(Message::MtuChanged(mtu as usize));
     }
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn Java_quadbt_QuadBT_onServiceConnected() {
-    let env = android::attach_jni_env();
+// `BluetoothDevice.PHY_LE_*`
+fn phy(phy: i32) -> Phy {
+    match phy {
+        2 => Phy::Le2M,
+        3 => Phy::LeCoded,
+        _ => Phy::Le1M,
+    }
+}
 
-    let quad_bt = ndk_utils::new_object!(env, "quadbt/QuadBT", "()V");
-    assert!(!quad_bt.is_null());
+// `BluetoothDevice.PHY_LE_*_MASK`
+fn phy_mask(phy: Phy) -> i32 {
+    match phy {
+        Phy::Le1M => 1,
+        Phy::Le2M => 2,
+        Phy::LeCoded => 4,
+    }
+}
 
-    let quad_bt = ndk_utils::new_global_ref!(env, quad_bt);
-    GLOBALS.lock().unwrap().quad_bt = quad_bt;
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onRssiRead(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    rssi: ndk_sys::jint,
+) {
+    if let Some(ref tx) = GLOBALS.lock().unwrap().tx {
+        let _ = tx.send(Message::Rssi(rssi as i16));
+    }
 }
 
-pub struct Adapter;
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onPhyChanged(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    tx_phy: ndk_sys::jint,
+    rx_phy: ndk_sys::jint,
+) {
+    if let Some(ref tx) = GLOBALS.lock().unwrap().tx {
+        let _ = tx.send(Message::PhyChanged {
+            tx: phy(tx_phy),
+            rx: phy(rx_phy),
+        });
+    }
+}
 
-impl Adapter {
-    pub fn new() -> Result<Adapter, BluetoothError> {
-        Ok(Adapter)
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onConnectionUpdated(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    interval: ndk_sys::jint,
+    latency: ndk_sys::jint,
+    timeout: ndk_sys::jint,
+) {
+    if let Some(ref tx) = GLOBALS.lock().unwrap().tx {
+        let _ = tx.send(Message::ConnectionParametersChanged {
+            interval: Duration::from_micros(interval as u64 * 1250),
+            latency: latency as u16,
+            supervision_timeout: Duration::from_millis(timeout as u64 * 10),
+        });
     }
+}
 
-    pub fn is_ready(&self) -> bool {
-        let quad_bt = GLOBALS.lock().unwrap().quad_bt;
-        if quad_bt.is_null() {
-            return false;
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onSubscriptionChanged(
+    env: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    uuid: ndk_sys::jstring,
+    enabled: ndk_sys::jboolean,
+    indication: ndk_sys::jboolean,
+) {
+    let uuid = ndk_utils::get_utf_str!(env, uuid);
+
+    let mut globals = GLOBALS.lock().unwrap();
+    if let Some(ref mut tx) = globals.tx {
+        let _ = tx.send(Message::SubscriptionChanged {
+            characteristic: uuid.to_owned(),
+            enabled: enabled != 0,
+            indication: indication != 0,
+        });
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onReliableWriteCompleted(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    status: ndk_sys::jint,
+) {
+    let mut globals = GLOBALS.lock().unwrap();
+    if let Some(ref mut tx) = globals.tx {
+        let _ = tx.send(Message::ReliableWriteCompleted(status == GATT_SUCCESS));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onOperationComplete(
+    env: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    status: ndk_sys::jint,
+) {
+    let mut globals = GLOBALS.lock().unwrap();
+    if status != GATT_SUCCESS {
+        let err = BluetoothError::from_gatt_status(status as u16);
+        info!("GATT operation failed: {}", err);
+        if err.requires_pairing() {
+            ndk_utils::call_bool_method!(env, globals.quad_bt, "bondConnected", "()Z");
+            let _ = take_exception(env);
+        }
+        if let Some(ref mut tx) = globals.tx {
+            let _ = tx.send(Message::Error(err));
+        }
+    }
+
+    globals.queue.complete();
+    pump_queue(env, &mut globals);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onGattConnected() {
+    let mut globals = GLOBALS.lock().unwrap();
+    // BluetoothLeService starts service discovery right away
+    if globals.state.transition(StateEvent::Connected).is_err() {
+        info!("unexpected connect in state {:?}", globals.state);
+        return;
+    }
+    let _ = globals.state.transition(StateEvent::DiscoveryStarted);
+    globals.deadline = Some(Deadline::new(globals.timeouts.discovery, false));
+    if let Some(ref mut tx) = globals.tx {
+        let _ = tx.send(Message::Connected);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onServicesDiscovered() {
+    let mut globals = GLOBALS.lock().unwrap();
+    if globals
+        .state
+        .transition(StateEvent::ServicesDiscovered)
+        .is_err()
+    {
+        return;
+    }
+    if let Some(ref mut tx) = globals.tx {
+        let _ = tx.send(Message::ServicesDiscovered);
+    }
+}
+
+/// `onConnectionStateChange` status: an HCI disconnect reason, or GATT_ERROR
+/// when connecting failed.
+fn disconnect_reason(status: i32) -> Option<BluetoothError> {
+    match status {
+        // GATT_SUCCESS and "connection terminated by local host"
+        0 | 0x16 => None,
+        0x08 => Some(BluetoothError::Timeout),
+        0x13 => Some(BluetoothError::DeviceDisconnected),
+        status => Some(BluetoothError::from_gatt_status(status as u16)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onGattDisconnected(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    status: ndk_sys::jint,
+) {
+    let mut globals = GLOBALS.lock().unwrap();
+    // torn down by `abort` already
+    if globals.state.is_disconnected() {
+        return;
+    }
+    globals.queue.clear();
+
+    let reason = disconnect_reason(status);
+    let message = if globals.state == ConnectionState::Connecting {
+        Message::ConnectFailed(reason.clone().unwrap_or(BluetoothError::DeviceUnavailable))
+    } else {
+        Message::Disconnected(reason.clone())
+    };
+    let _ = globals.state.transition(StateEvent::Disconnected(reason));
+    if let Some(ref mut tx) = globals.tx {
+        let _ = tx.send(message);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBT_onServiceConnected() {
+    let env = android::attach_jni_env();
+
+    let quad_bt = ndk_utils::new_object!(env, "quadbt/QuadBT", "()V");
+    assert!(!quad_bt.is_null());
+
+    let quad_bt = ndk_utils::new_global_ref!(env, quad_bt);
+    GLOBALS.lock().unwrap().quad_bt = quad_bt;
+
+    let enabled = ndk_utils::call_bool_method!(env, quad_bt, "isEnabled", "()Z") != 0;
+    set_adapter_state(if enabled {
+        AdapterState::PoweredOn
+    } else {
+        AdapterState::PoweredOff
+    });
+}
+
+fn server_state() -> Option<Arc<Mutex<ServerState>>> {
+    GLOBALS.lock().unwrap().server.clone()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBTServer_onConnectionStateChange(
+    env: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    address: ndk_sys::jstring,
+    connected: ndk_sys::jboolean,
+) {
+    let central = DeviceId(ndk_utils::get_utf_str!(env, address).to_string());
+
+    if let Some(state) = server_state() {
+        let mut state = state.lock().unwrap();
+        if connected != 0 {
+            state.central_connected(&central);
+        } else {
+            state.central_disconnected(&central);
         }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBTServer_onReadRequest(
+    env: *mut ndk_sys::JNIEnv,
+    server: ndk_sys::jobject,
+    device: ndk_sys::jobject,
+    address: ndk_sys::jstring,
+    request_id: ndk_sys::jint,
+    service: ndk_sys::jstring,
+    characteristic: ndk_sys::jstring,
+    offset: ndk_sys::jint,
+) {
+    let request = ReadRequest {
+        central: DeviceId(ndk_utils::get_utf_str!(env, address).to_string()),
+        service: ndk_utils::get_utf_str!(env, service).to_string(),
+        characteristic: ndk_utils::get_utf_str!(env, characteristic).to_string(),
+        offset: offset as usize,
+    };
+
+    let (status, value) = match server_state() {
+        Some(state) => match peripheral::handle_read(&state, &request) {
+            Ok(value) => (GATT_SUCCESS, value),
+            Err(err) => (err as i32, vec![]),
+        },
+        None => (AttError::UnlikelyError as i32, vec![]),
+    };
+
+    ndk_utils::call_void_method!(
+        env,
+        server,
+        "sendResponse",
+        "(Landroid/bluetooth/BluetoothDevice;III[B)V",
+        device,
+        request_id,
+        status,
+        offset,
+        new_byte_array(env, &value)
+    );
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBTServer_onWriteRequest(
+    env: *mut ndk_sys::JNIEnv,
+    server: ndk_sys::jobject,
+    device: ndk_sys::jobject,
+    address: ndk_sys::jstring,
+    request_id: ndk_sys::jint,
+    service: ndk_sys::jstring,
+    characteristic: ndk_sys::jstring,
+    value: ndk_sys::jobject,
+    prepared_write: ndk_sys::jboolean,
+    response_needed: ndk_sys::jboolean,
+    offset: ndk_sys::jint,
+) {
+    let request = WriteRequest {
+        central: DeviceId(ndk_utils::get_utf_str!(env, address).to_string()),
+        service: ndk_utils::get_utf_str!(env, service).to_string(),
+        characteristic: ndk_utils::get_utf_str!(env, characteristic).to_string(),
+        value: get_byte_array(env, value),
+        offset: offset as usize,
+    };
+
+    // prepare writes are held until `onExecuteWrite`
+    let status = match server_state() {
+        Some(state) if prepared_write != 0 => match peripheral::prepare_write(&state, &request) {
+            Ok(()) => GATT_SUCCESS,
+            Err(err) => err as i32,
+        },
+        Some(state) => match peripheral::handle_write(&state, &request) {
+            Ok(()) => GATT_SUCCESS,
+            Err(err) => err as i32,
+        },
+        None => AttError::UnlikelyError as i32,
+    };
+
+    if response_needed != 0 {
+        ndk_utils::call_void_method!(
+            env,
+            server,
+            "sendResponse",
+            "(Landroid/bluetooth/BluetoothDevice;III[B)V",
+            device,
+            request_id,
+            status,
+            offset,
+            value
+        );
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBTServer_onExecuteWrite(
+    env: *mut ndk_sys::JNIEnv,
+    server: ndk_sys::jobject,
+    device: ndk_sys::jobject,
+    address: ndk_sys::jstring,
+    request_id: ndk_sys::jint,
+    execute: ndk_sys::jboolean,
+) {
+    let central = DeviceId(ndk_utils::get_utf_str!(env, address).to_string());
+
+    let status = match server_state() {
+        Some(state) => match peripheral::execute_writes(&state, &central, execute != 0) {
+            Ok(()) => GATT_SUCCESS,
+            Err(err) => err as i32,
+        },
+        None => AttError::UnlikelyError as i32,
+    };
+
+    ndk_utils::call_void_method!(
+        env,
+        server,
+        "sendResponse",
+        "(Landroid/bluetooth/BluetoothDevice;III[B)V",
+        device,
+        request_id,
+        status,
+        0,
+        new_byte_array(env, &[])
+    );
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBTServer_onSubscriptionChange(
+    env: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    address: ndk_sys::jstring,
+    characteristic: ndk_sys::jstring,
+    subscribed: ndk_sys::jboolean,
+) {
+    let central = DeviceId(ndk_utils::get_utf_str!(env, address).to_string());
+    let characteristic = ndk_utils::get_utf_str!(env, characteristic);
+
+    if let Some(state) = server_state() {
+        state
+            .lock()
+            .unwrap()
+            .set_subscribed(&central, characteristic, subscribed != 0);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBTServer_onAdvertisingStarted(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+) {
+    if let Some(state) = server_state() {
+        state.lock().unwrap().send(ServerEvent::AdvertisingStarted);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_quadbt_QuadBTServer_onAdvertisingFailed(
+    _: *mut ndk_sys::JNIEnv,
+    _: ndk_sys::jobject,
+    error_code: ndk_sys::jint,
+) {
+    if let Some(state) = server_state() {
+        state
+            .lock()
+            .unwrap()
+            .send(ServerEvent::AdvertisingFailed(advertising_error(
+                error_code,
+            )));
+    }
+}
+
+unsafe fn connect_device(
+    env: *mut ndk_sys::JNIEnv,
+    globals: &mut GlobalData,
+    device_id: &DeviceId,
+) -> Result<(), BluetoothError> {
+    globals.adapter_state.require_powered_on()?;
+
+    known_device(env, globals, &device_id.0)?;
+    if !globals.state.is_disconnected() {
+        return Err(BluetoothError::InvalidState);
+    }
+
+    let device = &globals.devices[&device_id.0];
+    let started = ndk_utils::call_bool_method!(
+        env,
+        globals.quad_bt,
+        "connect",
+        "(Ljava/lang/String;)Z",
+        device.address_j.0
+    );
+    take_exception(env)?;
+    if started == 0 {
+        return Err(BluetoothError::DeviceUnavailable);
+    }
+
+    globals.mtu = DEFAULT_MTU;
+    globals.queue.clear();
+    globals.state.transition(StateEvent::Connect)?;
+    globals.deadline = Some(Deadline::new(globals.timeouts.connect, false));
+    Ok(())
+}
+
+/// Add a device no scan has seen, by its stored address.
+unsafe fn known_device(
+    env: *mut ndk_sys::JNIEnv,
+    globals: &mut GlobalData,
+    address: &str,
+) -> Result<(), BluetoothError> {
+    if globals.devices.contains_key(address) {
+        return Ok(());
+    }
+
+    let address_j = new_string(env, address);
+    let device_j = ndk_utils::call_object_method!(
+        env,
+        globals.quad_bt,
+        "remoteDevice",
+        "(Ljava/lang/String;)Landroid/bluetooth/BluetoothDevice;",
+        address_j
+    );
+    // IllegalArgumentException for anything but a valid address
+    if take_exception(env).is_err() || device_j.is_null() {
+        ((**env).DeleteLocalRef.unwrap())(env, address_j);
+        return Err(BluetoothError::DeviceUnavailable);
+    }
+
+    let mut device = Device {
+        object: GlobalRef::new(env, device_j),
+        address: address.to_string(),
+        address_j: GlobalRef::new(env, address_j),
+        name: None,
+        advertisement: AdvertisementData::default(),
+        seen: Instant::now(),
+    };
+    device.update_name(env);
+    ((**env).DeleteLocalRef.unwrap())(env, address_j);
+    ((**env).DeleteLocalRef.unwrap())(env, device_j);
+    globals.devices.insert(address.to_string(), device);
+    Ok(())
+}
+
+/// Tear the connection down right away, no callbacks follow.
+unsafe fn abort(env: *mut ndk_sys::JNIEnv, globals: &mut GlobalData, reason: BluetoothError) {
+    let rediscovery = globals
+        .deadline
+        .is_some_and(|deadline| deadline.rediscovery);
+    let message = if globals.state.is_connecting() && !rediscovery {
+        Message::ConnectFailed(reason.clone())
+    } else {
+        Message::Disconnected(Some(reason.clone()))
+    };
+    ndk_utils::call_void_method!(env, globals.quad_bt, "cancel", "()V");
+    globals.queue.clear();
+    let _ = globals
+        .state
+        .transition(StateEvent::Disconnected(Some(reason)));
+    if let Some(ref tx) = globals.tx {
+        let _ = tx.send(message);
+    }
+}
+
+fn check_timeouts() {
+    let env = unsafe { android::attach_jni_env() };
+    let mut globals = GLOBALS.lock().unwrap();
+
+    if globals.state.is_connecting()
+        && globals
+            .deadline
+            .is_some_and(|deadline| deadline.expired(Instant::now()))
+    {
+        unsafe { abort(env, &mut globals, BluetoothError::Timeout) };
+    } else if globals.state.is_ready() && globals.queue.overdue(globals.timeouts.operation) {
+        if let Some(ref tx) = globals.tx {
+            let _ = tx.send(Message::Error(BluetoothError::Timeout));
+        }
+        unsafe { abort(env, &mut globals, BluetoothError::Timeout) };
+    }
+}
+
+/// Queue an RSSI read when the monitor is due, one at a time.
+fn monitor_rssi(monitor: &mut RssiMonitor) {
+    let globals = GLOBALS.lock().unwrap();
+    let idle = globals.state.is_ready() && !globals.queue.rssi_queued();
+    drop(globals);
+    if idle && monitor.due() {
+        let _ = enqueue(Operation::ReadRssi);
+    }
+}
+
+/// Connect again to the device of a `Connection`, on the same channel.
+pub(crate) fn reconnect_device(device_id: &DeviceId) -> Result<(), BluetoothError> {
+    let env = unsafe { android::attach_jni_env() };
+
+    unsafe { connect_device(env, &mut GLOBALS.lock().unwrap(), device_id) }
+}
+
+/// A running scan saw the device after `since`.
+pub(crate) fn device_seen_since(device_id: &DeviceId, since: Instant) -> bool {
+    GLOBALS
+        .lock()
+        .unwrap()
+        .devices
+        .get(&device_id.0)
+        .map_or(false, |device| device.seen >= since)
+}
+
+pub struct Adapter {
+    rx: Receiver<AdapterEvent>,
+}
+
+impl Adapter {
+    pub fn new() -> Result<Adapter, BluetoothError> {
+        let (tx, rx) = mpsc::channel();
+        let mut globals = GLOBALS.lock().unwrap();
+        let _ = tx.send(AdapterEvent::StateChanged(globals.adapter_state));
+        globals.adapter_tx = Some(tx);
+        drop(globals);
+
+        Ok(Adapter { rx })
+    }
+
+    pub fn try_recv(&mut self) -> Result<Option<AdapterEvent>, BluetoothError> {
+        Ok(self.rx.try_recv().ok())
+    }
+
+    /// Bluetooth availability, changes arrive as `AdapterEvent::StateChanged`.
+    /// `Unknown` until the Bluetooth service is bound, which only happens
+    /// once the permissions are granted.
+    pub fn state(&self) -> AdapterState {
+        GLOBALS.lock().unwrap().adapter_state
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.state() == AdapterState::PoweredOn
+    }
+
+    pub fn permission_status(&self) -> PermissionStatus {
+        GLOBALS.lock().unwrap().permissions.status()
+    }
+
+    /// Ask the user for the Bluetooth permissions, the answer arrives as
+    /// `AdapterEvent::PermissionsResult`. Only shows the system prompt
+    /// when the permissions are not granted or denied for good.
+    pub fn request_permissions(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        if let Some(status) = globals.permissions.request()? {
+            if let Some(ref tx) = globals.adapter_tx {
+                let _ = tx.send(AdapterEvent::PermissionsResult(status));
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the user to turn Bluetooth on, the answer arrives as
+    /// `AdapterEvent::EnableResult`.
+    pub fn request_enable(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        if globals.enable_requested {
+            return Err(BluetoothError::OperationInProgress);
+        }
+        match globals.adapter_state {
+            AdapterState::PoweredOn => {
+                if let Some(ref tx) = globals.adapter_tx {
+                    let _ = tx.send(AdapterEvent::EnableResult(true));
+                }
+                return Ok(());
+            }
+            AdapterState::PoweredOff if !globals.quad_bt.is_null() => {}
+            AdapterState::PoweredOff => return Err(BluetoothError::AdapterNotReady),
+            state => state.require_powered_on()?,
+        }
+        let quad_bt = globals.quad_bt;
 
         unsafe {
             let env = android::attach_jni_env();
 
-            ndk_utils::call_bool_method!(env, quad_bt, "isEnabled", "()Z") != 0
+            ndk_utils::call_void_method!(env, quad_bt, "requestEnable", "()V");
+            take_exception(env)?;
         }
+        globals.enable_requested = true;
+        Ok(())
     }
 
     pub fn start_scan(&mut self) -> Result<(), BluetoothError> {
-        let quad_bt = GLOBALS.lock().unwrap().quad_bt;
-        if quad_bt.is_null() {
-            return Err(BluetoothError::AdapterNotReady);
-        }
+        let globals = GLOBALS.lock().unwrap();
+        globals.adapter_state.require_powered_on()?;
+        let quad_bt = globals.quad_bt;
+        drop(globals);
 
         unsafe {
             let env = android::attach_jni_env();
 
             ndk_utils::call_void_method!(env, quad_bt, "startScan", "()V");
+            take_exception(env)
         }
-
-        Ok(())
     }
 
     pub fn walk_devices<F: FnMut(&Device)>(&mut self, mut f: F) -> Result<(), BluetoothError> {
         let env = unsafe { android::attach_jni_env() };
 
         let mut globals = GLOBALS.lock().unwrap();
+        globals
+            .devices
+            .values_mut()
+            .for_each(|d| d.update_name(env));
+        // `f` may call into the adapter, e.g. `Device::bond`
+        let devices: Vec<Device> = globals.devices.values().cloned().collect();
+        drop(globals);
+        devices.iter().for_each(|d| f(d));
 
-        globals.devices.values_mut().for_each(|d| {
-            d.update_name(env);
-            f(d)
-        });
+        Ok(())
+    }
+
+    /// Bonded LE devices, whether seen by a scan or not.
+    pub fn bonded_devices(&self) -> Result<Vec<DeviceId>, BluetoothError> {
+        let globals = GLOBALS.lock().unwrap();
+        globals.adapter_state.require_powered_on()?;
+        let quad_bt = globals.quad_bt;
+        drop(globals);
+
+        unsafe {
+            let env = android::attach_jni_env();
+
+            with_local_frame(env, || {
+                let addresses = ndk_utils::call_object_method!(
+                    env,
+                    quad_bt,
+                    "bondedDevices",
+                    "()[Ljava/lang/String;"
+                );
+                take_exception(env)?;
+
+                Ok(get_string_array(env, addresses)
+                    .into_iter()
+                    .map(DeviceId)
+                    .collect())
+            })
+        }
+    }
 
+    /// Devices with a GATT connection to any app, e.g. the user's device
+    /// connected by the system. Android can not tell their services without
+    /// connecting, `services` is ignored.
+    pub fn connected_devices(&self, services: &[&str]) -> Result<Vec<DeviceId>, BluetoothError> {
+        let globals = GLOBALS.lock().unwrap();
+        globals.adapter_state.require_powered_on()?;
+        let quad_bt = globals.quad_bt;
+        drop(globals);
+
+        unsafe {
+            let env = android::attach_jni_env();
+
+            with_local_frame(env, || {
+                let addresses = ndk_utils::call_object_method!(
+                    env,
+                    quad_bt,
+                    "connectedDevices",
+                    "()[Ljava/lang/String;"
+                );
+                take_exception(env)?;
+
+                Ok(get_string_array(env, addresses)
+                    .into_iter()
+                    .map(DeviceId)
+                    .collect())
+            })
+        }
+    }
+
+    /// Forget the bond. Android has no public API for it, this uses the
+    /// hidden `BluetoothDevice.removeBond` and may fail on newer versions.
+    pub fn remove_bond(&mut self, device_id: &DeviceId) -> Result<(), BluetoothError> {
+        let globals = GLOBALS.lock().unwrap();
+        globals.adapter_state.require_powered_on()?;
+        let quad_bt = globals.quad_bt;
+        drop(globals);
+
+        unsafe {
+            let env = android::attach_jni_env();
+
+            let address = new_string(env, &device_id.0);
+            let removed = ndk_utils::call_bool_method!(
+                env,
+                quad_bt,
+                "removeBond",
+                "(Ljava/lang/String;)Z",
+                address
+            );
+            ((**env).DeleteLocalRef.unwrap())(env, address);
+            take_exception(env)?;
+            if removed == 0 {
+                return Err(BluetoothError::NotSupported);
+            }
+        }
         Ok(())
     }
 
@@ -336,53 +1527,309 @@ impl Adapter {
             .and_then(|d| d.name.clone())
     }
 
+    /// Timeouts for the following connects and the operations of the connection.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        GLOBALS.lock().unwrap().timeouts = timeouts;
+    }
+
     pub fn connect(&mut self, device_id: DeviceId) -> Result<Connection, BluetoothError> {
         let env = unsafe { android::attach_jni_env() };
 
         let mut globals = GLOBALS.lock().unwrap();
-
-        if globals.quad_bt.is_null() {
-            return Err(BluetoothError::AdapterNotReady);
-        }
-
-        if !globals.devices.contains_key(&device_id.0) {
-            return Err(BluetoothError::DeviceUnavailable);
-        }
-
-        let device = &globals.devices[&device_id.0];
-        unsafe {
-            ndk_utils::call_void_method!(
-                env,
-                globals.quad_bt,
-                "connect",
-                "(Ljava/lang/String;)V",
-                device.address_j
-            );
-        }
+        unsafe { connect_device(env, &mut globals, &device_id)? };
 
         let (_, rx) = mpsc::channel();
         let (tx, client_rx) = mpsc::channel();
 
         globals.tx = Some(tx);
         globals.rx = Some(rx);
+        globals.generation += 1;
+        let generation = globals.generation;
 
         Ok(Connection {
             device_id,
             rx: client_rx,
+            reconnect: None,
+            rssi_monitor: None,
+            generation,
         })
     }
+
+    /// Broadcast an advertisement, independent of any GATT server.
+    /// The outcome arrives as an `AdapterEvent`.
+    pub fn start_advertising(
+        &mut self,
+        data: AdvertisementData,
+        settings: AdvertisingSettings,
+    ) -> Result<(), BluetoothError> {
+        let env = unsafe { android::attach_jni_env() };
+        let quad_bt = GLOBALS.lock().unwrap().quad_bt;
+        if quad_bt.is_null() {
+            return Err(BluetoothError::AdapterNotReady);
+        }
+
+        // AdvertiseSettings.ADVERTISE_MODE_* and ADVERTISE_TX_POWER_*
+        let mode = match settings.mode {
+            AdvertisingMode::LowPower => 0,
+            AdvertisingMode::Balanced => 1,
+            AdvertisingMode::LowLatency => 2,
+        };
+        let tx_power = match settings.tx_power {
+            TxPower::UltraLow => 0,
+            TxPower::Low => 1,
+            TxPower::Medium => 2,
+            TxPower::High => 3,
+        };
+        let timeout = settings
+            .timeout
+            .map_or(0, |timeout| timeout.as_millis() as i32);
+
+        unsafe {
+            with_local_frame(env, || {
+                let manufacturer_ids =
+                    (**env).NewIntArray.unwrap()(env, data.manufacturer_data.len() as _);
+                let ids = data
+                    .manufacturer_data
+                    .iter()
+                    .map(|(id, _)| *id as i32)
+                    .collect::<Vec<_>>();
+                (**env).SetIntArrayRegion.unwrap()(
+                    env,
+                    manufacturer_ids,
+                    0,
+                    ids.len() as _,
+                    ids.as_ptr(),
+                );
+                let manufacturer_data = new_object_array(
+                    env,
+                    b"[B\0",
+                    data.manufacturer_data
+                        .iter()
+                        .map(|(_, payload)| new_byte_array(env, payload)),
+                );
+                let service_data_uuids = data
+                    .service_data
+                    .iter()
+                    .map(|(uuid, _)| uuid.clone())
+                    .collect::<Vec<_>>();
+                let service_data = new_object_array(
+                    env,
+                    b"[B\0",
+                    data.service_data
+                        .iter()
+                        .map(|(_, payload)| new_byte_array(env, payload)),
+                );
+
+                ndk_utils::call_void_method!(
+                    env,
+                    quad_bt,
+                    "startAdvertising",
+                    "(ZIIIZZ[Ljava/lang/String;[I[[B[Ljava/lang/String;[[B)V",
+                    settings.connectable as i32,
+                    mode,
+                    tx_power,
+                    timeout,
+                    data.local_name.is_some() as i32,
+                    data.tx_power_level.is_some() as i32,
+                    new_string_array(env, &data.service_uuids),
+                    manufacturer_ids,
+                    manufacturer_data,
+                    new_string_array(env, &service_data_uuids),
+                    service_data
+                );
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn stop_advertising(&mut self) -> Result<(), BluetoothError> {
+        let env = unsafe { android::attach_jni_env() };
+        let quad_bt = GLOBALS.lock().unwrap().quad_bt;
+        if quad_bt.is_null() {
+            return Err(BluetoothError::AdapterNotReady);
+        }
+
+        unsafe {
+            ndk_utils::call_void_method!(env, quad_bt, "stopAdvertising", "()V");
+        }
+
+        Ok(())
+    }
+
+    /// Start a local GATT server. Only one server may be open at a time.
+    pub fn open_gatt_server(
+        &mut self,
+        services: Vec<LocalService>,
+    ) -> Result<GattServer, BluetoothError> {
+        let env = unsafe { android::attach_jni_env() };
+
+        let mut globals = GLOBALS.lock().unwrap();
+
+        if globals.quad_bt.is_null() {
+            return Err(BluetoothError::AdapterNotReady);
+        }
+
+        let object = unsafe {
+            with_local_frame(env, || {
+                let server = ndk_utils::call_object_method!(
+                    env,
+                    globals.quad_bt,
+                    "createServer",
+                    "()Lquadbt/QuadBTServer;"
+                );
+                GlobalRef::new(env, server)
+            })
+        };
+
+        // a frame per attribute, a large database would overflow a single one
+        for service in &services {
+            unsafe {
+                with_local_frame(env, || {
+                    ndk_utils::call_void_method!(
+                        env,
+                        object.0,
+                        "addService",
+                        "(Ljava/lang/String;Z)V",
+                        new_string(env, &service.uuid),
+                        service.primary as i32
+                    )
+                });
+            }
+
+            for characteristic in &service.characteristics {
+                let mut properties = 0;
+                if characteristic.read {
+                    properties |= PROPERTY_READ;
+                }
+                if characteristic.write {
+                    properties |= PROPERTY_WRITE;
+                }
+                if characteristic.write_without_response {
+                    properties |= PROPERTY_WRITE_NO_RESPONSE;
+                }
+                if characteristic.notify {
+                    properties |= PROPERTY_NOTIFY;
+                }
+                if characteristic.indicate {
+                    properties |= PROPERTY_INDICATE;
+                }
+
+                unsafe {
+                    with_local_frame(env, || {
+                        ndk_utils::call_void_method!(
+                            env,
+                            object.0,
+                            "addCharacteristic",
+                            "(Ljava/lang/String;II[B)V",
+                            new_string(env, &characteristic.uuid),
+                            properties,
+                            permissions_to_java(characteristic.permissions),
+                            new_byte_array(env, &characteristic.value)
+                        );
+
+                        for descriptor in &characteristic.descriptors {
+                            ndk_utils::call_void_method!(
+                                env,
+                                object.0,
+                                "addDescriptor",
+                                "(Ljava/lang/String;I[B)V",
+                                new_string(env, &descriptor.uuid),
+                                permissions_to_java(descriptor.permissions),
+                                new_byte_array(env, &descriptor.value)
+                            );
+                        }
+                        if characteristic.notify || characteristic.indicate {
+                            ndk_utils::call_void_method!(
+                                env,
+                                object.0,
+                                "addDescriptor",
+                                "(Ljava/lang/String;I[B)V",
+                                new_string(env, CCCD_UUID),
+                                PERMISSION_READ | PERMISSION_WRITE,
+                                new_byte_array(env, &[0, 0])
+                            );
+                        }
+                    })
+                }
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let state = ServerState::new(services, tx);
+        globals.server = Some(state.clone());
+
+        let opened = unsafe { ndk_utils::call_bool_method!(env, object.0, "open", "()Z") != 0 };
+        if !opened {
+            globals.server = None;
+            return Err(BluetoothError::AdapterNotReady);
+        }
+
+        Ok(GattServer { object, state, rx })
+    }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Message {
     Connected,
-    Disconnected,
+    /// Connecting failed, the connection is unusable.
+    ConnectFailed(BluetoothError),
+    /// `None` after `Connection::disconnect`, the reason otherwise.
+    Disconnected(Option<BluetoothError>),
+    /// A queued operation failed.
+    Error(BluetoothError),
+    /// All characteristics were reported, the connection is ready for GATT operations.
+    ServicesDiscovered,
+    /// The peripheral changed its services, or `Connection::refresh_services` was
+    /// called. Every `Characteristic` received so far is invalid and operations on
+    /// it fail with `BluetoothError::InvalidHandle`, queued ones included.
+    /// Discovery runs again and reports the characteristics anew.
+    ServicesChanged,
     Data(Vec<u8>),
     CharacteristicDiscovered(Characteristic),
+    /// ATT_MTU negotiated, in response to `Connection::request_mtu`.
+    MtuChanged(usize),
+    /// Signal strength of the connection in dBm, in response to `Connection::read_rssi`.
+    Rssi(i16),
+    /// Outcome of `Connection::execute_reliable_write`.
+    ReliableWriteCompleted(bool),
+    /// The peripheral confirmed the change of its client configuration,
+    /// after `set_notification` or `set_indication`.
+    SubscriptionChanged {
+        characteristic: String,
+        enabled: bool,
+        /// Set by `set_indication`, clear by `set_notification`.
+        indication: bool,
+    },
+    /// The link was lost, another attempt follows after the backoff.
+    /// Only with a `ReconnectPolicy`, instead of `Disconnected`.
+    Reconnecting {
+        attempt: u32,
+        reason: BluetoothError,
+    },
+    /// The link is back and the subscriptions are restored.
+    Reconnected,
+    /// The link layer agreed on new connection parameters,
+    /// e.g. after `Connection::request_connection_priority`.
+    ConnectionParametersChanged {
+        interval: Duration,
+        /// Connection events the peripheral may skip.
+        latency: u16,
+        supervision_timeout: Duration,
+    },
+    /// PHYs in use, after `Connection::set_preferred_phy`.
+    PhyChanged {
+        tx: Phy,
+        rx: Phy,
+    },
 }
 
 pub struct Connection {
     device_id: DeviceId,
     rx: Receiver<Message>,
+    reconnect: Option<Box<Reconnector>>,
+    rssi_monitor: Option<RssiMonitor>,
+    generation: u64,
 }
 
 impl Connection {
@@ -390,18 +1837,371 @@ impl Connection {
         self.device_id.clone()
     }
 
+    pub fn state(&self) -> ConnectionState {
+        GLOBALS.lock().unwrap().state.clone()
+    }
+
     pub fn try_recv(&mut self) -> Result<Option<Message>, BluetoothError> {
-        Ok(self.rx.try_recv().ok())
+        check_timeouts();
+        if let Some(ref mut monitor) = self.rssi_monitor {
+            monitor_rssi(monitor);
+        }
+
+        let message = match self.reconnect {
+            Some(ref mut reconnect) => reconnect.try_recv(&self.device_id, &self.rx),
+            None => Ok(self.rx.try_recv().ok()),
+        };
+        if let (Some(monitor), Ok(Some(Message::Rssi(rssi)))) = (&mut self.rssi_monitor, &message) {
+            monitor.record(*rssi);
+        }
+        message
+    }
+
+    /// Reconnect on its own when the link is lost, `None` to stop.
+    /// Notifications and indications enabled after this are restored on reconnect.
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        let connected = matches!(
+            self.state(),
+            ConnectionState::Connected
+                | ConnectionState::DiscoveringServices
+                | ConnectionState::Ready
+        );
+        self.reconnect = policy.map(|policy| Box::new(Reconnector::new(policy, connected)));
+    }
+
+    /// Drop the cached services and discover them again, in case the peripheral
+    /// changed them without telling. Reported as `Message::ServicesChanged`.
+    /// Android drops its cache through a hidden API that may be unavailable.
+    pub fn refresh_services(&mut self) -> Result<(), BluetoothError> {
+        let env = unsafe { android::attach_jni_env() };
+        let mut globals = GLOBALS.lock().unwrap();
+        globals.state.require_ready()?;
+        unsafe { rediscover_services(env, &mut globals, true) }
+    }
+
+    /// Read the signal strength of the connection, the result arrives as `Message::Rssi`.
+    pub fn read_rssi(&mut self) -> Result<(), BluetoothError> {
+        enqueue(Operation::ReadRssi)
+    }
+
+    /// Read the RSSI every `interval` while the connection is ready, `None` to stop.
+    /// Each reading arrives as `Message::Rssi`.
+    pub fn set_rssi_interval(&mut self, interval: Option<Duration>) {
+        self.rssi_monitor = interval.map(RssiMonitor::new);
+    }
+
+    /// Mean of the last `RSSI_WINDOW` readings since `set_rssi_interval`,
+    /// `None` without an interval or before the first reading.
+    pub fn average_rssi(&self) -> Option<i16> {
+        self.rssi_monitor.as_ref()?.average()
+    }
+
+    /// Ask for a larger ATT_MTU, the result arrives as `Message::MtuChanged`.
+    pub fn request_mtu(&mut self, mtu: usize) -> Result<(), BluetoothError> {
+        enqueue(Operation::RequestMtu(mtu))
+    }
+
+    /// Ask for a shorter or longer connection interval, the parameters the
+    /// link layer settles on arrive as `Message::ConnectionParametersChanged`.
+    pub fn request_connection_priority(
+        &mut self,
+        priority: ConnectionPriority,
+    ) -> Result<(), BluetoothError> {
+        let globals = GLOBALS.lock().unwrap();
+        globals.state.require_ready()?;
+
+        // `BluetoothGatt.CONNECTION_PRIORITY_*`
+        let priority = match priority {
+            ConnectionPriority::Balanced => 0,
+            ConnectionPriority::High => 1,
+            ConnectionPriority::LowPower => 2,
+        };
+        unsafe {
+            let env = android::attach_jni_env();
+
+            let started = ndk_utils::call_bool_method!(
+                env,
+                globals.quad_bt,
+                "requestConnectionPriority",
+                "(I)Z",
+                priority
+            );
+            take_exception(env)?;
+            if started == 0 {
+                return Err(BluetoothError::OperationInProgress);
+            }
+        }
+        Ok(())
+    }
+
+    /// Prefer these PHYs, what both sides support is reported as `Message::PhyChanged`.
+    pub fn set_preferred_phy(&mut self, tx: Phy, rx: Phy) -> Result<(), BluetoothError> {
+        let globals = GLOBALS.lock().unwrap();
+        globals.state.require_ready()?;
+
+        unsafe {
+            let env = android::attach_jni_env();
+
+            let started = ndk_utils::call_bool_method!(
+                env,
+                globals.quad_bt,
+                "setPreferredPhy",
+                "(III)Z",
+                phy_mask(tx),
+                phy_mask(rx),
+                0
+            );
+            take_exception(env)?;
+            if started == 0 {
+                return Err(BluetoothError::DeviceDisconnected);
+            }
+        }
+        Ok(())
+    }
+
+    /// Start a reliable write transaction: the following writes with response
+    /// are held by the peripheral until `execute_reliable_write`.
+    pub fn begin_reliable_write(&mut self) -> Result<(), BluetoothError> {
+        enqueue(Operation::BeginReliableWrite)
+    }
+
+    /// Commit the transaction, the outcome arrives as `Message::ReliableWriteCompleted`.
+    pub fn execute_reliable_write(&mut self) -> Result<(), BluetoothError> {
+        enqueue(Operation::ExecuteReliableWrite)
+    }
+
+    /// Drop the writes of the transaction.
+    pub fn abort_reliable_write(&mut self) -> Result<(), BluetoothError> {
+        enqueue(Operation::AbortReliableWrite)
+    }
+
+    /// Operations queued or in flight on this connection.
+    pub fn queue_depth(&self) -> usize {
+        GLOBALS.lock().unwrap().queue.depth()
+    }
+
+    pub fn queue_limit(&self) -> usize {
+        GLOBALS.lock().unwrap().queue.limit
+    }
+
+    /// Operations beyond this many fail with `BluetoothError::QueueFull`.
+    pub fn set_queue_limit(&mut self, limit: usize) {
+        GLOBALS.lock().unwrap().queue.limit = limit;
+    }
+
+    /// Current ATT_MTU.
+    pub fn mtu(&self) -> usize {
+        GLOBALS.lock().unwrap().mtu
+    }
+
+    /// Longest value a single `send_bytes` of the given type accepts.
+    pub fn max_write_len(&self, write_type: WriteType) -> usize {
+        gatt::max_write_len(self.mtu(), write_type)
     }
 
     pub fn disconnect(&mut self) -> Result<(), BluetoothError> {
+        let disconnected = self.state().is_disconnected();
+        if let Some(ref mut reconnect) = self.reconnect {
+            if disconnected {
+                reconnect.stop(Message::Disconnected(None));
+            }
+        }
         let env = unsafe { android::attach_jni_env() };
         let mut globals = GLOBALS.lock().unwrap();
 
+        if !globals.state.is_active() {
+            return Ok(());
+        }
+        // a pending connect does not always report the disconnect,
+        // close the GATT client and report it right away
+        if globals.state.is_connecting() {
+            unsafe {
+                ndk_utils::call_void_method!(env, globals.quad_bt, "cancel", "()V");
+            }
+            globals.queue.clear();
+            globals.state.transition(StateEvent::Disconnected(None))?;
+            if let Some(ref tx) = globals.tx {
+                let _ = tx.send(Message::Disconnected(None));
+            }
+            return Ok(());
+        }
+        globals.state.transition(StateEvent::Disconnect)?;
         unsafe {
             ndk_utils::call_void_method!(env, globals.quad_bt, "disconnect", "()V");
         }
 
         Ok(())
     }
+
+    /// Abort a pending connect, or a reconnect waiting for its next attempt.
+    /// `Message::ConnectFailed(BluetoothError::Cancelled)` follows.
+    pub fn cancel(&mut self) -> Result<(), BluetoothError> {
+        let disconnected = self.state().is_disconnected();
+        if let Some(ref mut reconnect) = self.reconnect {
+            if disconnected && reconnect.stop(Message::ConnectFailed(BluetoothError::Cancelled)) {
+                return Ok(());
+            }
+        }
+        let env = unsafe { android::attach_jni_env() };
+        let mut globals = GLOBALS.lock().unwrap();
+
+        if !globals.state.is_connecting() {
+            return Err(BluetoothError::InvalidState);
+        }
+        unsafe { abort(env, &mut globals, BluetoothError::Cancelled) };
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    /// Disconnect and close the GATT client, Android only has a few of them.
+    fn drop(&mut self) {
+        let env = unsafe { android::attach_jni_env() };
+        let mut globals = GLOBALS.lock().unwrap();
+        if globals.generation != self.generation {
+            return;
+        }
+        unsafe {
+            ndk_utils::call_void_method!(env, globals.quad_bt, "cancel", "()V");
+        }
+        globals.queue.clear();
+        let _ = globals.state.transition(StateEvent::Disconnected(None));
+        globals.tx = None;
+    }
+}
+
+pub struct GattServer {
+    object: GlobalRef,
+    state: Arc<Mutex<ServerState>>,
+    rx: Receiver<ServerEvent>,
+}
+
+impl GattServer {
+    pub fn set_read_handler<F>(&mut self, f: F)
+    where
+        F: FnMut(&ReadRequest) -> Result<Vec<u8>, AttError> + Send + 'static,
+    {
+        self.state.lock().unwrap().set_read_handler(f);
+    }
+
+    pub fn set_write_handler<F>(&mut self, f: F)
+    where
+        F: FnMut(&WriteRequest) -> Result<(), AttError> + Send + 'static,
+    {
+        self.state.lock().unwrap().set_write_handler(f);
+    }
+
+    /// Advertise the primary services of this server.
+    pub fn start_advertising(&mut self) -> Result<(), BluetoothError> {
+        let env = unsafe { android::attach_jni_env() };
+
+        let uuids = self
+            .state
+            .lock()
+            .unwrap()
+            .services
+            .iter()
+            .filter(|service| service.primary)
+            .map(|service| service.uuid.clone())
+            .collect::<Vec<_>>();
+
+        unsafe {
+            with_local_frame(env, || {
+                ndk_utils::call_void_method!(
+                    env,
+                    self.object.0,
+                    "startAdvertising",
+                    "([Ljava/lang/String;)V",
+                    new_string_array(env, &uuids)
+                )
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn stop_advertising(&mut self) -> Result<(), BluetoothError> {
+        let env = unsafe { android::attach_jni_env() };
+
+        unsafe {
+            ndk_utils::call_void_method!(env, self.object.0, "stopAdvertising", "()V");
+        }
+
+        Ok(())
+    }
+
+    /// Send a new value to every central subscribed to the characteristic.
+    pub fn notify(&self, characteristic: &str, value: &[u8]) -> Result<(), BluetoothError> {
+        let env = unsafe { android::attach_jni_env() };
+
+        let (service, confirm, subscribers) = {
+            let state = self.state.lock().unwrap();
+            let (service, local) = state
+                .services
+                .iter()
+                .find_map(|service| {
+                    service
+                        .characteristics
+                        .iter()
+                        .find(|c| peripheral::uuid_eq(&c.uuid, characteristic))
+                        .map(|c| (service.uuid.clone(), c))
+                })
+                .ok_or(BluetoothError::DeviceUnavailable)?;
+            (
+                service,
+                local.indicate && !local.notify,
+                state.subscribers(characteristic),
+            )
+        };
+
+        for central in subscribers {
+            unsafe {
+                with_local_frame(env, || {
+                    ndk_utils::call_bool_method!(
+                        env,
+                        self.object.0,
+                        "notify",
+                        "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;[BZ)Z",
+                        new_string(env, &central.0),
+                        new_string(env, &service),
+                        new_string(env, characteristic),
+                        new_byte_array(env, value),
+                        confirm as i32
+                    )
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn try_recv(&mut self) -> Result<Option<ServerEvent>, BluetoothError> {
+        Ok(self.rx.try_recv().ok())
+    }
+
+    /// Stop advertising and drop all the connected centrals, also done on drop.
+    pub fn close(&mut self) -> Result<(), BluetoothError> {
+        let env = unsafe { android::attach_jni_env() };
+
+        unsafe {
+            ndk_utils::call_void_method!(env, self.object.0, "close", "()V");
+        }
+        let mut globals = GLOBALS.lock().unwrap();
+        // requests go to the newer server if this one was replaced
+        if globals
+            .server
+            .as_ref()
+            .is_some_and(|server| Arc::ptr_eq(server, &self.state))
+        {
+            globals.server = None;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for GattServer {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
 }
@@ -0,0 +1,485 @@
+//! Framing over notification streams.
+//!
+//! Notifications carry at most `mtu - 3` bytes and a peripheral is free to
+//! split a message anywhere, so a `Framed` reassembles the `Message::Data`
+//! fragments of one characteristic into complete frames, and splits outgoing
+//! frames into writes that fit the MTU.
+
+use crate::gatt::{WriteType, ATT_WRITE_HEADER_LEN, DEFAULT_MTU};
+use crate::{BluetoothError, Characteristic, Connection};
+
+/// Frames are dropped with `FrameError::TooLong` past this size by default.
+const DEFAULT_MAX_FRAME_LEN: usize = 64 * 1024;
+
+const SLIP_END: u8 = 0xc0;
+const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FrameError {
+    /// More than the maximum frame length was buffered without a complete frame,
+    /// the buffer was discarded. Also returned for a frame too long to encode,
+    /// e.g. longer than a `LengthPrefixed` prefix can express.
+    TooLong,
+    /// Invalid COBS or SLIP encoding, the frame was discarded.
+    Malformed,
+}
+
+/// Split data into writes of at most `mtu - 3` bytes.
+pub fn chunks(data: &[u8], mtu: usize) -> std::slice::Chunks<'_, u8> {
+    data.chunks(mtu.max(DEFAULT_MTU) - ATT_WRITE_HEADER_LEN)
+}
+
+pub trait Codec {
+    /// Bytes to send for one frame.
+    fn encode(&self, frame: &[u8]) -> Result<Vec<u8>, FrameError>;
+
+    /// Remove the first complete frame from `buffer` and return it,
+    /// `Ok(None)` until enough bytes arrived.
+    fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError>;
+}
+
+/// Newline delimited frames, a trailing `\r` is stripped.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lines;
+
+impl Codec for Lines {
+    fn encode(&self, frame: &[u8]) -> Result<Vec<u8>, FrameError> {
+        let mut bytes = frame.to_vec();
+        bytes.push(b'\n');
+        Ok(bytes)
+    }
+
+    fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        let end = match buffer.iter().position(|&b| b == b'\n') {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let mut frame = buffer.drain(..=end).collect::<Vec<u8>>();
+        frame.pop();
+        if frame.last() == Some(&b'\r') {
+            frame.pop();
+        }
+        Ok(Some(frame))
+    }
+}
+
+/// Frames preceded by their length as an unsigned integer of 1, 2 or 4 bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct LengthPrefixed {
+    prefix_len: usize,
+    big_endian: bool,
+}
+
+impl LengthPrefixed {
+    /// Panics if `prefix_len` is not 1, 2 or 4.
+    pub fn new(prefix_len: usize, big_endian: bool) -> LengthPrefixed {
+        assert!(
+            matches!(prefix_len, 1 | 2 | 4),
+            "length prefix must be 1, 2 or 4 bytes"
+        );
+        LengthPrefixed {
+            prefix_len,
+            big_endian,
+        }
+    }
+
+    /// Longest frame the prefix can express.
+    pub fn max_frame_len(&self) -> usize {
+        match self.prefix_len {
+            4 => u32::MAX as usize,
+            prefix_len => (1 << (8 * prefix_len)) - 1,
+        }
+    }
+}
+
+impl Codec for LengthPrefixed {
+    /// `FrameError::TooLong` for frames longer than `max_frame_len`.
+    fn encode(&self, frame: &[u8]) -> Result<Vec<u8>, FrameError> {
+        if frame.len() > self.max_frame_len() {
+            return Err(FrameError::TooLong);
+        }
+        let len = (frame.len() as u32).to_le_bytes();
+        let mut prefix = len[..self.prefix_len].to_vec();
+        if self.big_endian {
+            prefix.reverse();
+        }
+        prefix.extend_from_slice(frame);
+        Ok(prefix)
+    }
+
+    fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        if buffer.len() < self.prefix_len {
+            return Ok(None);
+        }
+        let mut prefix = buffer[..self.prefix_len].to_vec();
+        if self.big_endian {
+            prefix.reverse();
+        }
+        let len = prefix
+            .iter()
+            .rev()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+
+        if buffer.len() < self.prefix_len + len {
+            return Ok(None);
+        }
+        let frame = buffer[self.prefix_len..self.prefix_len + len].to_vec();
+        buffer.drain(..self.prefix_len + len);
+        Ok(Some(frame))
+    }
+}
+
+/// Consistent Overhead Byte Stuffing, frames are terminated by a zero byte.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cobs;
+
+impl Codec for Cobs {
+    fn encode(&self, frame: &[u8]) -> Result<Vec<u8>, FrameError> {
+        let mut bytes = Vec::with_capacity(frame.len() + frame.len() / 254 + 2);
+        let mut code_index = 0;
+        let mut code = 1u8;
+        bytes.push(0);
+
+        for &b in frame {
+            if b != 0 {
+                bytes.push(b);
+                code += 1;
+            }
+            if b == 0 || code == 0xff {
+                bytes[code_index] = code;
+                code_index = bytes.len();
+                bytes.push(0);
+                code = 1;
+            }
+        }
+        bytes[code_index] = code;
+        bytes.push(0);
+        Ok(bytes)
+    }
+
+    fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        loop {
+            let end = match buffer.iter().position(|&b| b == 0) {
+                Some(end) => end,
+                None => return Ok(None),
+            };
+            let encoded = buffer.drain(..=end).collect::<Vec<u8>>();
+            let encoded = &encoded[..end];
+            // consecutive delimiters are not frames
+            if encoded.is_empty() {
+                continue;
+            }
+
+            let mut frame = Vec::with_capacity(encoded.len());
+            let mut i = 0;
+            while i < encoded.len() {
+                let code = encoded[i] as usize;
+                if i + code > encoded.len() {
+                    return Err(FrameError::Malformed);
+                }
+                frame.extend_from_slice(&encoded[i + 1..i + code]);
+                i += code;
+                if code < 0xff && i < encoded.len() {
+                    frame.push(0);
+                }
+            }
+            return Ok(Some(frame));
+        }
+    }
+}
+
+/// RFC 1055 SLIP, frames are delimited by `0xC0`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Slip;
+
+impl Codec for Slip {
+    fn encode(&self, frame: &[u8]) -> Result<Vec<u8>, FrameError> {
+        let mut bytes = Vec::with_capacity(frame.len() + 2);
+        // a leading END flushes any line noise on the receiver side
+        bytes.push(SLIP_END);
+        for &b in frame {
+            match b {
+                SLIP_END => bytes.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+                SLIP_ESC => bytes.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+                _ => bytes.push(b),
+            }
+        }
+        bytes.push(SLIP_END);
+        Ok(bytes)
+    }
+
+    fn decode(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, FrameError> {
+        loop {
+            let end = match buffer.iter().position(|&b| b == SLIP_END) {
+                Some(end) => end,
+                None => return Ok(None),
+            };
+            let encoded = buffer.drain(..=end).collect::<Vec<u8>>();
+            let encoded = &encoded[..end];
+            if encoded.is_empty() {
+                continue;
+            }
+
+            let mut frame = Vec::with_capacity(encoded.len());
+            let mut bytes = encoded.iter();
+            while let Some(&b) = bytes.next() {
+                if b != SLIP_ESC {
+                    frame.push(b);
+                    continue;
+                }
+                match bytes.next() {
+                    Some(&SLIP_ESC_END) => frame.push(SLIP_END),
+                    Some(&SLIP_ESC_ESC) => frame.push(SLIP_ESC),
+                    _ => return Err(FrameError::Malformed),
+                }
+            }
+            return Ok(Some(frame));
+        }
+    }
+}
+
+/// Reassembles the fragments of one characteristic with a codec.
+pub struct Framed<C> {
+    codec: C,
+    buffer: Vec<u8>,
+    max_frame_len: usize,
+}
+
+impl<C: Codec> Framed<C> {
+    pub fn new(codec: C) -> Framed<C> {
+        Framed {
+            codec,
+            buffer: vec![],
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+        }
+    }
+
+    /// Largest encoded frame kept in the buffer while waiting for the rest of it.
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len;
+    }
+
+    pub fn codec(&mut self) -> &mut C {
+        &mut self.codec
+    }
+
+    /// Append a received fragment, usually the payload of `Message::Data`.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Next complete frame, `Ok(None)` until one was received.
+    /// After an error the following frames are still decoded.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let frame = self.codec.decode(&mut self.buffer)?;
+        if frame.is_none() && self.buffer.len() > self.max_frame_len {
+            self.buffer.clear();
+            return Err(FrameError::TooLong);
+        }
+        Ok(frame)
+    }
+
+    /// Bytes received but not part of a complete frame yet.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Encode a frame and split it into writes fitting into `mtu`, usually `Connection::mtu()`.
+    pub fn encode(&self, frame: &[u8], mtu: usize) -> Result<Vec<Vec<u8>>, FrameError> {
+        Ok(chunks(&self.codec.encode(frame)?, mtu)
+            .map(|chunk| chunk.to_vec())
+            .collect())
+    }
+
+    /// Encode a frame and write it to the characteristic, in as many writes as it takes:
+    /// without response, or with response if the characteristic only declares WRITE.
+    /// `BluetoothError::PayloadTooLarge` if the codec can not encode a frame this long.
+    /// Nothing is queued and `BluetoothError::QueueFull` is returned if the writes
+    /// do not fit into the operation queue, a partial frame would desync the peer.
+    pub fn send(
+        &self,
+        connection: &Connection,
+        characteristic: &Characteristic,
+        frame: &[u8],
+    ) -> Result<(), BluetoothError> {
+        let bytes = self
+            .codec
+            .encode(frame)
+            .map_err(|_| BluetoothError::PayloadTooLarge)?;
+        let write_type = WriteType::preferred(characteristic.properties);
+        let max_len = connection.max_write_len(write_type);
+        let free = connection
+            .queue_limit()
+            .saturating_sub(connection.queue_depth());
+        if bytes.len().div_ceil(max_len) > free {
+            return Err(BluetoothError::QueueFull);
+        }
+        for chunk in bytes.chunks(max_len) {
+            characteristic.send_bytes(chunk, write_type == WriteType::WithResponse)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed the chunks one by one and collect every frame decoded on the way.
+    fn receive<C: Codec>(framed: &mut Framed<C>, chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut frames = vec![];
+        for chunk in chunks {
+            framed.push(chunk);
+            while let Some(frame) = framed.next_frame().unwrap() {
+                frames.push(frame);
+            }
+        }
+        frames
+    }
+
+    fn round_trip<C: Codec + Copy>(codec: C) {
+        let frames: [&[u8]; 3] = [b"hello", &[0, 1, 0xc0, 0xdb, 0, 2], &[0x55; 300]];
+        let encoded: Vec<Vec<u8>> = frames.iter().map(|f| codec.encode(f).unwrap()).collect();
+
+        // a frame split over several notifications, one byte at a time
+        let mut framed = Framed::new(codec);
+        let bytes: Vec<&[u8]> = encoded[1].chunks(1).collect();
+        assert_eq!(receive(&mut framed, &bytes), vec![frames[1].to_vec()]);
+
+        // several frames in one notification
+        let mut framed = Framed::new(codec);
+        let all = encoded.concat();
+        assert_eq!(
+            receive(&mut framed, &[&all]),
+            frames.iter().map(|f| f.to_vec()).collect::<Vec<_>>()
+        );
+        assert_eq!(framed.buffered(), 0);
+
+        // every split point, the delimiter or prefix ending up at a chunk boundary
+        for split in 1..encoded[0].len() {
+            let mut framed = Framed::new(codec);
+            let (first, second) = encoded[0].split_at(split);
+            assert_eq!(
+                receive(&mut framed, &[first, second]),
+                vec![b"hello".to_vec()]
+            );
+        }
+    }
+
+    /// A frame longer than the buffer allows is dropped, the next one still decodes.
+    fn oversize<C: Codec + Copy>(codec: C) {
+        let mut framed = Framed::new(codec);
+        framed.set_max_frame_len(16);
+        let long = codec.encode(&[0x55; 32]).unwrap();
+
+        framed.push(&long[..20]);
+        assert_eq!(framed.next_frame(), Err(FrameError::TooLong));
+        assert_eq!(framed.buffered(), 0);
+
+        framed.push(&codec.encode(b"ok").unwrap());
+        assert_eq!(framed.next_frame(), Ok(Some(b"ok".to_vec())));
+    }
+
+    #[test]
+    fn lines() {
+        let codec = Lines;
+        round_trip(codec);
+        let mut framed = Framed::new(codec);
+        let all = [
+            codec.encode(b"first").unwrap(),
+            b"second\r\n".to_vec(),
+            codec.encode(b"").unwrap(),
+        ]
+        .concat();
+        assert_eq!(
+            receive(&mut framed, &[&all[..6], &all[6..]]),
+            vec![b"first".to_vec(), b"second".to_vec(), vec![]]
+        );
+
+        let mut framed = Framed::new(codec);
+        assert_eq!(
+            receive(&mut framed, &[b"spl", b"it", b"\n"]),
+            vec![b"split".to_vec()]
+        );
+        oversize(codec);
+    }
+
+    #[test]
+    fn length_prefixed() {
+        for big_endian in [false, true] {
+            for prefix_len in [2, 4] {
+                round_trip(LengthPrefixed::new(prefix_len, big_endian));
+                oversize(LengthPrefixed::new(prefix_len, big_endian));
+            }
+        }
+        let codec = LengthPrefixed::new(2, true);
+        assert_eq!(codec.encode(b"ab").unwrap(), [0, 2, b'a', b'b']);
+    }
+
+    #[test]
+    fn length_prefix_too_short_for_frame() {
+        let codec = LengthPrefixed::new(1, false);
+        assert_eq!(codec.max_frame_len(), 255);
+        assert_eq!(codec.encode(&[0; 255]).unwrap().len(), 256);
+        assert_eq!(codec.encode(&[0; 300]), Err(FrameError::TooLong));
+        assert_eq!(LengthPrefixed::new(2, false).max_frame_len(), 0xffff);
+    }
+
+    #[test]
+    fn cobs() {
+        round_trip(Cobs);
+        oversize(Cobs);
+        // runs of 254 non-zero bytes need an extra code byte
+        let frame = [1u8; 600];
+        let mut framed = Framed::new(Cobs);
+        assert_eq!(
+            receive(&mut framed, &[&Cobs.encode(&frame).unwrap()]),
+            vec![frame.to_vec()]
+        );
+        // consecutive delimiters are skipped
+        assert_eq!(
+            receive(&mut framed, &[&[0, 0, 2, b'x', 0]]),
+            vec![b"x".to_vec()]
+        );
+        assert_eq!(
+            Framed::new(Cobs).codec().decode(&mut vec![5, 1, 0]),
+            Err(FrameError::Malformed)
+        );
+    }
+
+    #[test]
+    fn slip() {
+        round_trip(Slip);
+        oversize(Slip);
+        assert_eq!(
+            Slip.encode(&[SLIP_END, SLIP_ESC]).unwrap(),
+            [
+                SLIP_END,
+                SLIP_ESC,
+                SLIP_ESC_END,
+                SLIP_ESC,
+                SLIP_ESC_ESC,
+                SLIP_END
+            ]
+        );
+        assert_eq!(
+            Slip.decode(&mut vec![SLIP_ESC, 1, SLIP_END]),
+            Err(FrameError::Malformed)
+        );
+    }
+
+    #[test]
+    fn encode_splits_into_mtu_sized_writes() {
+        let framed = Framed::new(LengthPrefixed::new(2, false));
+        let writes = framed.encode(&[7; 40], DEFAULT_MTU).unwrap();
+        assert_eq!(writes.iter().map(Vec::len).collect::<Vec<_>>(), [20, 20, 2]);
+        let framed = Framed::new(LengthPrefixed::new(1, false));
+        assert_eq!(
+            framed.encode(&[0; 256], DEFAULT_MTU),
+            Err(FrameError::TooLong)
+        );
+    }
+}
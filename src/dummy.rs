@@ -1,43 +1,92 @@
 #![allow(warnings)]
 
+//! Simulated backend for desktop platforms.
+//!
+//! There is no radio: GATT servers opened with `Adapter::open_gatt_server` are
+//! the only devices "in range", and the central side of the same process can
+//! scan for, connect to and talk with them.
+
 use miniquad::info;
 
 use once_cell::sync::Lazy;
-use std::{collections::HashMap, fmt, sync::Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use std::sync::mpsc::{self, Receiver, Sender};
 
-#[derive(Debug)]
-pub enum BluetoothError {
-    AdapterNotReady,
-    DeviceUnavailable,
-    DeviceDisconnected,
-}
+use crate::advertising::{AdvertisementData, AdvertisingError, AdvertisingSettings};
+use crate::error::BluetoothError;
+use crate::event::{AdapterEvent, AdapterState, BondState};
+use crate::gatt::{
+    self, CharacteristicProperties, ConnectionPriority, Phy, WriteType, DEFAULT_MTU, MAX_MTU,
+};
+use crate::peripheral::{
+    self, uuid_eq, Access, AttError, LocalService, Permissions, ReadRequest, ServerEvent,
+    ServerState, WriteRequest,
+};
+use crate::permission::{PermissionFlow, PermissionProvider, PermissionStatus};
+use crate::queue::{Operation, OperationQueue};
+use crate::reconnect::{ReconnectPolicy, Reconnector};
+use crate::rssi::RssiMonitor;
+use crate::state::{ConnectionState, StateEvent};
+use crate::timeout::Timeouts;
 
-impl fmt::Display for BluetoothError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
-    }
-}
-impl std::error::Error for BluetoothError {}
+/// Address of the simulated adapter itself, simulated peripherals see the central by it.
+const LOCAL_ADDRESS: &str = "00:00:00:00:00:00";
+/// Adapter name advertised by simulated GATT servers.
+const LOCAL_NAME: &str = "quad-bt";
+/// Writes without response the simulated link carries per connection event,
+/// a connection event being every `Connection::try_recv`.
+const LINK_CREDITS: usize = 4;
+/// Signal strength of every simulated connection, in dBm.
+const LINK_RSSI: i16 = -60;
+/// Opcode, handle and offset of an ATT prepare write.
+const ATT_PREPARE_WRITE_HEADER_LEN: usize = 5;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceId(String);
 
+impl DeviceId {
+    /// A stored id, as `DeviceId::as_str` gave it in an earlier session.
+    /// `Adapter::connect` takes it without scanning first.
+    pub fn new(id: &str) -> DeviceId {
+        DeviceId(id.to_string())
+    }
+
+    /// Address of the simulated peripheral.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Device {
     pub address: String,
     pub name: Option<String>,
+    /// Payload of the last advertisement seen.
+    pub advertisement: AdvertisementData,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    seen: Instant,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Characteristic {
+    // simulated peripheral and service this characteristic belongs to
+    #[cfg_attr(feature = "serde", serde(skip))]
+    address: String,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    service: String,
     pub id: String,
-    pub write: bool,
-    pub read: bool,
-    pub notify: bool,
-    pub indicate: bool,
-    pub broadcast: bool,
+    pub properties: CharacteristicProperties,
+    /// `GlobalData::discovery` the characteristic was reported in.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    discovery: u64,
 }
 
 impl Device {
@@ -45,34 +94,402 @@ impl Device {
         DeviceId(self.address.clone())
     }
 
-    // fn update_name(&mut self, env: *mut ndk_sys::JNIEnv) {
-    //     if self.name.is_some() {
-    //         return;
-    //     }
-    // }
+    /// Pair and bond with the device, progress arrives as
+    /// `AdapterEvent::BondStateChanged`. The simulated user accepts
+    /// the pairing with the next `Adapter::try_recv`.
+    pub fn bond(&self) -> Result<(), BluetoothError> {
+        GLOBALS.lock().unwrap().bond(&self.address)
+    }
+
+    pub fn bond_state(&self) -> Result<BondState, BluetoothError> {
+        Ok(GLOBALS.lock().unwrap().bond_state(&self.address))
+    }
 }
 
 impl Characteristic {
-    pub fn send_string(&self, data: &str) -> Result<(), BluetoothError> {
+    /// Encrypted attributes of a simulated server need a bond, like a
+    /// peripheral answering with Insufficient Authentication.
+    fn check_access(&self, access: fn(&Permissions) -> Access) -> Result<(), BluetoothError> {
+        let server = self.server()?;
+        let required = server
+            .lock()
+            .unwrap()
+            .characteristic(&self.service, &self.id)
+            .map(|characteristic| access(&characteristic.permissions));
+        if matches!(required, Some(Access::Encrypted | Access::EncryptedMitm))
+            && GLOBALS.lock().unwrap().bond_state(&self.address) != BondState::Bonded
+        {
+            return Err(BluetoothError::from_gatt_status(
+                AttError::InsufficientAuthentication as u16,
+            ));
+        }
         Ok(())
     }
 
+    fn server(&self) -> Result<Arc<Mutex<ServerState>>, BluetoothError> {
+        let globals = GLOBALS.lock().unwrap();
+
+        if globals.connected.as_ref() != Some(&self.address) {
+            return Err(BluetoothError::DeviceDisconnected);
+        }
+        globals
+            .servers
+            .get(&self.address)
+            .cloned()
+            .ok_or(BluetoothError::DeviceDisconnected)
+    }
+
+    /// Queue the string as a write without response, see `send_bytes`.
+    pub fn send_string(&self, data: &str) -> Result<(), BluetoothError> {
+        self.send_bytes(data.as_bytes(), false)
+    }
+
+    /// Queue a write, `verify` asks for a write with response.
     pub fn send_bytes(&self, data: &[u8], verify: bool) -> Result<(), BluetoothError> {
-        Ok(())
+        let write_type = WriteType::from_verify(verify);
+        self.properties.require(write_type.required_property())?;
+        let mtu = GLOBALS.lock().unwrap().mtu;
+        if data.len() > gatt::max_write_len(mtu, write_type) {
+            return Err(BluetoothError::PayloadTooLarge);
+        }
+
+        enqueue(Operation::Write(self.clone(), data.to_vec(), write_type))
+    }
+
+    /// Request the current value, it arrives as `Message::Data`.
+    pub fn read_value(&self) -> Result<(), BluetoothError> {
+        self.properties.require(CharacteristicProperties::READ)?;
+        enqueue(Operation::Read(self.clone()))
     }
 
+    /// Write a value longer than a single packet with response, as prepared writes.
+    /// The simulated peripheral applies the parts once, on the execute write.
+    ///
+    /// An attribute holds at most `MAX_ATTRIBUTE_LEN` (512) bytes, longer data
+    /// fails with `BluetoothError::PayloadTooLarge`: send it in frames, see `codec::Framed`.
+    pub fn write_long(&self, data: &[u8]) -> Result<(), BluetoothError> {
+        self.send_bytes(data, true)
+    }
+
+    /// Enable or disable notifications, confirmed by `Message::SubscriptionChanged`.
     pub fn set_notification(&self, notify: bool) -> Result<(), BluetoothError> {
-        Ok(())
+        self.properties.require(CharacteristicProperties::NOTIFY)?;
+        enqueue(Operation::Subscribe {
+            characteristic: self.clone(),
+            enable: notify,
+            indicate: false,
+        })
     }
 
+    /// Enable or disable indications, confirmed by `Message::SubscriptionChanged`.
     pub fn set_indication(&self, notify: bool) -> Result<(), BluetoothError> {
+        self.properties
+            .require(CharacteristicProperties::INDICATE)?;
+        enqueue(Operation::Subscribe {
+            characteristic: self.clone(),
+            enable: notify,
+            indicate: true,
+        })
+    }
+}
+
+fn enqueue(operation: Operation) -> Result<(), BluetoothError> {
+    let mut globals = GLOBALS.lock().unwrap();
+    globals.state.require_ready()?;
+    if let Some(characteristic) = operation.characteristic() {
+        if characteristic.discovery != globals.discovery {
+            return Err(BluetoothError::InvalidHandle);
+        }
+        // a characteristic of another simulated peripheral
+        if globals.connected.as_ref() != Some(&characteristic.address) {
+            return Err(BluetoothError::DeviceDisconnected);
+        }
+    }
+    globals.queue.push(operation)?;
+    drop(globals);
+    pump_queue();
+
+    Ok(())
+}
+
+/// Run queued operations, writes without response only while the link has credits.
+/// Operations run without the lock held, they call into the simulated server.
+fn pump_queue() {
+    loop {
+        let operation = {
+            let mut globals = GLOBALS.lock().unwrap();
+            let write_without_response = match globals.queue.front() {
+                Some(operation) => operation.is_write_without_response(),
+                None => return,
+            };
+            if write_without_response && globals.credits == 0 {
+                return;
+            }
+            // `None` if an operation is running already, it pumps the rest
+            match globals.queue.start() {
+                Some(operation) => {
+                    if write_without_response {
+                        globals.credits -= 1;
+                    }
+                    operation
+                }
+                None => return,
+            }
+        };
+        let res = execute(operation);
+        let mut globals = GLOBALS.lock().unwrap();
+        if let Err(err) = res {
+            info!("simulated GATT operation failed: {}", err);
+            if err.requires_pairing() {
+                if let Some(address) = globals.connected.clone() {
+                    let _ = globals.bond(&address);
+                }
+            }
+            if let Some(ref tx) = globals.tx {
+                let _ = tx.send(Message::Error(err));
+            }
+        }
+        globals.queue.complete();
+    }
+}
+
+/// Split a value into the (offset, part) prepare writes of a long write.
+fn prepared_writes(value: &[u8], mtu: usize) -> Vec<(usize, &[u8])> {
+    if value.len() <= gatt::max_write_len(mtu, WriteType::WithoutResponse) {
+        return vec![(0, value)];
+    }
+    let part_len = mtu - ATT_PREPARE_WRITE_HEADER_LEN;
+    value
+        .chunks(part_len)
+        .enumerate()
+        .map(|(i, part)| (i * part_len, part))
+        .collect()
+}
+
+/// A write request, or prepare writes with offsets and an execute write if
+/// the value does not fit a single packet.
+fn simulate_write(characteristic: &Characteristic, value: &[u8]) -> Result<(), BluetoothError> {
+    characteristic.check_access(|permissions| permissions.write)?;
+    let server = characteristic.server()?;
+    let mtu = GLOBALS.lock().unwrap().mtu;
+    let central = DeviceId(LOCAL_ADDRESS.to_string());
+
+    let parts = prepared_writes(value, mtu);
+    let requests = parts.iter().map(|&(offset, part)| WriteRequest {
+        central: central.clone(),
+        service: characteristic.service.clone(),
+        characteristic: characteristic.id.clone(),
+        value: part.to_vec(),
+        offset,
+    });
+    let res = if parts.len() == 1 {
+        peripheral::handle_write(&server, &requests.last().unwrap())
+    } else {
+        let prepared: Result<(), AttError> = requests
+            .map(|request| peripheral::prepare_write(&server, &request))
+            .collect();
+        // a rejected part cancels the queued ones
+        peripheral::execute_writes(&server, &central, prepared.is_ok()).and(prepared)
+    };
+    res.map_err(|err| BluetoothError::from_gatt_status(err as u16))
+}
+
+/// Every write of the transaction as prepare writes, executed together:
+/// if the peripheral rejects any of them none is applied.
+fn simulate_reliable_write(writes: &[(Characteristic, Vec<u8>)]) -> Result<(), BluetoothError> {
+    let server = match writes.first() {
+        Some((characteristic, _)) => characteristic.server()?,
+        None => return Ok(()),
+    };
+    let mtu = GLOBALS.lock().unwrap().mtu;
+    let central = DeviceId(LOCAL_ADDRESS.to_string());
+
+    let prepared = writes.iter().try_for_each(|(characteristic, value)| {
+        characteristic.check_access(|permissions| permissions.write)?;
+        let part_len = mtu - ATT_PREPARE_WRITE_HEADER_LEN;
+        // an empty value is still one prepare write
+        let parts = value
+            .chunks(part_len)
+            .enumerate()
+            .map(|(i, part)| (i * part_len, part));
+        for (offset, part) in parts.chain(value.is_empty().then_some((0, &[][..]))) {
+            let request = WriteRequest {
+                central: central.clone(),
+                service: characteristic.service.clone(),
+                characteristic: characteristic.id.clone(),
+                value: part.to_vec(),
+                offset,
+            };
+            peripheral::prepare_write(&server, &request)
+                .map_err(|err| BluetoothError::from_gatt_status(err as u16))?;
+        }
+        Ok(())
+    });
+    let executed = peripheral::execute_writes(&server, &central, prepared.is_ok())
+        .map_err(|err| BluetoothError::from_gatt_status(err as u16));
+    prepared.and(executed)
+}
+
+fn execute(operation: Operation) -> Result<(), BluetoothError> {
+    let central = DeviceId(LOCAL_ADDRESS.to_string());
+
+    match operation {
+        Operation::Read(characteristic) => {
+            characteristic.check_access(|permissions| permissions.read)?;
+            let request = ReadRequest {
+                central,
+                service: characteristic.service.clone(),
+                characteristic: characteristic.id.clone(),
+                offset: 0,
+            };
+            let server = characteristic.server()?;
+            let value = peripheral::handle_read(&server, &request)
+                .map_err(|err| BluetoothError::from_gatt_status(err as u16))?;
+            if let Some(ref tx) = GLOBALS.lock().unwrap().tx {
+                let _ = tx.send(Message::Data(value));
+            }
+        }
+        Operation::Write(characteristic, value, write_type) => {
+            let mut globals = GLOBALS.lock().unwrap();
+            if let (Some(writes), WriteType::WithResponse) =
+                (globals.reliable_write.as_mut(), write_type)
+            {
+                writes.push((characteristic, value));
+                return Ok(());
+            }
+            drop(globals);
+
+            let res = simulate_write(&characteristic, &value);
+            // writes without response are not answered, failed or not
+            if write_type == WriteType::WithResponse {
+                res?;
+            }
+        }
+        Operation::Subscribe {
+            characteristic,
+            enable,
+            indicate,
+        } => {
+            characteristic.properties.require(if indicate {
+                CharacteristicProperties::INDICATE
+            } else {
+                CharacteristicProperties::NOTIFY
+            })?;
+            let server = characteristic.server()?;
+            server
+                .lock()
+                .unwrap()
+                .set_subscribed(&central, &characteristic.id, enable);
+            if let Some(ref tx) = GLOBALS.lock().unwrap().tx {
+                let _ = tx.send(Message::SubscriptionChanged {
+                    characteristic: characteristic.id.clone(),
+                    enabled: enable,
+                    indication: indicate,
+                });
+            }
+        }
+        Operation::RequestMtu(mtu) => {
+            let mut globals = GLOBALS.lock().unwrap();
+            globals.mtu = mtu.clamp(DEFAULT_MTU, MAX_MTU);
+            if let Some(ref tx) = globals.tx {
+                let _ = tx.send(Message::MtuChanged(globals.mtu));
+            }
+        }
+        Operation::ReadRssi => {
+            if let Some(ref tx) = GLOBALS.lock().unwrap().tx {
+                let _ = tx.send(Message::Rssi(LINK_RSSI));
+            }
+        }
+        Operation::BeginReliableWrite => {
+            GLOBALS.lock().unwrap().reliable_write = Some(vec![]);
+        }
+        Operation::ExecuteReliableWrite => {
+            let writes = GLOBALS.lock().unwrap().reliable_write.take();
+            let success = match writes {
+                Some(writes) => simulate_reliable_write(&writes).is_ok(),
+                None => false,
+            };
+            if let Some(ref tx) = GLOBALS.lock().unwrap().tx {
+                let _ = tx.send(Message::ReliableWriteCompleted(success));
+            }
+        }
+        Operation::AbortReliableWrite => {
+            GLOBALS.lock().unwrap().reliable_write = None;
+        }
+    }
+    Ok(())
+}
+
+/// Something on air: an advertising GATT server or a broadcaster.
+struct Advertiser {
+    payload: Vec<u8>,
+    expires: Option<Instant>,
+}
+
+/// Permissions as a simulated user grants them, granted unless
+/// `Adapter::simulate_revoked_permissions` was called.
+struct SimulatedPermissions {
+    granted: bool,
+    rationale: bool,
+    // how the simulated user answers the prompt
+    answer: PermissionStatus,
+}
+
+impl PermissionProvider for SimulatedPermissions {
+    fn granted(&self) -> bool {
+        self.granted
+    }
+
+    fn should_show_rationale(&self) -> bool {
+        self.rationale
+    }
+
+    fn request(&mut self) -> Result<(), BluetoothError> {
+        match self.answer {
+            PermissionStatus::Granted => self.granted = true,
+            PermissionStatus::Denied => self.rationale = true,
+            PermissionStatus::PermanentlyDenied => self.rationale = false,
+            // the prompt was dismissed
+            PermissionStatus::NotDetermined => {}
+        }
         Ok(())
     }
 }
+
 struct GlobalData {
     devices: HashMap<String, Device>,
     tx: Option<Sender<Message>>,
     rx: Option<Receiver<Vec<u8>>>,
+    adapter_tx: Option<Sender<AdapterEvent>>,
+    permissions: PermissionFlow<SimulatedPermissions>,
+    powered: bool,
+    // how the simulated user answers `Adapter::request_enable`
+    enable_answer: bool,
+    // simulated peripherals paired with, `Bonding` until the next `Adapter::try_recv`
+    bonds: HashMap<String, BondState>,
+    scanning: bool,
+    // simulated peripheral the central side is connected to
+    connected: Option<String>,
+    state: ConnectionState,
+    // ATT_MTU of the connection
+    mtu: usize,
+    queue: OperationQueue,
+    // writes held back until the reliable write transaction is executed
+    reliable_write: Option<Vec<(Characteristic, Vec<u8>)>>,
+    // writes without response the link takes until the next connection event
+    credits: usize,
+    servers: HashMap<String, Arc<Mutex<ServerState>>>,
+    advertisers: HashMap<String, Advertiser>,
+    // address `Adapter::start_advertising` broadcasts with
+    broadcast_address: String,
+    next_address: u32,
+    timeouts: Timeouts,
+    /// Bumped by every `Adapter::connect`, a dropped `Connection` only
+    /// tears down the link while it is still the current one.
+    generation: u64,
+    /// Bumped whenever the services change, `Characteristic`s from an
+    /// earlier discovery are stale.
+    discovery: u64,
 }
 
 unsafe impl Send for GlobalData {}
@@ -83,29 +500,476 @@ static GLOBALS: Lazy<Mutex<GlobalData>> = Lazy::new(|| {
         devices: HashMap::new(),
         tx: None,
         rx: None,
+        adapter_tx: None,
+        permissions: PermissionFlow::new(SimulatedPermissions {
+            granted: true,
+            rationale: false,
+            answer: PermissionStatus::Granted,
+        }),
+        powered: true,
+        enable_answer: true,
+        bonds: HashMap::new(),
+        scanning: false,
+        connected: None,
+        state: ConnectionState::default(),
+        mtu: DEFAULT_MTU,
+        queue: OperationQueue::new(),
+        reliable_write: None,
+        credits: LINK_CREDITS,
+        servers: HashMap::new(),
+        advertisers: HashMap::new(),
+        broadcast_address: "00:00:00:00:00:01".to_string(),
+        next_address: 2,
+        timeouts: Timeouts::default(),
+        generation: 0,
+        discovery: 0,
     };
     Mutex::new(data)
 });
 
-pub struct Adapter;
+impl GlobalData {
+    fn adapter_state(&self) -> AdapterState {
+        if !self.permissions.provider.granted {
+            AdapterState::Unauthorized
+        } else if self.powered {
+            AdapterState::PoweredOn
+        } else {
+            AdapterState::PoweredOff
+        }
+    }
+
+    fn bond_state(&self, address: &str) -> BondState {
+        self.bonds.get(address).copied().unwrap_or(BondState::None)
+    }
+
+    fn bond(&mut self, address: &str) -> Result<(), BluetoothError> {
+        self.adapter_state().require_powered_on()?;
+        match self.bond_state(address) {
+            BondState::Bonded => return Ok(()),
+            BondState::Bonding => return Err(BluetoothError::OperationInProgress),
+            BondState::None => {}
+        }
+        if !self.servers.contains_key(address) && !self.advertisers.contains_key(address) {
+            return Err(BluetoothError::DeviceUnavailable);
+        }
+
+        self.bonds.insert(address.to_string(), BondState::Bonding);
+        self.send_adapter_event(AdapterEvent::BondStateChanged {
+            device: DeviceId(address.to_string()),
+            state: BondState::Bonding,
+        });
+        Ok(())
+    }
+
+    /// The simulated user accepts the pending pairings.
+    fn complete_bonding(&mut self) {
+        let mut bonded = vec![];
+        for (address, state) in self.bonds.iter_mut() {
+            if *state == BondState::Bonding {
+                *state = BondState::Bonded;
+                bonded.push(address.clone());
+            }
+        }
+        for address in bonded {
+            self.send_adapter_event(AdapterEvent::BondStateChanged {
+                device: DeviceId(address),
+                state: BondState::Bonded,
+            });
+        }
+    }
+
+    /// Turn the simulated adapter on or off, a link and a scan do not survive it.
+    fn set_powered(&mut self, powered: bool) {
+        let before = self.adapter_state();
+        self.powered = powered;
+        if !powered {
+            self.scanning = false;
+            if self.connected.is_some() {
+                self.disconnect_central(Some(BluetoothError::AdapterOff));
+            }
+        }
+        let after = self.adapter_state();
+        if after != before {
+            self.send_adapter_event(AdapterEvent::StateChanged(after));
+        }
+    }
+
+    fn send_adapter_event(&self, event: AdapterEvent) {
+        if let Some(ref tx) = self.adapter_tx {
+            let _ = tx.send(event);
+        }
+    }
+
+    /// Put an advertisement on air, a running scan sees it right away.
+    fn advertise(&mut self, address: &str, advertiser: Advertiser) {
+        self.advertisers.insert(address.to_string(), advertiser);
+        self.scan_advertisers();
+    }
+
+    fn scan_advertisers(&mut self) {
+        self.expire_advertisers();
+        if !self.scanning {
+            return;
+        }
+
+        let addresses: Vec<String> = self.advertisers.keys().cloned().collect();
+        for address in addresses {
+            self.receive_advertisement(&address);
+        }
+    }
+
+    /// Take in the advertisement of a device on air, `false` if it is not.
+    fn receive_advertisement(&mut self, address: &str) -> bool {
+        let advertiser = match self.advertisers.get(address) {
+            Some(advertiser) => advertiser,
+            None => return false,
+        };
+        let advertisement = AdvertisementData::parse(&advertiser.payload);
+        self.devices.insert(
+            address.to_string(),
+            Device {
+                address: address.to_string(),
+                name: advertisement.local_name.clone(),
+                advertisement,
+                seen: Instant::now(),
+            },
+        );
+        true
+    }
+
+    fn expire_advertisers(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .advertisers
+            .iter()
+            .filter(|(_, advertiser)| advertiser.expires.map_or(false, |expires| expires <= now))
+            .map(|(address, _)| address.clone())
+            .collect::<Vec<_>>();
+
+        for address in expired {
+            self.advertisers.remove(&address);
+            if address == self.broadcast_address {
+                self.send_adapter_event(AdapterEvent::AdvertisingStopped);
+            }
+        }
+    }
+
+    fn connect_central(
+        &mut self,
+        address: &str,
+        tx: Sender<Message>,
+    ) -> Result<(), BluetoothError> {
+        self.adapter_state().require_powered_on()?;
+        // a known address connects without a scan, as long as the device is on air
+        self.expire_advertisers();
+        if !self.devices.contains_key(address) && !self.receive_advertisement(address) {
+            return Err(BluetoothError::DeviceUnavailable);
+        }
+        // non-connectable broadcasters have no GATT server to connect to
+        let server = self
+            .servers
+            .get(address)
+            .cloned()
+            .ok_or(BluetoothError::DeviceUnavailable)?;
+
+        self.state.transition(StateEvent::Connect)?;
+
+        // the simulated link comes up and is discovered right away
+        let _ = self.state.transition(StateEvent::Connected);
+        let _ = tx.send(Message::Connected);
+        server
+            .lock()
+            .unwrap()
+            .central_connected(&DeviceId(LOCAL_ADDRESS.to_string()));
+        self.discover_services(address, &server, &tx);
+
+        self.connected = Some(address.to_string());
+        self.mtu = DEFAULT_MTU;
+        self.credits = LINK_CREDITS;
+        self.tx = Some(tx);
+        Ok(())
+    }
+
+    fn discover_services(
+        &mut self,
+        address: &str,
+        server: &Arc<Mutex<ServerState>>,
+        tx: &Sender<Message>,
+    ) {
+        let _ = self.state.transition(StateEvent::DiscoveryStarted);
+        for service in &server.lock().unwrap().services {
+            for characteristic in &service.characteristics {
+                let _ = tx.send(Message::CharacteristicDiscovered(Characteristic {
+                    address: address.to_string(),
+                    service: service.uuid.clone(),
+                    id: characteristic.uuid.clone(),
+                    properties: characteristic.properties(),
+                    discovery: self.discovery,
+                }));
+            }
+        }
+        let _ = self.state.transition(StateEvent::ServicesDiscovered);
+        let _ = tx.send(Message::ServicesDiscovered);
+    }
+
+    /// Invalidate the characteristics of the connection and discover them again.
+    fn services_changed(&mut self) {
+        let (address, tx) = match (self.connected.clone(), self.tx.clone()) {
+            (Some(address), Some(tx)) => (address, tx),
+            _ => return,
+        };
+        let server = match self.servers.get(&address) {
+            Some(server) => server.clone(),
+            None => return,
+        };
+
+        self.discovery += 1;
+        let _ = tx.send(Message::ServicesChanged);
+        for _ in 0..self.queue.drop_characteristic_operations() {
+            let _ = tx.send(Message::Error(BluetoothError::InvalidHandle));
+        }
+        self.discover_services(&address, &server, &tx);
+    }
+
+    fn disconnect_central(&mut self, reason: Option<BluetoothError>) {
+        self.queue.clear();
+        self.reliable_write = None;
+        let _ = self
+            .state
+            .transition(StateEvent::Disconnected(reason.clone()));
+        if let Some(address) = self.connected.take() {
+            if let Some(server) = self.servers.get(&address) {
+                server
+                    .lock()
+                    .unwrap()
+                    .central_disconnected(&DeviceId(LOCAL_ADDRESS.to_string()));
+            }
+            if let Some(ref tx) = self.tx {
+                let _ = tx.send(Message::Disconnected(reason));
+            }
+        }
+    }
+}
+
+fn check_timeouts() {
+    let mut globals = GLOBALS.lock().unwrap();
+
+    // the simulated link comes up within `Adapter::connect`, only operations can time out
+    if globals.state.is_ready() && globals.queue.overdue(globals.timeouts.operation) {
+        if let Some(ref tx) = globals.tx {
+            let _ = tx.send(Message::Error(BluetoothError::Timeout));
+        }
+        globals.disconnect_central(Some(BluetoothError::Timeout));
+    }
+}
+
+/// Queue an RSSI read when the monitor is due, one at a time.
+fn monitor_rssi(monitor: &mut RssiMonitor) {
+    let globals = GLOBALS.lock().unwrap();
+    let idle = globals.state.is_ready() && !globals.queue.rssi_queued();
+    drop(globals);
+    if idle && monitor.due() {
+        let _ = enqueue(Operation::ReadRssi);
+    }
+}
+
+/// Connect again to the device of a `Connection`, on the same channel.
+pub(crate) fn reconnect_device(device_id: &DeviceId) -> Result<(), BluetoothError> {
+    let mut globals = GLOBALS.lock().unwrap();
+    let tx = globals
+        .tx
+        .clone()
+        .ok_or(BluetoothError::DeviceUnavailable)?;
+    globals.connect_central(&device_id.0, tx)
+}
+
+/// A running scan saw the device after `since`.
+pub(crate) fn device_seen_since(device_id: &DeviceId, since: Instant) -> bool {
+    let mut globals = GLOBALS.lock().unwrap();
+    globals.scan_advertisers();
+    globals
+        .devices
+        .get(&device_id.0)
+        .map_or(false, |device| device.seen >= since)
+}
+
+pub struct Adapter {
+    rx: Receiver<AdapterEvent>,
+}
 
 impl Adapter {
     pub fn new() -> Result<Adapter, BluetoothError> {
-        Ok(Adapter)
+        let (tx, rx) = mpsc::channel();
+        let mut globals = GLOBALS.lock().unwrap();
+        let _ = tx.send(AdapterEvent::StateChanged(globals.adapter_state()));
+        globals.adapter_tx = Some(tx);
+        drop(globals);
+
+        Ok(Adapter { rx })
+    }
+
+    pub fn try_recv(&mut self) -> Result<Option<AdapterEvent>, BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        globals.expire_advertisers();
+        globals.complete_bonding();
+        drop(globals);
+
+        Ok(self.rx.try_recv().ok())
+    }
+
+    /// Bluetooth availability, changes arrive as `AdapterEvent::StateChanged`.
+    /// The simulated adapter is on unless turned off with
+    /// `Adapter::simulate_power_off` or the permissions are revoked.
+    pub fn state(&self) -> AdapterState {
+        GLOBALS.lock().unwrap().adapter_state()
+    }
+
+    pub fn permission_status(&self) -> PermissionStatus {
+        GLOBALS.lock().unwrap().permissions.status()
+    }
+
+    /// Ask the user for the Bluetooth permissions,
+    /// the answer arrives as `AdapterEvent::PermissionsResult`.
+    pub fn request_permissions(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        let before = globals.adapter_state();
+
+        let status = match globals.permissions.request()? {
+            Some(status) => status,
+            // the simulated user answers right away
+            None => globals.permissions.on_result(),
+        };
+        globals.send_adapter_event(AdapterEvent::PermissionsResult(status));
+        let after = globals.adapter_state();
+        if after != before {
+            globals.send_adapter_event(AdapterEvent::StateChanged(after));
+        }
+        Ok(())
+    }
+
+    /// Take the permissions away, the simulated user answers the
+    /// following prompts with `answer`. Simulation only.
+    pub fn simulate_revoked_permissions(&mut self, answer: PermissionStatus) {
+        let mut globals = GLOBALS.lock().unwrap();
+        let before = globals.adapter_state();
+
+        globals.permissions = PermissionFlow::new(SimulatedPermissions {
+            granted: false,
+            rationale: false,
+            answer,
+        });
+        if before != AdapterState::Unauthorized {
+            globals.send_adapter_event(AdapterEvent::StateChanged(AdapterState::Unauthorized));
+        }
+    }
+
+    /// Ask the user to turn Bluetooth on, the answer arrives as
+    /// `AdapterEvent::EnableResult`.
+    pub fn request_enable(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        match globals.adapter_state() {
+            AdapterState::PoweredOn => {
+                globals.send_adapter_event(AdapterEvent::EnableResult(true));
+            }
+            AdapterState::PoweredOff => {
+                let enabled = globals.enable_answer;
+                globals.send_adapter_event(AdapterEvent::EnableResult(enabled));
+                if enabled {
+                    globals.set_powered(true);
+                }
+            }
+            state => state.require_powered_on()?,
+        }
+        Ok(())
+    }
+
+    /// Turn the simulated adapter off, the simulated user answers
+    /// the following `request_enable` prompts with `enable`. Simulation only.
+    pub fn simulate_power_off(&mut self, enable: bool) {
+        let mut globals = GLOBALS.lock().unwrap();
+        globals.enable_answer = enable;
+        globals.set_powered(false);
+    }
+
+    /// Turn the simulated adapter back on, as from the system settings. Simulation only.
+    pub fn simulate_power_on(&mut self) {
+        GLOBALS.lock().unwrap().set_powered(true);
     }
 
     pub fn is_ready(&self) -> bool {
-        false
+        self.state() == AdapterState::PoweredOn
     }
 
     pub fn start_scan(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        globals.adapter_state().require_powered_on()?;
+        globals.scanning = true;
+        globals.scan_advertisers();
+
         Ok(())
     }
 
     pub fn walk_devices<F: FnMut(&Device)>(&mut self, mut f: F) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        globals.scan_advertisers();
+        // `f` may call into the adapter, e.g. `Device::bond`
+        let devices: Vec<Device> = globals.devices.values().cloned().collect();
+        drop(globals);
+        devices.iter().for_each(|d| f(d));
+
         Ok(())
     }
 
+    /// Devices bonded with `Device::bond`.
+    pub fn bonded_devices(&self) -> Result<Vec<DeviceId>, BluetoothError> {
+        let globals = GLOBALS.lock().unwrap();
+        Ok(globals
+            .bonds
+            .iter()
+            .filter(|(_, state)| **state == BondState::Bonded)
+            .map(|(address, _)| DeviceId(address.clone()))
+            .collect())
+    }
+
+    /// Forget the bond, the next encrypted access pairs again.
+    pub fn remove_bond(&mut self, device_id: &DeviceId) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        if globals.bonds.remove(&device_id.0).is_some() {
+            globals.send_adapter_event(AdapterEvent::BondStateChanged {
+                device: device_id.clone(),
+                state: BondState::None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Devices connected to the system with any of the given services, all
+    /// connected ones if `services` is empty. The simulated adapter only knows
+    /// its own `Connection`.
+    pub fn connected_devices(&self, services: &[&str]) -> Result<Vec<DeviceId>, BluetoothError> {
+        let globals = GLOBALS.lock().unwrap();
+        globals.adapter_state().require_powered_on()?;
+
+        let address = match globals.connected {
+            Some(ref address) => address,
+            None => return Ok(vec![]),
+        };
+        let matches = globals.servers.get(address).is_some_and(|server| {
+            services.is_empty()
+                || server
+                    .lock()
+                    .unwrap()
+                    .services
+                    .iter()
+                    .any(|service| services.iter().any(|uuid| uuid_eq(&service.uuid, uuid)))
+        });
+        Ok(if matches {
+            vec![DeviceId(address.clone())]
+        } else {
+            vec![]
+        })
+    }
+
     pub fn get_device_name(&self, device_id: &DeviceId) -> Option<String> {
         let globals = GLOBALS.lock().unwrap();
 
@@ -115,26 +979,147 @@ impl Adapter {
             .and_then(|d| d.name.clone())
     }
 
+    /// Timeouts for the following connects and the operations of the connection.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        GLOBALS.lock().unwrap().timeouts = timeouts;
+    }
+
     pub fn connect(&mut self, device_id: DeviceId) -> Result<Connection, BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
         let (tx, client_rx) = mpsc::channel();
+        globals.connect_central(&device_id.0, tx)?;
+        globals.generation += 1;
+        let generation = globals.generation;
 
         Ok(Connection {
-            device_id: DeviceId("".to_string()), 
-            rx: client_rx
+            device_id,
+            rx: client_rx,
+            reconnect: None,
+            rssi_monitor: None,
+            generation,
         })
     }
+
+    /// Start a local GATT server, it becomes visible to centrals once advertising.
+    pub fn open_gatt_server(
+        &mut self,
+        services: Vec<LocalService>,
+    ) -> Result<GattServer, BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+
+        let address = format!("00:00:00:00:00:{:02X}", globals.next_address);
+        globals.next_address += 1;
+
+        let (tx, rx) = mpsc::channel();
+        let state = ServerState::new(services, tx);
+
+        globals.servers.insert(address.clone(), state.clone());
+
+        Ok(GattServer { address, state, rx })
+    }
+
+    /// Broadcast an advertisement, independent of any GATT server.
+    /// The outcome arrives as an `AdapterEvent`.
+    pub fn start_advertising(
+        &mut self,
+        data: AdvertisementData,
+        settings: AdvertisingSettings,
+    ) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        globals.expire_advertisers();
+
+        let address = globals.broadcast_address.clone();
+
+        if globals.advertisers.contains_key(&address) {
+            globals.send_adapter_event(AdapterEvent::AdvertisingFailed(
+                AdvertisingError::AlreadyStarted,
+            ));
+        } else if let Ok(payload) = data.encode(settings.connectable) {
+            let expires = settings.timeout.map(|timeout| Instant::now() + timeout);
+            globals.advertise(&address, Advertiser { payload, expires });
+            globals.send_adapter_event(AdapterEvent::AdvertisingStarted);
+        } else {
+            globals.send_adapter_event(AdapterEvent::AdvertisingFailed(
+                AdvertisingError::DataTooLarge,
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn stop_advertising(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+
+        let address = globals.broadcast_address.clone();
+        if globals.advertisers.remove(&address).is_some() {
+            globals.send_adapter_event(AdapterEvent::AdvertisingStopped);
+        }
+
+        Ok(())
+    }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Message {
     Connected,
-    Disconnected,
+    /// Connecting failed, the connection is unusable.
+    ConnectFailed(BluetoothError),
+    /// `None` after `Connection::disconnect`, the reason otherwise.
+    Disconnected(Option<BluetoothError>),
+    /// A queued operation failed.
+    Error(BluetoothError),
+    /// All characteristics were reported, the connection is ready for GATT operations.
+    ServicesDiscovered,
+    /// The peripheral changed its services, or `Connection::refresh_services` was
+    /// called. Every `Characteristic` received so far is invalid and operations on
+    /// it fail with `BluetoothError::InvalidHandle`, queued ones included.
+    /// Discovery runs again and reports the characteristics anew.
+    ServicesChanged,
     Data(Vec<u8>),
     CharacteristicDiscovered(Characteristic),
+    /// ATT_MTU negotiated, in response to `Connection::request_mtu`.
+    MtuChanged(usize),
+    /// Signal strength of the connection in dBm, in response to `Connection::read_rssi`.
+    Rssi(i16),
+    /// Outcome of `Connection::execute_reliable_write`.
+    ReliableWriteCompleted(bool),
+    /// The peripheral confirmed the change of its client configuration,
+    /// after `set_notification` or `set_indication`.
+    SubscriptionChanged {
+        characteristic: String,
+        enabled: bool,
+        /// Set by `set_indication`, clear by `set_notification`.
+        indication: bool,
+    },
+    /// The link was lost, another attempt follows after the backoff.
+    /// Only with a `ReconnectPolicy`, instead of `Disconnected`.
+    Reconnecting {
+        attempt: u32,
+        reason: BluetoothError,
+    },
+    /// The link is back and the subscriptions are restored.
+    Reconnected,
+    /// The link layer agreed on new connection parameters,
+    /// e.g. after `Connection::request_connection_priority`.
+    ConnectionParametersChanged {
+        interval: Duration,
+        /// Connection events the peripheral may skip.
+        latency: u16,
+        supervision_timeout: Duration,
+    },
+    /// PHYs in use, after `Connection::set_preferred_phy`.
+    PhyChanged {
+        tx: Phy,
+        rx: Phy,
+    },
 }
 
 pub struct Connection {
     device_id: DeviceId,
     rx: Receiver<Message>,
+    reconnect: Option<Box<Reconnector>>,
+    rssi_monitor: Option<RssiMonitor>,
+    generation: u64,
 }
 
 impl Connection {
@@ -142,11 +1127,335 @@ impl Connection {
         self.device_id.clone()
     }
 
+    pub fn state(&self) -> ConnectionState {
+        GLOBALS.lock().unwrap().state.clone()
+    }
+
     pub fn try_recv(&mut self) -> Result<Option<Message>, BluetoothError> {
-        Ok(self.rx.try_recv().ok())
+        GLOBALS.lock().unwrap().credits = LINK_CREDITS;
+        pump_queue();
+        check_timeouts();
+        if let Some(ref mut monitor) = self.rssi_monitor {
+            monitor_rssi(monitor);
+        }
+
+        let message = match self.reconnect {
+            Some(ref mut reconnect) => reconnect.try_recv(&self.device_id, &self.rx),
+            None => Ok(self.rx.try_recv().ok()),
+        };
+        if let (Some(monitor), Ok(Some(Message::Rssi(rssi)))) = (&mut self.rssi_monitor, &message) {
+            monitor.record(*rssi);
+        }
+        message
+    }
+
+    /// Reconnect on its own when the link is lost, `None` to stop.
+    /// Notifications and indications enabled after this are restored on reconnect.
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        let connected = matches!(
+            self.state(),
+            ConnectionState::Connected
+                | ConnectionState::DiscoveringServices
+                | ConnectionState::Ready
+        );
+        self.reconnect = policy.map(|policy| Box::new(Reconnector::new(policy, connected)));
+    }
+
+    /// Drop the cached services and discover them again, in case the peripheral
+    /// changed them without telling. Reported as `Message::ServicesChanged`.
+    pub fn refresh_services(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        globals.state.require_ready()?;
+        globals.services_changed();
+        Ok(())
+    }
+
+    /// Read the signal strength of the connection, the result arrives as `Message::Rssi`.
+    /// Simulated peripherals are always `LINK_RSSI` away.
+    pub fn read_rssi(&mut self) -> Result<(), BluetoothError> {
+        enqueue(Operation::ReadRssi)
+    }
+
+    /// Read the RSSI every `interval` while the connection is ready, `None` to stop.
+    /// Each reading arrives as `Message::Rssi`.
+    pub fn set_rssi_interval(&mut self, interval: Option<Duration>) {
+        self.rssi_monitor = interval.map(RssiMonitor::new);
+    }
+
+    /// Mean of the last `RSSI_WINDOW` readings since `set_rssi_interval`,
+    /// `None` without an interval or before the first reading.
+    pub fn average_rssi(&self) -> Option<i16> {
+        self.rssi_monitor.as_ref()?.average()
+    }
+
+    /// Ask for a larger ATT_MTU, the result arrives as `Message::MtuChanged`.
+    /// Simulated peripherals accept anything up to `MAX_MTU`.
+    pub fn request_mtu(&mut self, mtu: usize) -> Result<(), BluetoothError> {
+        enqueue(Operation::RequestMtu(mtu))
+    }
+
+    /// Ask for a shorter or longer connection interval, the parameters the
+    /// link layer settles on arrive as `Message::ConnectionParametersChanged`.
+    /// The simulated link takes the middle of Android's ranges.
+    pub fn request_connection_priority(
+        &mut self,
+        priority: ConnectionPriority,
+    ) -> Result<(), BluetoothError> {
+        let globals = GLOBALS.lock().unwrap();
+        globals.state.require_ready()?;
+
+        let (interval, latency) = match priority {
+            ConnectionPriority::Balanced => (Duration::from_micros(40_000), 0),
+            ConnectionPriority::High => (Duration::from_micros(12_500), 0),
+            ConnectionPriority::LowPower => (Duration::from_micros(112_500), 2),
+        };
+        if let Some(ref tx) = globals.tx {
+            let _ = tx.send(Message::ConnectionParametersChanged {
+                interval,
+                latency,
+                supervision_timeout: Duration::from_secs(5),
+            });
+        }
+        Ok(())
+    }
+
+    /// Prefer these PHYs, what both sides support is reported as `Message::PhyChanged`.
+    /// Simulated peripherals support all of them.
+    pub fn set_preferred_phy(&mut self, tx: Phy, rx: Phy) -> Result<(), BluetoothError> {
+        let globals = GLOBALS.lock().unwrap();
+        globals.state.require_ready()?;
+
+        if let Some(ref tx_) = globals.tx {
+            let _ = tx_.send(Message::PhyChanged { tx, rx });
+        }
+        Ok(())
+    }
+
+    /// Start a reliable write transaction: the following writes with response
+    /// are held by the peripheral until `execute_reliable_write`.
+    /// The simulated peripheral applies all of them on execute, or none if one is rejected.
+    pub fn begin_reliable_write(&mut self) -> Result<(), BluetoothError> {
+        enqueue(Operation::BeginReliableWrite)
+    }
+
+    /// Commit the transaction, the outcome arrives as `Message::ReliableWriteCompleted`.
+    pub fn execute_reliable_write(&mut self) -> Result<(), BluetoothError> {
+        enqueue(Operation::ExecuteReliableWrite)
+    }
+
+    /// Drop the writes of the transaction.
+    pub fn abort_reliable_write(&mut self) -> Result<(), BluetoothError> {
+        enqueue(Operation::AbortReliableWrite)
+    }
+
+    /// Operations queued or in flight on this connection.
+    pub fn queue_depth(&self) -> usize {
+        GLOBALS.lock().unwrap().queue.depth()
+    }
+
+    pub fn queue_limit(&self) -> usize {
+        GLOBALS.lock().unwrap().queue.limit
+    }
+
+    /// Operations beyond this many fail with `BluetoothError::QueueFull`.
+    pub fn set_queue_limit(&mut self, limit: usize) {
+        GLOBALS.lock().unwrap().queue.limit = limit;
+    }
+
+    /// Current ATT_MTU.
+    pub fn mtu(&self) -> usize {
+        GLOBALS.lock().unwrap().mtu
+    }
+
+    /// Longest value a single `send_bytes` of the given type accepts.
+    pub fn max_write_len(&self, write_type: WriteType) -> usize {
+        gatt::max_write_len(self.mtu(), write_type)
     }
 
     pub fn disconnect(&mut self) -> Result<(), BluetoothError> {
+        let disconnected = self.state().is_disconnected();
+        if let Some(ref mut reconnect) = self.reconnect {
+            if disconnected {
+                reconnect.stop(Message::Disconnected(None));
+            }
+        }
+        let mut globals = GLOBALS.lock().unwrap();
+
+        if globals.connected.as_ref() != Some(&self.device_id.0) || !globals.state.is_active() {
+            return Ok(());
+        }
+        globals.state.transition(StateEvent::Disconnect)?;
+        globals.disconnect_central(None);
+        Ok(())
+    }
+
+    /// Abort a reconnect waiting for its next attempt,
+    /// `Message::ConnectFailed(BluetoothError::Cancelled)` follows.
+    /// The simulated link comes up within `Adapter::connect`, there is no
+    /// pending connect to abort otherwise.
+    pub fn cancel(&mut self) -> Result<(), BluetoothError> {
+        let disconnected = self.state().is_disconnected();
+        let stopped = match self.reconnect {
+            Some(ref mut reconnect) if disconnected => {
+                reconnect.stop(Message::ConnectFailed(BluetoothError::Cancelled))
+            }
+            _ => false,
+        };
+        if !stopped {
+            return Err(BluetoothError::InvalidState);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut globals = GLOBALS.lock().unwrap();
+        if globals.generation != self.generation {
+            return;
+        }
+        if globals.connected.as_ref() == Some(&self.device_id.0) {
+            globals.disconnect_central(None);
+        }
+        globals.tx = None;
+    }
+}
+
+pub struct GattServer {
+    address: String,
+    state: Arc<Mutex<ServerState>>,
+    rx: Receiver<ServerEvent>,
+}
+
+impl GattServer {
+    /// Address the simulated peripheral is discovered with.
+    /// Simulation only, real platforms do not expose the local address.
+    pub fn device_id(&self) -> DeviceId {
+        DeviceId(self.address.clone())
+    }
+
+    pub fn set_read_handler<F>(&mut self, f: F)
+    where
+        F: FnMut(&ReadRequest) -> Result<Vec<u8>, AttError> + Send + 'static,
+    {
+        self.state.lock().unwrap().set_read_handler(f);
+    }
+
+    pub fn set_write_handler<F>(&mut self, f: F)
+    where
+        F: FnMut(&WriteRequest) -> Result<(), AttError> + Send + 'static,
+    {
+        self.state.lock().unwrap().set_write_handler(f);
+    }
+
+    /// Advertise the primary services of this server.
+    pub fn start_advertising(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+
+        let mut state = self.state.lock().unwrap();
+        let data = AdvertisementData {
+            local_name: Some(LOCAL_NAME.to_string()),
+            service_uuids: state
+                .services
+                .iter()
+                .filter(|service| service.primary)
+                .map(|service| service.uuid.clone())
+                .collect(),
+            ..Default::default()
+        };
+
+        match data.encode(true) {
+            Ok(payload) => {
+                globals.advertise(
+                    &self.address,
+                    Advertiser {
+                        payload,
+                        expires: None,
+                    },
+                );
+                state.send(ServerEvent::AdvertisingStarted);
+            }
+            Err(_) => state.send(ServerEvent::AdvertisingFailed(
+                AdvertisingError::DataTooLarge,
+            )),
+        }
+        Ok(())
+    }
+
+    pub fn stop_advertising(&mut self) -> Result<(), BluetoothError> {
+        GLOBALS.lock().unwrap().advertisers.remove(&self.address);
+
         Ok(())
     }
+
+    /// Send a new value to every central subscribed to the characteristic.
+    pub fn notify(&self, characteristic: &str, value: &[u8]) -> Result<(), BluetoothError> {
+        let subscribers = self.state.lock().unwrap().subscribers(characteristic);
+
+        let globals = GLOBALS.lock().unwrap();
+        if globals.connected.as_ref() != Some(&self.address) {
+            return Ok(());
+        }
+        if subscribers.iter().any(|central| central.0 == LOCAL_ADDRESS) {
+            // a notification is a single packet, the rest of the value is lost
+            let len = value
+                .len()
+                .min(gatt::max_write_len(globals.mtu, WriteType::WithoutResponse));
+            if let Some(ref tx) = globals.tx {
+                let _ = tx.send(Message::Data(value[..len].to_vec()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn try_recv(&mut self) -> Result<Option<ServerEvent>, BluetoothError> {
+        Ok(self.rx.try_recv().ok())
+    }
+
+    /// Replace the services, like a firmware update would. A connected central
+    /// gets a Service Changed indication and discovers them again.
+    /// Simulation only.
+    pub fn simulate_service_change(
+        &mut self,
+        services: Vec<LocalService>,
+    ) -> Result<(), BluetoothError> {
+        self.state.lock().unwrap().services = services;
+
+        let mut globals = GLOBALS.lock().unwrap();
+        if globals.connected.as_ref() == Some(&self.address) {
+            globals.services_changed();
+        }
+        Ok(())
+    }
+
+    /// Drop the link to the central as if it went out of range.
+    /// Simulation only, unlike `close` the server stays around to reconnect to.
+    pub fn simulate_link_loss(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+
+        if globals.connected.as_ref() == Some(&self.address) {
+            globals.disconnect_central(Some(BluetoothError::DeviceDisconnected));
+        }
+        Ok(())
+    }
+
+    /// Stop advertising and drop all the connected centrals, also done on drop.
+    pub fn close(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+
+        if globals.connected.as_ref() == Some(&self.address) {
+            globals.disconnect_central(Some(BluetoothError::DeviceDisconnected));
+        }
+        globals.servers.remove(&self.address);
+        globals.advertisers.remove(&self.address);
+        globals.devices.remove(&self.address);
+
+        Ok(())
+    }
+}
+
+impl Drop for GattServer {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
 }
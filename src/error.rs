@@ -0,0 +1,159 @@
+//! Errors shared by all the backends.
+
+use crate::gatt::CharacteristicProperties;
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BluetoothError {
+    /// The adapter is not initialized yet, see `Adapter::is_ready`.
+    AdapterNotReady,
+    /// Bluetooth is turned off.
+    AdapterOff,
+    /// The app lacks the Bluetooth permission, or the user denied it.
+    PermissionDenied,
+    DeviceUnavailable,
+    DeviceDisconnected,
+    /// The value does not fit into a single write, see `Connection::max_write_len`.
+    PayloadTooLarge,
+    /// Too many operations queued, see `Connection::queue_depth`.
+    QueueFull,
+    /// The platform has no such operation.
+    NotSupported,
+    /// The characteristic lacks the property the operation needs.
+    MissingProperty(CharacteristicProperties),
+    /// The peripheral did not answer in time, see `Adapter::set_timeouts`.
+    Timeout,
+    /// `Connection::cancel` was called.
+    Cancelled,
+    /// Another operation of the same kind is still running.
+    OperationInProgress,
+    /// The attribute is not on the peripheral (anymore).
+    InvalidHandle,
+    /// Not allowed in the current state of the connection, see `Connection::state`.
+    InvalidState,
+    /// An argument is out of range, e.g. an advertisement over the legacy size.
+    InvalidArgument,
+    /// The peripheral or the platform stack answered with a GATT status.
+    Gatt {
+        status: u16,
+        #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_status_name"))]
+        name: StatusName,
+    },
+    /// Java exception or `NSError` description.
+    Platform(String),
+}
+
+impl BluetoothError {
+    /// The peripheral wants an encrypted link, backends start pairing on
+    /// these and the operation can be retried once bonded.
+    pub fn requires_pairing(&self) -> bool {
+        matches!(
+            self,
+            BluetoothError::Gatt {
+                status: 0x05 | 0x0c | 0x0f,
+                ..
+            }
+        )
+    }
+
+    /// Error for a failed GATT status as reported by Android callbacks or
+    /// `CBATTErrorDomain` codes.
+    pub fn from_gatt_status(status: u16) -> BluetoothError {
+        match status {
+            0x01 => BluetoothError::InvalidHandle,
+            0xfe => BluetoothError::OperationInProgress,
+            _ => BluetoothError::Gatt {
+                status,
+                name: gatt_status_name(status),
+            },
+        }
+    }
+}
+
+// Spelled as an alias, serde's derive would borrow a `&'static str` field
+// from the input and only deserialize from `'static` data.
+type StatusName = &'static str;
+
+/// The names all come from `gatt_status_name`, find the static one again.
+#[cfg(feature = "serde")]
+fn deserialize_status_name<'de, D>(deserializer: D) -> Result<StatusName, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let name = <String as serde::Deserialize>::deserialize(deserializer)?;
+    Ok((0..=0x101)
+        .map(gatt_status_name)
+        .find(|known| *known == name)
+        .unwrap_or("Unknown"))
+}
+
+/// Name of an ATT error code, or of an Android specific GATT status.
+pub fn gatt_status_name(status: u16) -> &'static str {
+    match status {
+        0x00 => "Success",
+        0x01 => "Invalid Handle",
+        0x02 => "Read Not Permitted",
+        0x03 => "Write Not Permitted",
+        0x04 => "Invalid PDU",
+        0x05 => "Insufficient Authentication",
+        0x06 => "Request Not Supported",
+        0x07 => "Invalid Offset",
+        0x08 => "Insufficient Authorization",
+        0x09 => "Prepare Queue Full",
+        0x0a => "Attribute Not Found",
+        0x0b => "Attribute Not Long",
+        0x0c => "Insufficient Encryption Key Size",
+        0x0d => "Invalid Attribute Value Length",
+        0x0e => "Unlikely Error",
+        0x0f => "Insufficient Encryption",
+        0x10 => "Unsupported Group Type",
+        0x11 => "Insufficient Resources",
+        0x12 => "Database Out Of Sync",
+        0x13 => "Value Not Allowed",
+        0x80..=0x9f => match status {
+            // Android GATT_ERROR and GATT_CONNECTION_CONGESTED
+            0x85 => "GATT Error",
+            0x8f => "Connection Congested",
+            _ => "Application Error",
+        },
+        0xfc => "Write Request Rejected",
+        0xfd => "Client Characteristic Configuration Descriptor Improperly Configured",
+        0xfe => "Procedure Already in Progress",
+        0xff => "Out of Range",
+        // Android GATT_FAILURE
+        0x101 => "GATT Failure",
+        _ => "Unknown",
+    }
+}
+
+impl fmt::Display for BluetoothError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BluetoothError::AdapterNotReady => write!(f, "Bluetooth adapter is not ready"),
+            BluetoothError::AdapterOff => write!(f, "Bluetooth is turned off"),
+            BluetoothError::PermissionDenied => write!(f, "Bluetooth permission denied"),
+            BluetoothError::DeviceUnavailable => write!(f, "device unavailable"),
+            BluetoothError::DeviceDisconnected => write!(f, "device disconnected"),
+            BluetoothError::PayloadTooLarge => write!(f, "payload too large for a single write"),
+            BluetoothError::QueueFull => write!(f, "operation queue is full"),
+            BluetoothError::NotSupported => write!(f, "not supported on this platform"),
+            BluetoothError::MissingProperty(property) => {
+                write!(f, "characteristic lacks {:?}", property)
+            }
+            BluetoothError::Timeout => write!(f, "operation timed out"),
+            BluetoothError::Cancelled => write!(f, "cancelled"),
+            BluetoothError::OperationInProgress => write!(f, "operation already in progress"),
+            BluetoothError::InvalidHandle => write!(f, "invalid attribute handle"),
+            BluetoothError::InvalidState => write!(f, "not allowed in this connection state"),
+            BluetoothError::InvalidArgument => write!(f, "invalid argument"),
+            BluetoothError::Gatt { status, name } => {
+                write!(f, "GATT error 0x{:02x}: {}", status, name)
+            }
+            BluetoothError::Platform(description) => write!(f, "{}", description),
+        }
+    }
+}
+
+impl std::error::Error for BluetoothError {}
@@ -0,0 +1,64 @@
+//! Adapter wide events, polled with `Adapter::try_recv`.
+
+use crate::advertising::AdvertisingError;
+use crate::permission::PermissionStatus;
+use crate::{BluetoothError, DeviceId};
+
+/// Bluetooth availability, as CoreBluetooth reports it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AdapterState {
+    /// Not known yet, a `StateChanged` event follows.
+    Unknown,
+    /// The stack is restarting or switching on or off, a new state follows.
+    Resetting,
+    /// No Bluetooth LE on this device.
+    Unsupported,
+    /// The app lacks the Bluetooth permissions, or the user denied them.
+    Unauthorized,
+    PoweredOff,
+    PoweredOn,
+}
+
+impl AdapterState {
+    /// Error for operations that need a powered on adapter.
+    pub fn require_powered_on(self) -> Result<(), BluetoothError> {
+        match self {
+            AdapterState::PoweredOn => Ok(()),
+            AdapterState::PoweredOff => Err(BluetoothError::AdapterOff),
+            AdapterState::Unauthorized => Err(BluetoothError::PermissionDenied),
+            AdapterState::Unsupported => Err(BluetoothError::NotSupported),
+            AdapterState::Unknown | AdapterState::Resetting => Err(BluetoothError::AdapterNotReady),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BondState {
+    None,
+    /// Pairing is running, the user may be asked to confirm it.
+    Bonding,
+    Bonded,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AdapterEvent {
+    /// See `Adapter::state`. The first event of every adapter, with the
+    /// state at the time it was created.
+    StateChanged(AdapterState),
+    /// Answer to `Adapter::request_permissions`.
+    PermissionsResult(PermissionStatus),
+    /// Answer to `Adapter::request_enable`, `true` if the user turned Bluetooth on.
+    EnableResult(bool),
+    /// See `Device::bond`. Also follows a failed pairing, with `BondState::None`.
+    BondStateChanged {
+        device: DeviceId,
+        state: BondState,
+    },
+    AdvertisingStarted,
+    /// Stopped on request or because the advertising timeout elapsed.
+    AdvertisingStopped,
+    AdvertisingFailed(AdvertisingError),
+}
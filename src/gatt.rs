@@ -0,0 +1,204 @@
+//! Platform independent parts of the GATT client.
+
+use crate::BluetoothError;
+
+/// ATT_MTU every connection starts with.
+pub const DEFAULT_MTU: usize = 23;
+/// Largest ATT_MTU a connection may negotiate.
+pub const MAX_MTU: usize = 517;
+/// Largest attribute value, the limit for writes with response.
+pub const MAX_ATTRIBUTE_LEN: usize = 512;
+/// Opcode and handle of an ATT write or notification.
+pub(crate) const ATT_WRITE_HEADER_LEN: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WriteType {
+    /// Acknowledged by the peripheral. Values longer than a single packet are
+    /// sent as a long write by the OS.
+    WithResponse,
+    /// Unacknowledged, limited to a single packet.
+    WithoutResponse,
+}
+
+impl WriteType {
+    /// `send_bytes` takes a `verify` flag rather than a write type.
+    pub(crate) fn from_verify(verify: bool) -> WriteType {
+        if verify {
+            WriteType::WithResponse
+        } else {
+            WriteType::WithoutResponse
+        }
+    }
+
+    /// Without response if the characteristic takes it, with response otherwise.
+    /// Streams use it, plenty of peripherals only declare one of the two.
+    pub fn preferred(properties: CharacteristicProperties) -> WriteType {
+        if properties.contains(CharacteristicProperties::WRITE_WITHOUT_RESPONSE) {
+            WriteType::WithoutResponse
+        } else {
+            WriteType::WithResponse
+        }
+    }
+
+    /// Property a characteristic needs for writes of this type.
+    pub fn required_property(self) -> CharacteristicProperties {
+        match self {
+            WriteType::WithResponse => CharacteristicProperties::WRITE,
+            WriteType::WithoutResponse => CharacteristicProperties::WRITE_WITHOUT_RESPONSE,
+        }
+    }
+}
+
+/// Trade-off between latency and power, see `Connection::request_connection_priority`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConnectionPriority {
+    /// What every connection starts with, a 30 - 50 ms interval on Android.
+    Balanced,
+    /// Short interval for throughput and latency, 11.25 - 15 ms.
+    High,
+    /// Long interval with slave latency, 100 - 125 ms.
+    LowPower,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Phy {
+    Le1M,
+    /// Twice the data rate, half the range.
+    Le2M,
+    /// Long range, at most a quarter of the data rate.
+    LeCoded,
+}
+
+/// Longest value a single write of the given type can carry with this ATT_MTU.
+/// CoreBluetooth reports it itself.
+#[cfg(not(any(target_os = "ios", target_os = "macos")))]
+pub(crate) fn max_write_len(mtu: usize, write_type: WriteType) -> usize {
+    match write_type {
+        WriteType::WithResponse => MAX_ATTRIBUTE_LEN,
+        WriteType::WithoutResponse => mtu - ATT_WRITE_HEADER_LEN,
+    }
+}
+
+/// Properties of a remote characteristic, the bits of the characteristic
+/// declaration. Android `getProperties` and iOS `CBCharacteristicProperties`
+/// share the values.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CharacteristicProperties(u16);
+
+impl CharacteristicProperties {
+    pub const BROADCAST: CharacteristicProperties = CharacteristicProperties(0x01);
+    pub const READ: CharacteristicProperties = CharacteristicProperties(0x02);
+    pub const WRITE_WITHOUT_RESPONSE: CharacteristicProperties = CharacteristicProperties(0x04);
+    pub const WRITE: CharacteristicProperties = CharacteristicProperties(0x08);
+    pub const NOTIFY: CharacteristicProperties = CharacteristicProperties(0x10);
+    pub const INDICATE: CharacteristicProperties = CharacteristicProperties(0x20);
+    pub const AUTHENTICATED_SIGNED_WRITES: CharacteristicProperties =
+        CharacteristicProperties(0x40);
+    /// Described further by the Characteristic Extended Properties descriptor.
+    pub const EXTENDED_PROPERTIES: CharacteristicProperties = CharacteristicProperties(0x80);
+    /// iOS only, notifications require an encrypted link.
+    pub const NOTIFY_ENCRYPTION_REQUIRED: CharacteristicProperties =
+        CharacteristicProperties(0x100);
+    /// iOS only, indications require an encrypted link.
+    pub const INDICATE_ENCRYPTION_REQUIRED: CharacteristicProperties =
+        CharacteristicProperties(0x200);
+
+    const NAMES: [(CharacteristicProperties, &'static str); 10] = [
+        (Self::BROADCAST, "BROADCAST"),
+        (Self::READ, "READ"),
+        (Self::WRITE_WITHOUT_RESPONSE, "WRITE_WITHOUT_RESPONSE"),
+        (Self::WRITE, "WRITE"),
+        (Self::NOTIFY, "NOTIFY"),
+        (Self::INDICATE, "INDICATE"),
+        (
+            Self::AUTHENTICATED_SIGNED_WRITES,
+            "AUTHENTICATED_SIGNED_WRITES",
+        ),
+        (Self::EXTENDED_PROPERTIES, "EXTENDED_PROPERTIES"),
+        (
+            Self::NOTIFY_ENCRYPTION_REQUIRED,
+            "NOTIFY_ENCRYPTION_REQUIRED",
+        ),
+        (
+            Self::INDICATE_ENCRYPTION_REQUIRED,
+            "INDICATE_ENCRYPTION_REQUIRED",
+        ),
+    ];
+
+    pub const fn empty() -> CharacteristicProperties {
+        CharacteristicProperties(0)
+    }
+
+    /// Unknown bits are dropped.
+    pub const fn from_bits_truncate(bits: u16) -> CharacteristicProperties {
+        CharacteristicProperties(bits & 0x3ff)
+    }
+
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// All of `other` is set.
+    pub const fn contains(self, other: CharacteristicProperties) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Any of `other` is set.
+    pub const fn intersects(self, other: CharacteristicProperties) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// `BluetoothError::MissingProperty` unless all of `required` is set.
+    pub(crate) fn require(self, required: CharacteristicProperties) -> Result<(), BluetoothError> {
+        if self.contains(required) {
+            Ok(())
+        } else {
+            Err(BluetoothError::MissingProperty(required))
+        }
+    }
+}
+
+impl std::ops::BitOr for CharacteristicProperties {
+    type Output = CharacteristicProperties;
+
+    fn bitor(self, other: CharacteristicProperties) -> CharacteristicProperties {
+        CharacteristicProperties(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for CharacteristicProperties {
+    fn bitor_assign(&mut self, other: CharacteristicProperties) {
+        self.0 |= other.0;
+    }
+}
+
+impl std::ops::BitAnd for CharacteristicProperties {
+    type Output = CharacteristicProperties;
+
+    fn bitand(self, other: CharacteristicProperties) -> CharacteristicProperties {
+        CharacteristicProperties(self.0 & other.0)
+    }
+}
+
+impl std::fmt::Debug for CharacteristicProperties {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names = Self::NAMES
+            .iter()
+            .filter(|(property, _)| self.contains(*property))
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            write!(f, "CharacteristicProperties(empty)")
+        } else {
+            write!(f, "CharacteristicProperties({})", names.join(" | "))
+        }
+    }
+}
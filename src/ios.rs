@@ -3,102 +3,313 @@
 use miniquad::info;
 
 use once_cell::sync::Lazy;
-use std::{collections::HashMap, fmt, sync::Mutex};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use std::sync::mpsc::{self, Receiver, Sender};
 
 //use objc::{msg_send, class, sel, sel_impl};
 use miniquad::native::apple::{apple_util::*, frameworks::*};
 
+use crate::advertising::{AdvertisementData, AdvertisingError, AdvertisingSettings};
+use crate::error::BluetoothError;
+use crate::event::{AdapterEvent, AdapterState, BondState};
+use crate::gatt::{
+    CharacteristicProperties, ConnectionPriority, Phy, WriteType, ATT_WRITE_HEADER_LEN,
+};
+use crate::peripheral::{
+    self, Access, AttError, LocalService, Permissions, ReadRequest, ServerEvent, ServerState,
+    WriteRequest,
+};
+use crate::permission::PermissionStatus;
+use crate::queue::{Operation, OperationQueue};
+use crate::reconnect::{ReconnectPolicy, Reconnector};
+use crate::rssi::RssiMonitor;
+use crate::state::{ConnectionState, StateEvent};
+use crate::timeout::{Deadline, Timeouts};
+
 #[link(name = "CoreBluetooth", kind = "framework")]
-extern "C" {}
+extern "C" {
+    static CBAdvertisementDataLocalNameKey: ObjcId;
+    static CBAdvertisementDataManufacturerDataKey: ObjcId;
+    static CBAdvertisementDataServiceDataKey: ObjcId;
+    static CBAdvertisementDataServiceUUIDsKey: ObjcId;
+    static CBAdvertisementDataTxPowerLevelKey: ObjcId;
+    static CBCentralManagerOptionShowPowerAlertKey: ObjcId;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceId(String);
+
+impl DeviceId {
+    /// A stored id, as `DeviceId::as_str` gave it in an earlier session.
+    /// `Adapter::connect` takes it without scanning first.
+    pub fn new(id: &str) -> DeviceId {
+        DeviceId(id.to_string())
+    }
+
+    /// `CBPeripheral.identifier`, stable for this app on this iPhone.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
 
+/// Retained Objective-C object, released on drop.
 #[derive(Debug)]
-pub enum BluetoothError {
-    AdapterNotReady,
-    DeviceUnavailable,
-    DeviceDisconnected,
+struct Retained(ObjcId);
+
+impl Retained {
+    unsafe fn new(object: ObjcId) -> Retained {
+        let object: ObjcId = msg_send![object, retain];
+        Retained(object)
+    }
 }
 
-impl fmt::Display for BluetoothError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+impl Clone for Retained {
+    fn clone(&self) -> Retained {
+        unsafe { Retained::new(self.0) }
     }
 }
-impl std::error::Error for BluetoothError {}
 
-#[derive(Clone, Debug)]
-pub struct DeviceId(String);
+impl Drop for Retained {
+    fn drop(&mut self) {
+        unsafe {
+            let () = msg_send![self.0, release];
+        }
+    }
+}
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Device {
-    peripheral: ObjcId,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    peripheral: Retained,
     pub address: String,
     pub name: Option<String>,
+    /// Payload of the last advertisement seen.
+    pub advertisement: AdvertisementData,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Characteristic {
     pub id: String,
-    characteristic: ObjcId,
-    peripheral: ObjcId,
-    // pub write: bool,
-    // pub read: bool,
-    // pub notify: bool,
-    // pub indicate: bool,
-    // pub broadcast: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    characteristic: Retained,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    peripheral: Retained,
+    pub properties: CharacteristicProperties,
+    /// `GlobalData::discovery` the characteristic was reported in.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    discovery: u64,
 }
 
 impl Device {
     pub fn id(&self) -> DeviceId {
         DeviceId(self.address.clone())
     }
+
+    /// CoreBluetooth has no pairing API, iOS pairs on its own once the
+    /// peripheral asks for an encrypted link.
+    pub fn bond(&self) -> Result<(), BluetoothError> {
+        Err(BluetoothError::NotSupported)
+    }
+
+    pub fn bond_state(&self) -> Result<BondState, BluetoothError> {
+        Err(BluetoothError::NotSupported)
+    }
 }
 
 impl Characteristic {
+    /// Queue the string as a write without response, see `send_bytes`.
     pub fn send_string(&self, data: &str) -> Result<(), BluetoothError> {
         self.send_bytes(data.as_bytes(), false)
     }
 
+    /// Queue a write, `verify` asks for a write with response.
     pub fn send_bytes(&self, data: &[u8], verify: bool) -> Result<(), BluetoothError> {
-        unsafe {
-            let data: ObjcId = msg_send![class!(NSData),
-                                         dataWithBytes:data.as_ptr()
-                                         length: data.len()];
-            let () = msg_send![self.peripheral,
-                              writeValue:data
-                               forCharacteristic:self.characteristic
-                               type:if verify {1} else {0}
-            ];
+        let write_type = WriteType::from_verify(verify);
+        self.properties.require(write_type.required_property())?;
+        if data.len() > unsafe { max_write_len(self.peripheral.0, write_type) } {
+            return Err(BluetoothError::PayloadTooLarge);
         }
 
-        Ok(())
+        enqueue(Operation::Write(self.clone(), data.to_vec(), write_type))
+    }
+
+    /// Request the current value, it arrives as `Message::Data`.
+    pub fn read_value(&self) -> Result<(), BluetoothError> {
+        self.properties.require(CharacteristicProperties::READ)?;
+        enqueue(Operation::Read(self.clone()))
+    }
+
+    /// Write a value longer than a single packet with response, as prepared writes.
+    /// CoreBluetooth splits the value into prepared writes itself.
+    ///
+    /// An attribute holds at most `MAX_ATTRIBUTE_LEN` (512) bytes, longer data
+    /// fails with `BluetoothError::PayloadTooLarge`: send it in frames, see `codec::Framed`.
+    pub fn write_long(&self, data: &[u8]) -> Result<(), BluetoothError> {
+        self.send_bytes(data, true)
     }
 
+    /// Enable or disable notifications, confirmed by `Message::SubscriptionChanged`.
     pub fn set_notification(&self, notify: bool) -> Result<(), BluetoothError> {
-        unsafe {
-            let () = msg_send![self.peripheral,
-                              setNotifyValue:YES
-                              forCharacteristic:self.characteristic
-            ];
-        }
-        Ok(())
+        self.properties.require(CharacteristicProperties::NOTIFY)?;
+        enqueue(Operation::Subscribe {
+            characteristic: self.clone(),
+            enable: notify,
+            indicate: false,
+        })
     }
 
+    /// Enable or disable indications, confirmed by `Message::SubscriptionChanged`.
     pub fn set_indication(&self, notify: bool) -> Result<(), BluetoothError> {
-        unsafe {
-            let () = msg_send![self.peripheral,
-                              setNotifyValue:YES
-                              forCharacteristic:self.characteristic
-            ];
+        self.properties
+            .require(CharacteristicProperties::INDICATE)?;
+        enqueue(Operation::Subscribe {
+            characteristic: self.clone(),
+            enable: notify,
+            indicate: true,
+        })
+    }
+}
+
+fn enqueue(operation: Operation) -> Result<(), BluetoothError> {
+    let mut globals = GLOBALS.lock().unwrap();
+    globals.state.require_ready()?;
+    if operation
+        .characteristic()
+        .is_some_and(|characteristic| characteristic.discovery != globals.discovery)
+    {
+        return Err(BluetoothError::InvalidHandle);
+    }
+    globals.queue.push(operation)?;
+    unsafe { pump_queue(&mut globals) };
+
+    Ok(())
+}
+
+/// One operation at a time, completed from the delegate callback reporting it.
+/// Writes without response have no callback and complete right away, but
+/// CoreBluetooth silently drops them once the link is out of buffers: those
+/// wait for `peripheralIsReadyToSendWriteWithoutResponse:`.
+unsafe fn pump_queue(globals: &mut GlobalData) {
+    loop {
+        if let Some(Operation::Write(characteristic, _, WriteType::WithoutResponse)) =
+            globals.queue.front()
+        {
+            let ready: BOOL = msg_send![characteristic.peripheral.0, canSendWriteWithoutResponse];
+            if ready == NO {
+                return;
+            }
         }
-        Ok(())
+        let operation = match globals.queue.start() {
+            Some(operation) => operation,
+            None => return,
+        };
+        if !start_operation(globals, &operation) {
+            return;
+        }
+        globals.queue.complete();
+    }
+}
+
+/// The operation in flight is done if `reported` matches it, start the next one.
+fn complete_operation<F: Fn(&Operation) -> bool>(reported: F) {
+    let mut globals = GLOBALS.lock().unwrap();
+    if globals.queue.in_flight().is_some_and(reported) {
+        globals.queue.complete();
+        unsafe { pump_queue(&mut globals) };
+    }
+}
+
+/// `true` if the operation is complete already, `false` if a delegate callback
+/// completes it.
+unsafe fn start_operation(globals: &GlobalData, operation: &Operation) -> bool {
+    match operation {
+        Operation::Read(characteristic) => {
+            let () = msg_send![characteristic.peripheral.0,
+                               readValueForCharacteristic:characteristic.characteristic.0];
+            false
+        }
+        Operation::Write(characteristic, value, write_type) => {
+            let data: ObjcId = msg_send![class!(NSData),
+                                         dataWithBytes:value.as_ptr()
+                                         length:value.len()];
+            // CBCharacteristicWriteType
+            let write_type: isize = match write_type {
+                WriteType::WithResponse => 0,
+                WriteType::WithoutResponse => 1,
+            };
+            let () = msg_send![characteristic.peripheral.0,
+                               writeValue:data
+                               forCharacteristic:characteristic.characteristic.0
+                               type:write_type];
+            write_type == 1
+        }
+        Operation::Subscribe {
+            characteristic,
+            enable,
+            ..
+        } => {
+            // CoreBluetooth picks notifications or indications by the properties
+            let enable = if *enable { YES } else { NO };
+            let () = msg_send![characteristic.peripheral.0,
+                               setNotifyValue:enable
+                               forCharacteristic:characteristic.characteristic.0];
+            false
+        }
+        Operation::ReadRssi => match globals.peripheral {
+            Some(ref peripheral) => {
+                let () = msg_send![peripheral.0, readRSSI];
+                false
+            }
+            None => true,
+        },
     }
 }
+
 struct GlobalData {
     devices: HashMap<String, Device>,
     tx: Option<Sender<Message>>,
     rx: Option<Receiver<Vec<u8>>>,
+    server: Option<Arc<Mutex<ServerState>>>,
+    peripheral_manager: ObjcId,
+    // CBMutableCharacteristic for each local (service, characteristic), needed for notifications
+    server_characteristics: Vec<(String, String, ObjcId)>,
+    // values CoreBluetooth had no room for, sent again once it is ready to update subscribers
+    pending_notifications: VecDeque<(ObjcId, Vec<u8>)>,
+    advertise_requested: bool,
+    adapter_tx: Option<Sender<AdapterEvent>>,
+    // `Adapter::request_permissions` waits for the user to answer the prompt
+    permissions_requested: bool,
+    // `Adapter::request_enable` waits for Bluetooth to be turned on
+    enable_requested: bool,
+    // manager created to show the power alert
+    power_alert: Option<Retained>,
+    // advertisement requested with `Adapter::start_advertising`
+    broadcast: Option<AdvertisementData>,
+    broadcast_expires: Option<Instant>,
+    queue: OperationQueue,
+    // CBCentralManager of the `Adapter`
+    central: ObjcId,
+    state: ConnectionState,
+    // CBPeripheral connected or being connected to
+    peripheral: Option<Retained>,
+    // services with characteristic discovery still running
+    pending_services: usize,
+    timeouts: Timeouts,
+    deadline: Option<Deadline>,
+    /// Bumped by every `Adapter::connect`, a dropped `Connection` only
+    /// tears down the link while it is still the current one.
+    generation: u64,
+    /// Bumped whenever the services change, `Characteristic`s from an
+    /// earlier discovery are stale.
+    discovery: u64,
 }
 
 unsafe impl Send for GlobalData {}
@@ -109,16 +320,169 @@ static GLOBALS: Lazy<Mutex<GlobalData>> = Lazy::new(|| {
         devices: HashMap::new(),
         tx: None,
         rx: None,
+        server: None,
+        peripheral_manager: nil,
+        server_characteristics: vec![],
+        pending_notifications: VecDeque::new(),
+        advertise_requested: false,
+        adapter_tx: None,
+        permissions_requested: false,
+        enable_requested: false,
+        power_alert: None,
+        broadcast: None,
+        broadcast_expires: None,
+        queue: OperationQueue::new(),
+        central: nil,
+        state: ConnectionState::default(),
+        peripheral: None,
+        pending_services: 0,
+        timeouts: Timeouts::default(),
+        deadline: None,
+        generation: 0,
+        discovery: 0,
     };
     Mutex::new(data)
 });
 
+/// Take in a peripheral no scan has seen, keeping what a scan found out.
+unsafe fn add_peripheral(globals: &mut GlobalData, peripheral: ObjcId) -> String {
+    let uuid: ObjcId = msg_send![peripheral, identifier];
+    let uuid: ObjcId = msg_send![uuid, UUIDString];
+    let uuid = nsstring_to_string(uuid);
+    if globals.devices.contains_key(&uuid) {
+        return uuid;
+    }
+
+    let delegate: ObjcId = msg_send![globals.central, delegate];
+    let () = msg_send![peripheral, setDelegate: delegate];
+    let name: ObjcId = msg_send![peripheral, name];
+    globals.devices.insert(
+        uuid.clone(),
+        Device {
+            peripheral: Retained::new(peripheral),
+            name: (name != nil).then(|| nsstring_to_string(name)),
+            address: uuid.clone(),
+            advertisement: AdvertisementData::default(),
+        },
+    );
+    uuid
+}
+
+/// Look up a peripheral by its stored identifier, in range or not.
+unsafe fn known_device(globals: &mut GlobalData, identifier: &str) {
+    if globals.devices.contains_key(identifier) {
+        return;
+    }
+
+    let uuid: ObjcId = msg_send![class!(NSUUID), alloc];
+    let uuid: ObjcId = msg_send![uuid, initWithUUIDString: str_to_nsstring(identifier)];
+    if uuid == nil {
+        return;
+    }
+    let identifiers: ObjcId = msg_send![class!(NSArray), arrayWithObject: uuid];
+    let () = msg_send![uuid, release];
+
+    let peripherals: ObjcId =
+        msg_send![globals.central, retrievePeripheralsWithIdentifiers: identifiers];
+    let count: usize = msg_send![peripherals, count];
+    if count > 0 {
+        let peripheral: ObjcId = msg_send![peripherals, objectAtIndex: 0];
+        add_peripheral(globals, peripheral);
+    }
+}
+
+unsafe fn connect_device(
+    globals: &mut GlobalData,
+    device_id: &DeviceId,
+) -> Result<(), BluetoothError> {
+    let state: ManagerState = msg_send![globals.central, state];
+    state.check()?;
+
+    let peripheral = globals
+        .devices
+        .get(&device_id.0)
+        .ok_or(BluetoothError::DeviceUnavailable)?
+        .peripheral
+        .clone();
+    globals.state.transition(StateEvent::Connect)?;
+    globals.queue.clear();
+    globals.deadline = Some(Deadline::new(globals.timeouts.connect, false));
+
+    let () = msg_send![globals.central,
+                       connectPeripheral:peripheral.0
+                       options:nil];
+    globals.peripheral = Some(peripheral);
+    Ok(())
+}
+
+/// Tear the connection down right away, the delegate callbacks that may
+/// follow are ignored.
+unsafe fn abort(globals: &mut GlobalData, reason: BluetoothError) {
+    let rediscovery = globals
+        .deadline
+        .is_some_and(|deadline| deadline.rediscovery);
+    let message = if globals.state.is_connecting() && !rediscovery {
+        Message::ConnectFailed(reason.clone())
+    } else {
+        Message::Disconnected(Some(reason.clone()))
+    };
+    if let Some(ref peripheral) = globals.peripheral {
+        let () = msg_send![globals.central, cancelPeripheralConnection: peripheral.0];
+    }
+    globals.queue.clear();
+    let _ = globals
+        .state
+        .transition(StateEvent::Disconnected(Some(reason)));
+    if let Some(ref tx) = globals.tx {
+        let _ = tx.send(message);
+    }
+}
+
+fn check_timeouts() {
+    let mut globals = GLOBALS.lock().unwrap();
+
+    if globals.state.is_connecting()
+        && globals
+            .deadline
+            .is_some_and(|deadline| deadline.expired(Instant::now()))
+    {
+        unsafe { abort(&mut globals, BluetoothError::Timeout) };
+    } else if globals.state.is_ready() && globals.queue.overdue(globals.timeouts.operation) {
+        if let Some(ref tx) = globals.tx {
+            let _ = tx.send(Message::Error(BluetoothError::Timeout));
+        }
+        unsafe { abort(&mut globals, BluetoothError::Timeout) };
+    }
+}
+
+/// Read the RSSI when the monitor is due, CoreBluetooth queues the reads itself.
+fn monitor_rssi(monitor: &mut RssiMonitor) {
+    let globals = GLOBALS.lock().unwrap();
+    let idle = globals.state.is_ready() && !globals.queue.rssi_queued();
+    drop(globals);
+    if idle && monitor.due() {
+        let _ = enqueue(Operation::ReadRssi);
+    }
+}
+
+/// Connect again to the device of a `Connection`, on the same channel.
+pub(crate) fn reconnect_device(device_id: &DeviceId) -> Result<(), BluetoothError> {
+    unsafe { connect_device(&mut GLOBALS.lock().unwrap(), device_id) }
+}
+
+/// A pending connect completes once the peripheral advertises again,
+/// there is nothing to wait for.
+pub(crate) fn device_seen_since(_device_id: &DeviceId, _since: Instant) -> bool {
+    true
+}
+
 pub struct Adapter {
     blue_central: ObjcId,
+    rx: Receiver<AdapterEvent>,
 }
 
 #[repr(usize)]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ManagerState {
     Unknown = 0,
     Resetting,
@@ -128,6 +492,53 @@ pub enum ManagerState {
     PoweredOn,
 }
 
+impl ManagerState {
+    /// Error for operations that need a powered on manager.
+    fn check(&self) -> Result<(), BluetoothError> {
+        AdapterState::from(*self).require_powered_on()
+    }
+}
+
+/// `CBManager.authorization`, asked for when the first manager is created.
+fn permission_status() -> PermissionStatus {
+    let authorization: isize = unsafe { msg_send![class!(CBManager), authorization] };
+    match authorization {
+        0 => PermissionStatus::NotDetermined,
+        3 => PermissionStatus::Granted,
+        // denied and restricted, iOS never asks twice
+        _ => PermissionStatus::PermanentlyDenied,
+    }
+}
+
+impl From<ManagerState> for AdapterState {
+    fn from(state: ManagerState) -> AdapterState {
+        match state {
+            ManagerState::Unknown => AdapterState::Unknown,
+            ManagerState::Resetting => AdapterState::Resetting,
+            ManagerState::Unsupported => AdapterState::Unsupported,
+            ManagerState::Unauthorized => AdapterState::Unauthorized,
+            ManagerState::PoweredOff => AdapterState::PoweredOff,
+            ManagerState::PoweredOn => AdapterState::PoweredOn,
+        }
+    }
+}
+
+/// `CBATTErrorDomain` codes are ATT errors, anything else is described as is.
+unsafe fn nserror_to_error(error: ObjcId) -> BluetoothError {
+    let domain: ObjcId = msg_send![error, domain];
+    let domain = nsstring_to_string(domain);
+    let code: isize = msg_send![error, code];
+    match (domain.as_str(), code) {
+        ("CBATTErrorDomain", code) => return BluetoothError::from_gatt_status(code as u16),
+        // CBErrorConnectionTimeout and CBErrorPeripheralDisconnected
+        ("CBErrorDomain", 6) => return BluetoothError::Timeout,
+        ("CBErrorDomain", 7) => return BluetoothError::DeviceDisconnected,
+        _ => {}
+    }
+    let description: ObjcId = msg_send![error, localizedDescription];
+    BluetoothError::Platform(nsstring_to_string(description))
+}
+
 pub fn define_central_manager_delegate() -> *const Class {
     let superclass = class!(NSObject);
     let mut decl = ClassDecl::new("QuadBTCentralManager", superclass).unwrap();
@@ -145,6 +556,23 @@ pub fn define_central_manager_delegate() -> *const Class {
                                       scanForPeripheralsWithServices:nil
                                       options:nil];
             }
+            send_adapter_event(AdapterEvent::StateChanged(state.into()));
+
+            let status = permission_status();
+            let mut globals = GLOBALS.lock().unwrap();
+            if globals.enable_requested && state == ManagerState::PoweredOn {
+                globals.enable_requested = false;
+                globals.power_alert = None;
+                if let Some(ref tx) = globals.adapter_tx {
+                    let _ = tx.send(AdapterEvent::EnableResult(true));
+                }
+            }
+            if globals.permissions_requested && status != PermissionStatus::NotDetermined {
+                globals.permissions_requested = false;
+                if let Some(ref tx) = globals.adapter_tx {
+                    let _ = tx.send(AdapterEvent::PermissionsResult(status));
+                }
+            }
         };
     }
 
@@ -153,12 +581,10 @@ pub fn define_central_manager_delegate() -> *const Class {
         _: Sel,
         _central: ObjcId,
         peripheral: ObjcId,
-        _advertisement_data: ObjcId,
+        advertisement_data: ObjcId,
         _rssi: ObjcId,
     ) {
         unsafe {
-            let peripheral: ObjcId = msg_send![peripheral, retain];
-
             let () = msg_send![peripheral, setDelegate: this];
 
             let name: ObjcId = msg_send![peripheral, name];
@@ -168,13 +594,16 @@ pub fn define_central_manager_delegate() -> *const Class {
             let uuid: ObjcId = msg_send![uuid, UUIDString];
             let uuid = nsstring_to_string(uuid);
 
+            let advertisement = advertisement_from_dictionary(advertisement_data);
+
             let mut globals = GLOBALS.lock().unwrap();
             globals.devices.insert(
                 uuid.clone(),
                 Device {
-                    peripheral,
+                    peripheral: Retained::new(peripheral),
                     name: Some(name),
                     address: uuid,
+                    advertisement,
                 },
             );
         }
@@ -186,13 +615,18 @@ pub fn define_central_manager_delegate() -> *const Class {
         _central: ObjcId,
         peripheral: ObjcId,
     ) {
-        info!("connect peripheral?");
+        {
+            let mut globals = GLOBALS.lock().unwrap();
+            if globals.state.transition(StateEvent::Connected).is_err() {
+                info!("unexpected connect in state {:?}", globals.state);
+                return;
+            }
+            let _ = globals.state.transition(StateEvent::DiscoveryStarted);
+            globals.deadline = Some(Deadline::new(globals.timeouts.discovery, false));
+        }
+        send_message(Message::Connected);
 
         unsafe {
-            let string = str_to_nsstring("6E400001-B5A3-F393-E0A9-E50E24DCCA9E");
-            let cbuuid: ObjcId = msg_send![class!(CBUUID), UUIDWithString: string];
-
-            let arr: ObjcId = msg_send![class!(NSArray), arrayWithObject: cbuuid];
             let () = msg_send![peripheral, discoverServices: nil];
         }
     }
@@ -202,9 +636,22 @@ pub fn define_central_manager_delegate() -> *const Class {
         _: Sel,
         _central: ObjcId,
         _peripheral: ObjcId,
-        _error: ObjcId,
+        error: ObjcId,
     ) {
-        panic!("disconnect?");
+        // no error after cancelPeripheralConnection:
+        let reason = (error != nil).then(|| unsafe { nserror_to_error(error) });
+        {
+            let mut globals = GLOBALS.lock().unwrap();
+            // torn down by `abort` already
+            if globals.state.is_disconnected() {
+                return;
+            }
+            globals.queue.clear();
+            let _ = globals
+                .state
+                .transition(StateEvent::Disconnected(reason.clone()));
+        }
+        send_message(Message::Disconnected(reason));
     }
 
     extern "C" fn did_fail_to_connect_peripheral(
@@ -214,7 +661,21 @@ pub fn define_central_manager_delegate() -> *const Class {
         _peripheral: ObjcId,
         error: ObjcId,
     ) {
-        panic!("error!")
+        let err = if error != nil {
+            unsafe { nserror_to_error(error) }
+        } else {
+            BluetoothError::DeviceUnavailable
+        };
+        {
+            let mut globals = GLOBALS.lock().unwrap();
+            if globals.state.is_disconnected() {
+                return;
+            }
+            let _ = globals
+                .state
+                .transition(StateEvent::Disconnected(Some(err.clone())));
+        }
+        send_message(Message::ConnectFailed(err));
     }
 
     extern "C" fn connection_event_did_occur(
@@ -224,28 +685,25 @@ pub fn define_central_manager_delegate() -> *const Class {
         event: ObjcId,
         peripheral: ObjcId,
     ) {
-        panic!("event?!")
+        info!("connection event {:?}", event);
     }
 
     extern "C" fn did_discover_services(this: &Object, _: Sel, peripheral: ObjcId, error: ObjcId) {
         unsafe {
+            if error != nil {
+                send_message(Message::Error(nserror_to_error(error)));
+                return;
+            }
             let services: ObjcId = msg_send![peripheral, services];
-
-            let string = str_to_nsstring("6e400002-b5a3-f393-e0a9-e50e24dcca9e");
-            let cbuuid: ObjcId = msg_send![class!(CBUUID), UUIDWithString: string];
-
-            let string = str_to_nsstring("6e400003-b5a3-f393-e0a9-e50e24dcca9e");
-            let cbuuid1: ObjcId = msg_send![class!(CBUUID), UUIDWithString: string];
-
             let count: usize = msg_send![services, count];
 
-            let arr = [cbuuid, cbuuid1, nil];
-            let chars: ObjcId = msg_send![class!(NSArray),
-                                      arrayWithObjects: arr.as_ptr()
-                                      count:2];
+            GLOBALS.lock().unwrap().pending_services = count;
+            if count == 0 {
+                services_discovered();
+            }
             for i in 0..count {
                 let service: ObjcId = msg_send![services, objectAtIndex: i];
-                let () = msg_send![peripheral, discoverCharacteristics:chars forService:service];
+                let () = msg_send![peripheral, discoverCharacteristics:nil forService:service];
             }
         }
     }
@@ -258,8 +716,14 @@ pub fn define_central_manager_delegate() -> *const Class {
         error: ObjcId,
     ) {
         unsafe {
+            if error != nil {
+                send_message(Message::Error(nserror_to_error(error)));
+                service_done();
+                return;
+            }
             let characteristics: ObjcId = msg_send![service, characteristics];
             let count: usize = msg_send![characteristics, count];
+            let discovery = GLOBALS.lock().unwrap().discovery;
 
             for i in 0..count {
                 let characteristic: ObjcId = msg_send![characteristics, objectAtIndex: i];
@@ -269,16 +733,17 @@ pub fn define_central_manager_delegate() -> *const Class {
                 let uuid = nsstring_to_string(uuid);
                 info!("{}", uuid);
 
-                let mut globals = GLOBALS.lock().unwrap();
-                if let Some(ref mut tx) = globals.tx {
-                    tx.send(Message::CharacteristicDiscovered(Characteristic {
-                        id: uuid.to_owned(),
-                        characteristic: msg_send![characteristic, retain],
-                        peripheral: msg_send![peripheral, retain],
-                    }))
-                    .unwrap();
-                }
+                let properties: usize = msg_send![characteristic, properties];
+
+                send_message(Message::CharacteristicDiscovered(Characteristic {
+                    id: uuid.to_owned(),
+                    characteristic: Retained::new(characteristic),
+                    peripheral: Retained::new(peripheral),
+                    properties: CharacteristicProperties::from_bits_truncate(properties as u16),
+                    discovery,
+                }));
             }
+            service_done();
         }
     }
 
@@ -290,15 +755,112 @@ pub fn define_central_manager_delegate() -> *const Class {
         error: ObjcId,
     ) {
         unsafe {
-            let value: ObjcId = msg_send![characteristic, value];
-            let length: usize = msg_send![value, length];
-            let bytes: *const u8 = msg_send![value, bytes];
-            let bytes = std::slice::from_raw_parts(bytes, length);
-            let mut globals = GLOBALS.lock().unwrap();
-            if let Some(ref mut tx) = globals.tx {
-                tx.send(Message::Data(bytes.to_vec())).unwrap();
+            let uuid: ObjcId = msg_send![characteristic, UUID];
+            let uuid: ObjcId = msg_send![uuid, UUIDString];
+            let uuid = nsstring_to_string(uuid);
+            if error != nil {
+                send_message(Message::Error(nserror_to_error(error)));
+            } else {
+                let enabled: BOOL = msg_send![characteristic, isNotifying];
+                let indication =
+                    GLOBALS
+                        .lock()
+                        .unwrap()
+                        .queue
+                        .in_flight()
+                        .is_some_and(|operation| {
+                            matches!(operation, Operation::Subscribe { indicate: true, .. })
+                        });
+                send_message(Message::SubscriptionChanged {
+                    characteristic: uuid,
+                    enabled: enabled == YES,
+                    indication,
+                });
             }
         }
+        complete_operation(|operation| {
+            matches!(operation, Operation::Subscribe { characteristic: c, .. }
+                     if c.characteristic.0 == characteristic)
+        });
+    }
+
+    extern "C" fn did_update_value_for_characteristic(
+        this: &Object,
+        _: Sel,
+        peripheral: ObjcId,
+        characteristic: ObjcId,
+        error: ObjcId,
+    ) {
+        unsafe {
+            if error != nil {
+                send_message(Message::Error(nserror_to_error(error)));
+            } else {
+                let value: ObjcId = msg_send![characteristic, value];
+                let length: usize = msg_send![value, length];
+                let bytes: *const u8 = msg_send![value, bytes];
+                let bytes = std::slice::from_raw_parts(bytes, length);
+                send_message(Message::Data(bytes.to_vec()));
+            }
+        }
+        // notifications arrive the same way, only a read of this characteristic is done
+        complete_operation(
+            |operation| matches!(operation, Operation::Read(c) if c.characteristic.0 == characteristic),
+        );
+    }
+
+    extern "C" fn did_write_value_for_characteristic(
+        _: &Object,
+        _: Sel,
+        _peripheral: ObjcId,
+        characteristic: ObjcId,
+        error: ObjcId,
+    ) {
+        if error != nil {
+            send_message(Message::Error(unsafe { nserror_to_error(error) }));
+        }
+        complete_operation(|operation| {
+            matches!(operation, Operation::Write(c, _, WriteType::WithResponse)
+                     if c.characteristic.0 == characteristic)
+        });
+    }
+
+    // the peripheral sent a Service Changed indication
+    extern "C" fn did_modify_services(
+        _: &Object,
+        _: Sel,
+        _peripheral: ObjcId,
+        _invalidated_services: ObjcId,
+    ) {
+        let mut globals = GLOBALS.lock().unwrap();
+        if globals.state.is_active() {
+            unsafe { rediscover_services(&mut globals) };
+        }
+    }
+
+    extern "C" fn did_read_rssi(
+        _: &Object,
+        _: Sel,
+        _peripheral: ObjcId,
+        rssi: ObjcId,
+        error: ObjcId,
+    ) {
+        unsafe {
+            if error != nil {
+                send_message(Message::Error(nserror_to_error(error)));
+            } else {
+                let rssi: i16 = msg_send![rssi, shortValue];
+                send_message(Message::Rssi(rssi));
+            }
+        }
+        complete_operation(|operation| matches!(operation, Operation::ReadRssi));
+    }
+
+    extern "C" fn peripheral_is_ready_to_send_write_without_response(
+        _: &Object,
+        _: Sel,
+        _peripheral: ObjcId,
+    ) {
+        unsafe { pump_queue(&mut GLOBALS.lock().unwrap()) };
     }
 
     unsafe {
@@ -343,116 +905,958 @@ pub fn define_central_manager_delegate() -> *const Class {
         );
         decl.add_method(
             sel!(peripheral:didUpdateValueForCharacteristic:error:),
-            did_update_notification_state_for_characteristic
+            did_update_value_for_characteristic
+                as extern "C" fn(&Object, Sel, ObjcId, ObjcId, ObjcId),
+        );
+        decl.add_method(
+            sel!(peripheral:didWriteValueForCharacteristic:error:),
+            did_write_value_for_characteristic
                 as extern "C" fn(&Object, Sel, ObjcId, ObjcId, ObjcId),
         );
+        decl.add_method(
+            sel!(peripheral:didModifyServices:),
+            did_modify_services as extern "C" fn(&Object, Sel, ObjcId, ObjcId),
+        );
+        decl.add_method(
+            sel!(peripheral:didReadRSSI:error:),
+            did_read_rssi as extern "C" fn(&Object, Sel, ObjcId, ObjcId, ObjcId),
+        );
+        decl.add_method(
+            sel!(peripheralIsReadyToSendWriteWithoutResponse:),
+            peripheral_is_ready_to_send_write_without_response
+                as extern "C" fn(&Object, Sel, ObjcId),
+        );
     }
 
     return decl.register();
 }
 
-mod dispatch {
-    #[repr(C)]
-    pub struct dispatch_object_s {
-        _private: [u8; 0],
-    }
-    pub type dispatch_queue_t = *mut dispatch_object_s;
+const CB_PROPERTY_READ: usize = 0x02;
+const CB_PROPERTY_WRITE_WITHOUT_RESPONSE: usize = 0x04;
+const CB_PROPERTY_WRITE: usize = 0x08;
+const CB_PROPERTY_NOTIFY: usize = 0x10;
+const CB_PROPERTY_INDICATE: usize = 0x20;
+
+const CB_PERMISSION_READABLE: usize = 0x01;
+const CB_PERMISSION_WRITEABLE: usize = 0x02;
+const CB_PERMISSION_READ_ENCRYPTION_REQUIRED: usize = 0x04;
+const CB_PERMISSION_WRITE_ENCRYPTION_REQUIRED: usize = 0x08;
+
+const USER_DESCRIPTION_UUID: &str = "2901";
+
+// CoreBluetooth has no separate MITM level, encryption implies pairing.
+fn permissions_to_cb(permissions: Permissions) -> usize {
+    let read = match permissions.read {
+        Access::None => 0,
+        Access::Open => CB_PERMISSION_READABLE,
+        Access::Encrypted | Access::EncryptedMitm => CB_PERMISSION_READ_ENCRYPTION_REQUIRED,
+    };
+    let write = match permissions.write {
+        Access::None => 0,
+        Access::Open => CB_PERMISSION_WRITEABLE,
+        Access::Encrypted | Access::EncryptedMitm => CB_PERMISSION_WRITE_ENCRYPTION_REQUIRED,
+    };
+    read | write
+}
 
-    #[cfg_attr(
-        any(target_os = "macos", target_os = "ios"),
-        link(name = "System", kind = "dylib")
-    )]
-    #[cfg_attr(
-        not(any(target_os = "macos", target_os = "ios")),
-        link(name = "dispatch", kind = "dylib")
-    )]
-    extern "C" {
-        static _dispatch_main_q: dispatch_object_s;
+unsafe fn cbuuid(uuid: &str) -> ObjcId {
+    msg_send![class!(CBUUID), UUIDWithString: str_to_nsstring(uuid)]
+}
+
+unsafe fn cbuuid_to_string(uuid: ObjcId) -> String {
+    let uuid: ObjcId = msg_send![uuid, UUIDString];
+    nsstring_to_string(uuid)
+}
+
+unsafe fn nsdata_to_vec(data: ObjcId) -> Vec<u8> {
+    if data == nil {
+        return vec![];
     }
-    pub fn dispatch_get_main_queue() -> dispatch_queue_t {
-        unsafe { &_dispatch_main_q as *const _ as dispatch_queue_t }
+    let length: usize = msg_send![data, length];
+    let bytes: *const u8 = msg_send![data, bytes];
+    if length == 0 {
+        return vec![];
     }
+    std::slice::from_raw_parts(bytes, length).to_vec()
 }
 
-impl Adapter {
-    pub fn new() -> Result<Adapter, BluetoothError> {
-        unsafe {
-            let delegate_class: ObjcId = msg_send!(define_central_manager_delegate(), class);
-            let delegate: ObjcId = msg_send!(delegate_class, new);
+fn server_state() -> Option<Arc<Mutex<ServerState>>> {
+    GLOBALS.lock().unwrap().server.clone()
+}
 
-            let blue_central: ObjcId = msg_send![class!(CBCentralManager), alloc];
-            //let queue = dispatch::dispatch_get_main_queue();
-            let blue_central: ObjcId = msg_send![blue_central, initWithDelegate:delegate
-                                                 queue:nil];
+/// Turn the local database into CBMutableServices, only allowed once the manager is powered on.
+unsafe fn add_services(manager: ObjcId) {
+    let services = match server_state() {
+        Some(state) => state.lock().unwrap().services.clone(),
+        None => return,
+    };
 
-            Ok(Adapter { blue_central })
-        }
+    let mut globals = GLOBALS.lock().unwrap();
+    if !globals.server_characteristics.is_empty() {
+        return;
     }
 
-    pub fn is_ready(&self) -> bool {
-        true
+    for service in &services {
+        let native: ObjcId = msg_send![class!(CBMutableService), alloc];
+        let native: ObjcId = msg_send![native,
+                                       initWithType:cbuuid(&service.uuid)
+                                       primary:if service.primary { YES } else { NO }];
+
+        let characteristics: ObjcId = msg_send![class!(NSMutableArray), array];
+        for characteristic in &service.characteristics {
+            let mut properties = 0;
+            if characteristic.read {
+                properties |= CB_PROPERTY_READ;
+            }
+            if characteristic.write {
+                properties |= CB_PROPERTY_WRITE;
+            }
+            if characteristic.write_without_response {
+                properties |= CB_PROPERTY_WRITE_WITHOUT_RESPONSE;
+            }
+            if characteristic.notify {
+                properties |= CB_PROPERTY_NOTIFY;
+            }
+            if characteristic.indicate {
+                properties |= CB_PROPERTY_INDICATE;
+            }
+
+            // value is nil to get read requests instead of a value cached by the OS
+            let native_characteristic: ObjcId = msg_send![class!(CBMutableCharacteristic), alloc];
+            let native_characteristic: ObjcId = msg_send![native_characteristic,
+                                                          initWithType:cbuuid(&characteristic.uuid)
+                                                          properties:properties
+                                                          value:nil
+                                                          permissions:permissions_to_cb(characteristic.permissions)];
+
+            let descriptors: ObjcId = msg_send![class!(NSMutableArray), array];
+            for descriptor in &characteristic.descriptors {
+                // CoreBluetooth only allows publishing the user description
+                if !peripheral::uuid_eq(&descriptor.uuid, USER_DESCRIPTION_UUID) {
+                    miniquad::warn!("descriptor {} is not supported on iOS", descriptor.uuid);
+                    continue;
+                }
+                let value = String::from_utf8_lossy(&descriptor.value);
+                let native_descriptor: ObjcId = msg_send![class!(CBMutableDescriptor), alloc];
+                let native_descriptor: ObjcId = msg_send![native_descriptor,
+                                                          initWithType:cbuuid(&descriptor.uuid)
+                                                          value:str_to_nsstring(&value)];
+                let () = msg_send![descriptors, addObject: native_descriptor];
+            }
+            let () = msg_send![native_characteristic, setDescriptors: descriptors];
+
+            let () = msg_send![characteristics, addObject: native_characteristic];
+            globals.server_characteristics.push((
+                service.uuid.clone(),
+                characteristic.uuid.clone(),
+                native_characteristic,
+            ));
+        }
+        let () = msg_send![native, setCharacteristics: characteristics];
+        let () = msg_send![manager, addService: native];
     }
+}
 
-    pub fn start_scan(&mut self) -> Result<(), BluetoothError> {
-        miniquad::warn!("start_scan");
-        // unsafe {
-        //     let _: () = msg_send![self.blue_central,
-        //                           scanForPeripheralsWithServices:nil
-        //                           options:nil];
-        // };
+unsafe fn advertisement_from_dictionary(dictionary: ObjcId) -> AdvertisementData {
+    let mut data = AdvertisementData::default();
 
-        Ok(())
+    let name: ObjcId = msg_send![dictionary, objectForKey: CBAdvertisementDataLocalNameKey];
+    if name != nil {
+        data.local_name = Some(nsstring_to_string(name));
     }
 
-    pub fn walk_devices<F: FnMut(&Device)>(&mut self, mut f: F) -> Result<(), BluetoothError> {
-        let mut globals = GLOBALS.lock().unwrap();
-        globals.devices.values_mut().for_each(|d| f(d));
+    let uuids: ObjcId = msg_send![dictionary, objectForKey: CBAdvertisementDataServiceUUIDsKey];
+    if uuids != nil {
+        let count: usize = msg_send![uuids, count];
+        for i in 0..count {
+            let uuid: ObjcId = msg_send![uuids, objectAtIndex: i];
+            data.service_uuids.push(cbuuid_to_string(uuid));
+        }
+    }
 
-        Ok(())
+    let manufacturer: ObjcId =
+        msg_send![dictionary, objectForKey: CBAdvertisementDataManufacturerDataKey];
+    let manufacturer = nsdata_to_vec(manufacturer);
+    if manufacturer.len() >= 2 {
+        let company = u16::from_le_bytes([manufacturer[0], manufacturer[1]]);
+        data.manufacturer_data
+            .push((company, manufacturer[2..].to_vec()));
     }
 
-    pub fn get_device_name(&self, device_id: &DeviceId) -> Option<String> {
-        let globals = GLOBALS.lock().unwrap();
+    let service_data: ObjcId =
+        msg_send![dictionary, objectForKey: CBAdvertisementDataServiceDataKey];
+    if service_data != nil {
+        let keys: ObjcId = msg_send![service_data, allKeys];
+        let count: usize = msg_send![keys, count];
+        for i in 0..count {
+            let uuid: ObjcId = msg_send![keys, objectAtIndex: i];
+            let value: ObjcId = msg_send![service_data, objectForKey: uuid];
+            data.service_data
+                .push((cbuuid_to_string(uuid), nsdata_to_vec(value)));
+        }
+    }
 
-        globals
-            .devices
-            .get(&device_id.0)
-            .and_then(|d| d.name.clone())
+    let tx_power: ObjcId = msg_send![dictionary, objectForKey: CBAdvertisementDataTxPowerLevelKey];
+    if tx_power != nil {
+        let tx_power: i32 = msg_send![tx_power, intValue];
+        data.tx_power_level = Some(tx_power as i8);
     }
 
-    pub fn connect(&mut self, device_id: DeviceId) -> Result<Connection, BluetoothError> {
-        let mut globals = GLOBALS.lock().unwrap();
+    data
+}
 
-        let (tx, client_rx) = mpsc::channel();
+/// CoreBluetooth only advertises a name and service UUIDs.
+unsafe fn start_advertising(manager: ObjcId, data: &AdvertisementData) {
+    let dictionary: ObjcId = msg_send![class!(NSMutableDictionary), dictionary];
 
-        globals.tx = Some(tx);
+    let uuids: ObjcId = msg_send![class!(NSMutableArray), array];
+    for uuid in &data.service_uuids {
+        let () = msg_send![uuids, addObject: cbuuid(uuid)];
+    }
+    let () = msg_send![dictionary, setObject:uuids forKey:CBAdvertisementDataServiceUUIDsKey];
 
-        let device = &globals.devices.get(&device_id.0).unwrap();
+    if let Some(ref name) = data.local_name {
+        let () = msg_send![dictionary,
+                           setObject:str_to_nsstring(name)
+                           forKey:CBAdvertisementDataLocalNameKey];
+    }
 
-        unsafe {
-            let () = msg_send![self.blue_central, stopScan];
-            let () = msg_send![self.blue_central,
-                               connectPeripheral:device.peripheral
-                               options:nil];
+    let () = msg_send![manager, startAdvertising: dictionary];
+}
+
+fn server_advertisement() -> AdvertisementData {
+    let mut data = AdvertisementData::default();
+    if let Some(state) = server_state() {
+        data.service_uuids = state
+            .lock()
+            .unwrap()
+            .services
+            .iter()
+            .filter(|s| s.primary)
+            .map(|s| s.uuid.clone())
+            .collect();
+    }
+    data
+}
+
+/// Both the GATT server and the broadcaster share one peripheral manager.
+unsafe fn peripheral_manager(globals: &mut GlobalData) -> ObjcId {
+    if globals.peripheral_manager == nil {
+        let delegate_class: ObjcId = msg_send!(define_peripheral_manager_delegate(), class);
+        let delegate: ObjcId = msg_send!(delegate_class, new);
+
+        let manager: ObjcId = msg_send![class!(CBPeripheralManager), alloc];
+        let manager: ObjcId = msg_send![manager, initWithDelegate:delegate
+                                        queue:nil];
+        globals.peripheral_manager = manager;
+    }
+    globals.peripheral_manager
+}
+
+fn send_message(message: Message) {
+    if let Some(ref tx) = GLOBALS.lock().unwrap().tx {
+        let _ = tx.send(message);
+    }
+}
+
+/// Characteristic discovery of one service finished.
+fn service_done() {
+    let done = {
+        let mut globals = GLOBALS.lock().unwrap();
+        globals.pending_services = globals.pending_services.saturating_sub(1);
+        globals.pending_services == 0
+    };
+    if done {
+        services_discovered();
+    }
+}
+
+/// Invalidate the characteristics of the connection and discover them again.
+/// CoreBluetooth would only rediscover the invalidated services, all of them
+/// are discovered to report every characteristic anew.
+unsafe fn rediscover_services(globals: &mut GlobalData) {
+    globals.discovery += 1;
+    if let Some(ref tx) = globals.tx {
+        let _ = tx.send(Message::ServicesChanged);
+        for _ in 0..globals.queue.drop_characteristic_operations() {
+            let _ = tx.send(Message::Error(BluetoothError::InvalidHandle));
+        }
+    }
+    let _ = globals.state.transition(StateEvent::DiscoveryStarted);
+    globals.deadline = Some(Deadline::new(globals.timeouts.discovery, true));
+
+    if let Some(ref peripheral) = globals.peripheral {
+        let () = msg_send![peripheral.0, discoverServices: nil];
+    }
+}
+
+fn services_discovered() {
+    let ready = GLOBALS
+        .lock()
+        .unwrap()
+        .state
+        .transition(StateEvent::ServicesDiscovered)
+        .is_ok();
+    if ready {
+        send_message(Message::ServicesDiscovered);
+    }
+}
+
+fn send_adapter_event(event: AdapterEvent) {
+    if let Some(ref tx) = GLOBALS.lock().unwrap().adapter_tx {
+        let _ = tx.send(event);
+    }
+}
+
+unsafe fn request_central(request: ObjcId) -> DeviceId {
+    let central: ObjcId = msg_send![request, central];
+    let identifier: ObjcId = msg_send![central, identifier];
+    let identifier: ObjcId = msg_send![identifier, UUIDString];
+    DeviceId(nsstring_to_string(identifier))
+}
+
+unsafe fn characteristic_ids(characteristic: ObjcId) -> (String, String) {
+    let service: ObjcId = msg_send![characteristic, service];
+    let service: ObjcId = msg_send![service, UUID];
+    let uuid: ObjcId = msg_send![characteristic, UUID];
+    (cbuuid_to_string(service), cbuuid_to_string(uuid))
+}
+
+/// The parts of a long write arrive together after the execute write,
+/// they are applied as one write like on the other backends.
+unsafe fn apply_write_requests(
+    state: &Mutex<ServerState>,
+    requests: ObjcId,
+    count: usize,
+) -> Result<(), AttError> {
+    let requests = (0..count)
+        .map(|i| {
+            let request: ObjcId = msg_send![requests, objectAtIndex: i];
+            let characteristic: ObjcId = msg_send![request, characteristic];
+            let (service, characteristic) = characteristic_ids(characteristic);
+            let value: ObjcId = msg_send![request, value];
+            let offset: usize = msg_send![request, offset];
+
+            WriteRequest {
+                central: request_central(request),
+                service,
+                characteristic,
+                value: nsdata_to_vec(value),
+                offset,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    match &requests[..] {
+        [] => Ok(()),
+        [request] => peripheral::handle_write(state, request),
+        [first, ..] => {
+            let prepared = requests
+                .iter()
+                .try_for_each(|request| peripheral::prepare_write(state, request));
+            // a rejected request cancels the whole batch
+            peripheral::execute_writes(state, &first.central, prepared.is_ok()).and(prepared)
+        }
+    }
+}
+
+/// `false` if the transmit queue is full, `peripheralManagerIsReadyToUpdateSubscribers:` follows.
+unsafe fn update_value(manager: ObjcId, characteristic: ObjcId, value: &[u8]) -> bool {
+    let data: ObjcId = msg_send![class!(NSData),
+                                 dataWithBytes:value.as_ptr()
+                                 length:value.len()];
+    let sent: BOOL = msg_send![manager,
+                               updateValue:data
+                               forCharacteristic:characteristic
+                               onSubscribedCentrals:nil];
+    sent == YES
+}
+
+pub fn define_peripheral_manager_delegate() -> *const Class {
+    if let Some(class) = Class::get("QuadBTPeripheralManager") {
+        return class;
+    }
+
+    let superclass = class!(NSObject);
+    let mut decl = ClassDecl::new("QuadBTPeripheralManager", superclass).unwrap();
+
+    decl.add_protocol(Protocol::get("CBPeripheralManagerDelegate").unwrap());
+
+    extern "C" fn peripheral_manager_did_update_state(_: &Object, _: Sel, manager: ObjcId) {
+        unsafe {
+            let state: ManagerState = msg_send![manager, state];
+            miniquad::warn!("peripheral manager: {:?}", state);
+
+            if state == ManagerState::PoweredOn {
+                add_services(manager);
+
+                let (advertise_requested, broadcast) = {
+                    let globals = GLOBALS.lock().unwrap();
+                    (globals.advertise_requested, globals.broadcast.clone())
+                };
+                if advertise_requested {
+                    start_advertising(manager, &server_advertisement());
+                } else if let Some(broadcast) = broadcast {
+                    start_advertising(manager, &broadcast);
+                }
+            }
+        }
+    }
+
+    extern "C" fn did_add_service(
+        _: &Object,
+        _: Sel,
+        _manager: ObjcId,
+        _service: ObjcId,
+        error: ObjcId,
+    ) {
+        if error != nil {
+            miniquad::error!("GATT service not added");
+        }
+    }
+
+    extern "C" fn did_start_advertising(_: &Object, _: Sel, _manager: ObjcId, error: ObjcId) {
+        let error = if error == nil {
+            None
+        } else {
+            let code: isize = unsafe { msg_send![error, code] };
+            Some(AdvertisingError::Internal(code as i32))
+        };
+
+        let (advertise_requested, broadcast) = {
+            let globals = GLOBALS.lock().unwrap();
+            (globals.advertise_requested, globals.broadcast.is_some())
         };
+        if advertise_requested {
+            if let Some(state) = server_state() {
+                state.lock().unwrap().send(match error {
+                    None => ServerEvent::AdvertisingStarted,
+                    Some(error) => ServerEvent::AdvertisingFailed(error),
+                });
+            }
+        }
+        if broadcast {
+            send_adapter_event(match error {
+                None => AdapterEvent::AdvertisingStarted,
+                Some(error) => AdapterEvent::AdvertisingFailed(error),
+            });
+        }
+    }
+
+    extern "C" fn did_receive_read_request(_: &Object, _: Sel, manager: ObjcId, request: ObjcId) {
+        unsafe {
+            let characteristic: ObjcId = msg_send![request, characteristic];
+            let (service, characteristic) = characteristic_ids(characteristic);
+            let offset: usize = msg_send![request, offset];
+
+            let request_data = ReadRequest {
+                central: request_central(request),
+                service,
+                characteristic,
+                offset,
+            };
+
+            let result = match server_state() {
+                Some(state) => peripheral::handle_read(&state, &request_data),
+                None => Err(AttError::UnlikelyError),
+            };
+            let code = match result {
+                Ok(value) => {
+                    let data: ObjcId = msg_send![class!(NSData),
+                                                 dataWithBytes:value.as_ptr()
+                                                 length:value.len()];
+                    let () = msg_send![request, setValue: data];
+                    0
+                }
+                Err(err) => err as isize,
+            };
+            let () = msg_send![manager, respondToRequest:request withResult:code];
+        }
+    }
+
+    extern "C" fn did_receive_write_requests(
+        _: &Object,
+        _: Sel,
+        manager: ObjcId,
+        requests: ObjcId,
+    ) {
+        unsafe {
+            let count: usize = msg_send![requests, count];
+            let result = match server_state() {
+                Some(state) => apply_write_requests(&state, requests, count),
+                None => Err(AttError::UnlikelyError),
+            };
+            let code = result.err().map_or(0, |err| err as isize);
+
+            // CoreBluetooth answers the whole batch with the first request
+            if count > 0 {
+                let first: ObjcId = msg_send![requests, objectAtIndex: 0];
+                let () = msg_send![manager, respondToRequest:first withResult:code];
+            }
+        }
+    }
+
+    extern "C" fn did_subscribe(
+        _: &Object,
+        _: Sel,
+        _manager: ObjcId,
+        central: ObjcId,
+        characteristic: ObjcId,
+    ) {
+        set_subscribed(central, characteristic, true);
+    }
+
+    extern "C" fn did_unsubscribe(
+        _: &Object,
+        _: Sel,
+        _manager: ObjcId,
+        central: ObjcId,
+        characteristic: ObjcId,
+    ) {
+        set_subscribed(central, characteristic, false);
+    }
+
+    fn set_subscribed(central: ObjcId, characteristic: ObjcId, subscribed: bool) {
+        unsafe {
+            let identifier: ObjcId = msg_send![central, identifier];
+            let identifier: ObjcId = msg_send![identifier, UUIDString];
+            let central = DeviceId(nsstring_to_string(identifier));
+            let (_, characteristic) = characteristic_ids(characteristic);
+
+            if let Some(state) = server_state() {
+                state
+                    .lock()
+                    .unwrap()
+                    .set_subscribed(&central, &characteristic, subscribed);
+            }
+        }
+    }
+
+    extern "C" fn ready_to_update_subscribers(_: &Object, _: Sel, manager: ObjcId) {
+        let mut globals = GLOBALS.lock().unwrap();
+        while let Some((characteristic, value)) = globals.pending_notifications.front() {
+            if !unsafe { update_value(manager, *characteristic, value) } {
+                return;
+            }
+            globals.pending_notifications.pop_front();
+        }
+    }
+
+    unsafe {
+        decl.add_method(
+            sel!(peripheralManagerDidUpdateState:),
+            peripheral_manager_did_update_state as extern "C" fn(&Object, Sel, ObjcId),
+        );
+        decl.add_method(
+            sel!(peripheralManager:didAddService:error:),
+            did_add_service as extern "C" fn(&Object, Sel, ObjcId, ObjcId, ObjcId),
+        );
+        decl.add_method(
+            sel!(peripheralManagerDidStartAdvertising:error:),
+            did_start_advertising as extern "C" fn(&Object, Sel, ObjcId, ObjcId),
+        );
+        decl.add_method(
+            sel!(peripheralManager:didReceiveReadRequest:),
+            did_receive_read_request as extern "C" fn(&Object, Sel, ObjcId, ObjcId),
+        );
+        decl.add_method(
+            sel!(peripheralManager:didReceiveWriteRequests:),
+            did_receive_write_requests as extern "C" fn(&Object, Sel, ObjcId, ObjcId),
+        );
+        decl.add_method(
+            sel!(peripheralManager:central:didSubscribeToCharacteristic:),
+            did_subscribe as extern "C" fn(&Object, Sel, ObjcId, ObjcId, ObjcId),
+        );
+        decl.add_method(
+            sel!(peripheralManager:central:didUnsubscribeFromCharacteristic:),
+            did_unsubscribe as extern "C" fn(&Object, Sel, ObjcId, ObjcId, ObjcId),
+        );
+        decl.add_method(
+            sel!(peripheralManagerIsReadyToUpdateSubscribers:),
+            ready_to_update_subscribers as extern "C" fn(&Object, Sel, ObjcId),
+        );
+    }
+
+    return decl.register();
+}
+
+mod dispatch {
+    #[repr(C)]
+    pub struct dispatch_object_s {
+        _private: [u8; 0],
+    }
+    pub type dispatch_queue_t = *mut dispatch_object_s;
+
+    #[cfg_attr(
+        any(target_os = "macos", target_os = "ios"),
+        link(name = "System", kind = "dylib")
+    )]
+    #[cfg_attr(
+        not(any(target_os = "macos", target_os = "ios")),
+        link(name = "dispatch", kind = "dylib")
+    )]
+    extern "C" {
+        static _dispatch_main_q: dispatch_object_s;
+    }
+    pub fn dispatch_get_main_queue() -> dispatch_queue_t {
+        unsafe { &_dispatch_main_q as *const _ as dispatch_queue_t }
+    }
+}
+
+impl Adapter {
+    pub fn new() -> Result<Adapter, BluetoothError> {
+        unsafe {
+            let delegate_class: ObjcId = msg_send!(define_central_manager_delegate(), class);
+            let delegate: ObjcId = msg_send!(delegate_class, new);
+
+            let blue_central: ObjcId = msg_send![class!(CBCentralManager), alloc];
+            //let queue = dispatch::dispatch_get_main_queue();
+            let blue_central: ObjcId = msg_send![blue_central, initWithDelegate:delegate
+                                                 queue:nil];
+
+            let (tx, rx) = mpsc::channel();
+            let mut globals = GLOBALS.lock().unwrap();
+            globals.adapter_tx = Some(tx);
+            globals.central = blue_central;
+
+            Ok(Adapter { blue_central, rx })
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<Option<AdapterEvent>, BluetoothError> {
+        // CoreBluetooth has no advertising timeout, emulate it
+        let expired = GLOBALS
+            .lock()
+            .unwrap()
+            .broadcast_expires
+            .map_or(false, |expires| expires <= Instant::now());
+        if expired {
+            self.stop_advertising()?;
+        }
+
+        Ok(self.rx.try_recv().ok())
+    }
+
+    /// Bluetooth availability, changes arrive as `AdapterEvent::StateChanged`.
+    pub fn state(&self) -> AdapterState {
+        let state: ManagerState = unsafe { msg_send![self.blue_central, state] };
+        state.into()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.state() == AdapterState::PoweredOn
+    }
+
+    pub fn permission_status(&self) -> PermissionStatus {
+        permission_status()
+    }
+
+    /// Shows the system alert pointing to the Bluetooth settings. iOS does not
+    /// report the answer, `AdapterEvent::EnableResult(true)` follows once
+    /// Bluetooth is on, nothing if the alert is dismissed.
+    pub fn request_enable(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        match self.state() {
+            AdapterState::PoweredOn => {
+                if let Some(ref tx) = globals.adapter_tx {
+                    let _ = tx.send(AdapterEvent::EnableResult(true));
+                }
+                return Ok(());
+            }
+            AdapterState::PoweredOff => {}
+            state => state.require_powered_on()?,
+        }
+
+        unsafe {
+            // the alert shows when a manager is created while Bluetooth is off
+            let number: ObjcId = msg_send![class!(NSNumber), numberWithBool: YES];
+            let options: ObjcId = msg_send![class!(NSDictionary),
+                                            dictionaryWithObject: number
+                                            forKey: CBCentralManagerOptionShowPowerAlertKey];
+            let manager: ObjcId = msg_send![class!(CBCentralManager), alloc];
+            let manager: ObjcId = msg_send![manager, initWithDelegate:nil
+                                            queue:nil
+                                            options:options];
+            globals.power_alert = Some(Retained::new(manager));
+            let () = msg_send![manager, release];
+        }
+        globals.enable_requested = true;
+        Ok(())
+    }
+
+    /// Reports the answer as `AdapterEvent::PermissionsResult`. iOS already
+    /// prompts when the `Adapter` is created, this waits for that answer.
+    pub fn request_permissions(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        if globals.permissions_requested {
+            return Err(BluetoothError::OperationInProgress);
+        }
+        match permission_status() {
+            PermissionStatus::NotDetermined => globals.permissions_requested = true,
+            status => {
+                if let Some(ref tx) = globals.adapter_tx {
+                    let _ = tx.send(AdapterEvent::PermissionsResult(status));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn start_scan(&mut self) -> Result<(), BluetoothError> {
+        miniquad::warn!("start_scan");
+        // unsafe {
+        //     let _: () = msg_send![self.blue_central,
+        //                           scanForPeripheralsWithServices:nil
+        //                           options:nil];
+        // };
+
+        Ok(())
+    }
+
+    pub fn walk_devices<F: FnMut(&Device)>(&mut self, mut f: F) -> Result<(), BluetoothError> {
+        // `f` may call into the adapter, e.g. `Device::bond`
+        let devices: Vec<Device> = GLOBALS.lock().unwrap().devices.values().cloned().collect();
+        devices.iter().for_each(|d| f(d));
+
+        Ok(())
+    }
+
+    /// Peripherals connected to the system, e.g. by another app, with any of
+    /// the given services. iOS finds none without service UUIDs.
+    pub fn connected_devices(&self, services: &[&str]) -> Result<Vec<DeviceId>, BluetoothError> {
+        self.state().require_powered_on()?;
+
+        let mut globals = GLOBALS.lock().unwrap();
+        unsafe {
+            let uuids: ObjcId = msg_send![class!(NSMutableArray), array];
+            for service in services {
+                let () = msg_send![uuids, addObject: cbuuid(service)];
+            }
+            let peripherals: ObjcId = msg_send![self.blue_central,
+                                                retrieveConnectedPeripheralsWithServices: uuids];
+            let count: usize = msg_send![peripherals, count];
+            Ok((0..count)
+                .map(|i| {
+                    let peripheral: ObjcId = msg_send![peripherals, objectAtIndex: i];
+                    DeviceId(add_peripheral(&mut globals, peripheral))
+                })
+                .collect())
+        }
+    }
+
+    /// Bonds are not visible to apps on iOS.
+    pub fn bonded_devices(&self) -> Result<Vec<DeviceId>, BluetoothError> {
+        Err(BluetoothError::NotSupported)
+    }
+
+    /// Only the user can forget a device, in the Bluetooth settings.
+    pub fn remove_bond(&mut self, _device_id: &DeviceId) -> Result<(), BluetoothError> {
+        Err(BluetoothError::NotSupported)
+    }
+
+    pub fn get_device_name(&self, device_id: &DeviceId) -> Option<String> {
+        let globals = GLOBALS.lock().unwrap();
+
+        globals
+            .devices
+            .get(&device_id.0)
+            .and_then(|d| d.name.clone())
+    }
+
+    /// Timeouts for the following connects and the operations of the connection.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        GLOBALS.lock().unwrap().timeouts = timeouts;
+    }
+
+    pub fn connect(&mut self, device_id: DeviceId) -> Result<Connection, BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        unsafe { known_device(&mut globals, &device_id.0) };
+        let peripheral = globals
+            .devices
+            .get(&device_id.0)
+            .ok_or(BluetoothError::DeviceUnavailable)?
+            .peripheral
+            .clone();
+
+        unsafe {
+            let () = msg_send![self.blue_central, stopScan];
+            connect_device(&mut globals, &device_id)?;
+        }
+
+        let (tx, client_rx) = mpsc::channel();
+        globals.tx = Some(tx);
+        globals.generation += 1;
+        let generation = globals.generation;
 
         Ok(Connection {
             device_id,
+            peripheral,
             rx: client_rx,
+            reconnect: None,
+            rssi_monitor: None,
+            generation,
         })
     }
+
+    /// Start a local GATT server. Only one server may be open at a time.
+    /// Services are published once the peripheral manager is powered on.
+    pub fn open_gatt_server(
+        &mut self,
+        services: Vec<LocalService>,
+    ) -> Result<GattServer, BluetoothError> {
+        let (tx, rx) = mpsc::channel();
+        let state = ServerState::new(services, tx);
+
+        let mut globals = GLOBALS.lock().unwrap();
+        globals.server = Some(state.clone());
+        globals.server_characteristics.clear();
+        globals.pending_notifications.clear();
+        globals.advertise_requested = false;
+
+        let manager = unsafe { peripheral_manager(&mut globals) };
+        drop(globals);
+
+        // a reused manager will not report the power state again
+        unsafe {
+            let state: ManagerState = msg_send![manager, state];
+            if state == ManagerState::PoweredOn {
+                add_services(manager);
+            }
+        }
+
+        Ok(GattServer { manager, state, rx })
+    }
+
+    /// Broadcast an advertisement, independent of any GATT server.
+    /// The outcome arrives as an `AdapterEvent`.
+    /// CoreBluetooth can only advertise a name and service UUIDs, and shares
+    /// the advertisement with the GATT server.
+    pub fn start_advertising(
+        &mut self,
+        data: AdvertisementData,
+        settings: AdvertisingSettings,
+    ) -> Result<(), BluetoothError> {
+        if !data.manufacturer_data.is_empty()
+            || !data.service_data.is_empty()
+            || data.tx_power_level.is_some()
+        {
+            send_adapter_event(AdapterEvent::AdvertisingFailed(
+                AdvertisingError::FeatureUnsupported,
+            ));
+            return Ok(());
+        }
+
+        let mut globals = GLOBALS.lock().unwrap();
+        if globals.broadcast.is_some() {
+            drop(globals);
+            send_adapter_event(AdapterEvent::AdvertisingFailed(
+                AdvertisingError::AlreadyStarted,
+            ));
+            return Ok(());
+        }
+
+        globals.broadcast = Some(data.clone());
+        globals.broadcast_expires = settings.timeout.map(|timeout| Instant::now() + timeout);
+        let manager = unsafe { peripheral_manager(&mut globals) };
+        drop(globals);
+
+        unsafe {
+            let state: ManagerState = msg_send![manager, state];
+            if state == ManagerState::PoweredOn {
+                start_advertising(manager, &data);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn stop_advertising(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+
+        globals.broadcast_expires = None;
+        if globals.broadcast.take().is_some() {
+            unsafe {
+                let () = msg_send![globals.peripheral_manager, stopAdvertising];
+            }
+            drop(globals);
+            send_adapter_event(AdapterEvent::AdvertisingStopped);
+        }
+
+        Ok(())
+    }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Message {
     Connected,
-    Disconnected,
+    /// Connecting failed, the connection is unusable.
+    ConnectFailed(BluetoothError),
+    /// `None` after `Connection::disconnect`, the reason otherwise.
+    Disconnected(Option<BluetoothError>),
+    /// A queued operation failed.
+    Error(BluetoothError),
+    /// All characteristics were reported, the connection is ready for GATT operations.
+    ServicesDiscovered,
+    /// The peripheral changed its services, or `Connection::refresh_services` was
+    /// called. Every `Characteristic` received so far is invalid and operations on
+    /// it fail with `BluetoothError::InvalidHandle`, queued ones included.
+    /// Discovery runs again and reports the characteristics anew.
+    ServicesChanged,
     Data(Vec<u8>),
     CharacteristicDiscovered(Characteristic),
+    /// ATT_MTU negotiated, in response to `Connection::request_mtu`.
+    MtuChanged(usize),
+    /// Signal strength of the connection in dBm, in response to `Connection::read_rssi`.
+    Rssi(i16),
+    /// Outcome of `Connection::execute_reliable_write`.
+    ReliableWriteCompleted(bool),
+    /// The peripheral confirmed the change of its client configuration,
+    /// after `set_notification` or `set_indication`.
+    SubscriptionChanged {
+        characteristic: String,
+        enabled: bool,
+        /// Set by `set_indication`, clear by `set_notification`.
+        indication: bool,
+    },
+    /// The link was lost, another attempt follows after the backoff.
+    /// Only with a `ReconnectPolicy`, instead of `Disconnected`.
+    Reconnecting {
+        attempt: u32,
+        reason: BluetoothError,
+    },
+    /// The link is back and the subscriptions are restored.
+    Reconnected,
+    /// The link layer agreed on new connection parameters,
+    /// e.g. after `Connection::request_connection_priority`.
+    ConnectionParametersChanged {
+        interval: Duration,
+        /// Connection events the peripheral may skip.
+        latency: u16,
+        supervision_timeout: Duration,
+    },
+    /// PHYs in use, after `Connection::set_preferred_phy`.
+    PhyChanged {
+        tx: Phy,
+        rx: Phy,
+    },
+}
+
+// CBCharacteristicWriteType
+unsafe fn max_write_len(peripheral: ObjcId, write_type: WriteType) -> usize {
+    let write_type: isize = match write_type {
+        WriteType::WithResponse => 0,
+        WriteType::WithoutResponse => 1,
+    };
+    msg_send![peripheral, maximumWriteValueLengthForType: write_type]
 }
 
 pub struct Connection {
     device_id: DeviceId,
+    peripheral: Retained,
     rx: Receiver<Message>,
+    reconnect: Option<Box<Reconnector>>,
+    rssi_monitor: Option<RssiMonitor>,
+    generation: u64,
 }
 
 impl Connection {
@@ -460,11 +1864,275 @@ impl Connection {
         self.device_id.clone()
     }
 
+    pub fn state(&self) -> ConnectionState {
+        GLOBALS.lock().unwrap().state.clone()
+    }
+
     pub fn try_recv(&mut self) -> Result<Option<Message>, BluetoothError> {
-        Ok(self.rx.try_recv().ok())
+        check_timeouts();
+        if let Some(ref mut monitor) = self.rssi_monitor {
+            monitor_rssi(monitor);
+        }
+
+        let message = match self.reconnect {
+            Some(ref mut reconnect) => reconnect.try_recv(&self.device_id, &self.rx),
+            None => Ok(self.rx.try_recv().ok()),
+        };
+        if let (Some(monitor), Ok(Some(Message::Rssi(rssi)))) = (&mut self.rssi_monitor, &message) {
+            monitor.record(*rssi);
+        }
+        message
+    }
+
+    /// Reconnect on its own when the link is lost, `None` to stop.
+    /// Notifications and indications enabled after this are restored on reconnect.
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        let connected = matches!(
+            self.state(),
+            ConnectionState::Connected
+                | ConnectionState::DiscoveringServices
+                | ConnectionState::Ready
+        );
+        self.reconnect = policy.map(|policy| Box::new(Reconnector::new(policy, connected)));
+    }
+
+    /// Discover the services again, in case the peripheral changed them without
+    /// telling. Reported as `Message::ServicesChanged`. CoreBluetooth has no way
+    /// to drop its cache, it only rediscovers.
+    pub fn refresh_services(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        globals.state.require_ready()?;
+        unsafe { rediscover_services(&mut globals) };
+        Ok(())
+    }
+
+    /// Read the signal strength of the connection, the result arrives as `Message::Rssi`.
+    pub fn read_rssi(&mut self) -> Result<(), BluetoothError> {
+        enqueue(Operation::ReadRssi)
+    }
+
+    /// Read the RSSI every `interval` while the connection is ready, `None` to stop.
+    /// Each reading arrives as `Message::Rssi`.
+    pub fn set_rssi_interval(&mut self, interval: Option<Duration>) {
+        self.rssi_monitor = interval.map(RssiMonitor::new);
+    }
+
+    /// Mean of the last `RSSI_WINDOW` readings since `set_rssi_interval`,
+    /// `None` without an interval or before the first reading.
+    pub fn average_rssi(&self) -> Option<i16> {
+        self.rssi_monitor.as_ref()?.average()
+    }
+
+    /// CoreBluetooth negotiates the largest MTU on its own,
+    /// this only reports the current one as `Message::MtuChanged`.
+    pub fn request_mtu(&mut self, _mtu: usize) -> Result<(), BluetoothError> {
+        let mtu = self.mtu();
+        if let Some(ref tx) = GLOBALS.lock().unwrap().tx {
+            let _ = tx.send(Message::MtuChanged(mtu));
+        }
+        Ok(())
+    }
+
+    /// CoreBluetooth picks the connection parameters on its own.
+    pub fn request_connection_priority(
+        &mut self,
+        _priority: ConnectionPriority,
+    ) -> Result<(), BluetoothError> {
+        Err(BluetoothError::NotSupported)
+    }
+
+    /// CoreBluetooth uses 2M and Coded PHY on its own where both sides support them.
+    pub fn set_preferred_phy(&mut self, _tx: Phy, _rx: Phy) -> Result<(), BluetoothError> {
+        Err(BluetoothError::NotSupported)
+    }
+
+    /// Current ATT_MTU.
+    pub fn mtu(&self) -> usize {
+        self.max_write_len(WriteType::WithoutResponse) + ATT_WRITE_HEADER_LEN
+    }
+
+    /// Longest value a single `send_bytes` of the given type accepts.
+    pub fn max_write_len(&self, write_type: WriteType) -> usize {
+        unsafe { max_write_len(self.peripheral.0, write_type) }
+    }
+
+    /// CoreBluetooth has no reliable writes.
+    pub fn begin_reliable_write(&mut self) -> Result<(), BluetoothError> {
+        Err(BluetoothError::NotSupported)
+    }
+
+    pub fn execute_reliable_write(&mut self) -> Result<(), BluetoothError> {
+        Err(BluetoothError::NotSupported)
+    }
+
+    pub fn abort_reliable_write(&mut self) -> Result<(), BluetoothError> {
+        Err(BluetoothError::NotSupported)
+    }
+
+    /// Operations queued on this connection.
+    pub fn queue_depth(&self) -> usize {
+        GLOBALS.lock().unwrap().queue.depth()
+    }
+
+    pub fn queue_limit(&self) -> usize {
+        GLOBALS.lock().unwrap().queue.limit
+    }
+
+    /// Operations beyond this many fail with `BluetoothError::QueueFull`.
+    pub fn set_queue_limit(&mut self, limit: usize) {
+        GLOBALS.lock().unwrap().queue.limit = limit;
     }
 
     pub fn disconnect(&mut self) -> Result<(), BluetoothError> {
+        let disconnected = self.state().is_disconnected();
+        if let Some(ref mut reconnect) = self.reconnect {
+            if disconnected {
+                reconnect.stop(Message::Disconnected(None));
+            }
+        }
+        let mut globals = GLOBALS.lock().unwrap();
+        if !globals.state.is_active() {
+            return Ok(());
+        }
+        globals.state.transition(StateEvent::Disconnect)?;
+        unsafe {
+            let () = msg_send![globals.central, cancelPeripheralConnection: self.peripheral.0];
+        }
+        Ok(())
+    }
+
+    /// Abort a pending connect, or a reconnect waiting for its next attempt.
+    /// `Message::ConnectFailed(BluetoothError::Cancelled)` follows.
+    pub fn cancel(&mut self) -> Result<(), BluetoothError> {
+        let disconnected = self.state().is_disconnected();
+        if let Some(ref mut reconnect) = self.reconnect {
+            if disconnected && reconnect.stop(Message::ConnectFailed(BluetoothError::Cancelled)) {
+                return Ok(());
+            }
+        }
+        let mut globals = GLOBALS.lock().unwrap();
+
+        if !globals.state.is_connecting() {
+            return Err(BluetoothError::InvalidState);
+        }
+        unsafe { abort(&mut globals, BluetoothError::Cancelled) };
         Ok(())
     }
 }
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut globals = GLOBALS.lock().unwrap();
+        if globals.generation != self.generation {
+            return;
+        }
+        if globals.state.is_active() {
+            unsafe {
+                let () = msg_send![globals.central, cancelPeripheralConnection: self.peripheral.0];
+            }
+        }
+        globals.queue.clear();
+        globals.peripheral = None;
+        let _ = globals.state.transition(StateEvent::Disconnected(None));
+        globals.tx = None;
+    }
+}
+
+pub struct GattServer {
+    manager: ObjcId,
+    state: Arc<Mutex<ServerState>>,
+    rx: Receiver<ServerEvent>,
+}
+
+impl GattServer {
+    pub fn set_read_handler<F>(&mut self, f: F)
+    where
+        F: FnMut(&ReadRequest) -> Result<Vec<u8>, AttError> + Send + 'static,
+    {
+        self.state.lock().unwrap().set_read_handler(f);
+    }
+
+    pub fn set_write_handler<F>(&mut self, f: F)
+    where
+        F: FnMut(&WriteRequest) -> Result<(), AttError> + Send + 'static,
+    {
+        self.state.lock().unwrap().set_write_handler(f);
+    }
+
+    /// Advertise the primary services of this server.
+    pub fn start_advertising(&mut self) -> Result<(), BluetoothError> {
+        GLOBALS.lock().unwrap().advertise_requested = true;
+
+        unsafe {
+            let state: ManagerState = msg_send![self.manager, state];
+            if state == ManagerState::PoweredOn {
+                start_advertising(self.manager, &server_advertisement());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stop_advertising(&mut self) -> Result<(), BluetoothError> {
+        GLOBALS.lock().unwrap().advertise_requested = false;
+
+        unsafe {
+            let () = msg_send![self.manager, stopAdvertising];
+        }
+        Ok(())
+    }
+
+    /// Send a new value to every central subscribed to the characteristic.
+    /// When the transmit queue is full the value is kept and sent, in order,
+    /// once CoreBluetooth has room again.
+    pub fn notify(&self, characteristic: &str, value: &[u8]) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        let native = globals
+            .server_characteristics
+            .iter()
+            .find(|(_, uuid, _)| peripheral::uuid_eq(uuid, characteristic))
+            .map(|(_, _, native)| *native)
+            .ok_or(BluetoothError::DeviceUnavailable)?;
+
+        if !globals.pending_notifications.is_empty()
+            || !unsafe { update_value(self.manager, native, value) }
+        {
+            globals
+                .pending_notifications
+                .push_back((native, value.to_vec()));
+        }
+        Ok(())
+    }
+
+    pub fn try_recv(&mut self) -> Result<Option<ServerEvent>, BluetoothError> {
+        Ok(self.rx.try_recv().ok())
+    }
+
+    /// Stop advertising and remove the published services, also done on drop.
+    pub fn close(&mut self) -> Result<(), BluetoothError> {
+        let mut globals = GLOBALS.lock().unwrap();
+        // replaced by a newer server, the published services are not ours
+        if !globals
+            .server
+            .as_ref()
+            .is_some_and(|server| Arc::ptr_eq(server, &self.state))
+        {
+            return Ok(());
+        }
+        unsafe {
+            let () = msg_send![self.manager, stopAdvertising];
+            let () = msg_send![self.manager, removeAllServices];
+        }
+        globals.server = None;
+        globals.server_characteristics.clear();
+        globals.pending_notifications.clear();
+        globals.advertise_requested = false;
+
+        Ok(())
+    }
+}
+
+impl Drop for GattServer {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
@@ -1,3 +1,36 @@
+pub mod advertising;
+pub mod codec;
+pub mod error;
+pub mod event;
+pub mod gatt;
+pub mod peripheral;
+pub mod permission;
+pub mod queue;
+pub mod reconnect;
+pub mod registry;
+pub mod rssi;
+pub mod state;
+pub mod timeout;
+pub mod uart;
+
+pub use advertising::{
+    AdvertisementData, AdvertisingError, AdvertisingMode, AdvertisingSettings, TxPower,
+};
+pub use codec::{Codec, FrameError, Framed};
+pub use error::BluetoothError;
+pub use event::{AdapterEvent, AdapterState, BondState};
+pub use gatt::{CharacteristicProperties, ConnectionPriority, Phy, WriteType};
+pub use peripheral::{
+    Access, AttError, LocalCharacteristic, LocalDescriptor, LocalService, Permissions, ReadRequest,
+    ServerEvent, WriteRequest,
+};
+pub use permission::PermissionStatus;
+pub use reconnect::ReconnectPolicy;
+pub use registry::{DeviceRegistry, KnownDevice};
+pub use state::ConnectionState;
+pub use timeout::Timeouts;
+pub use uart::NordicUart;
+
 #[cfg(target_os = "android")]
 pub mod android;
 
@@ -10,8 +43,8 @@ pub mod ios;
 #[cfg(any(target_os = "ios", target_os = "macos"))]
 pub use ios::*;
 
-// #[cfg(target_os = "macos")]
-// pub mod dummy;
+#[cfg(not(any(target_os = "android", target_os = "ios", target_os = "macos")))]
+pub mod dummy;
 
-// #[cfg(target_os = "macos")]
-// pub use dummy::*;
+#[cfg(not(any(target_os = "android", target_os = "ios", target_os = "macos")))]
+pub use dummy::*;
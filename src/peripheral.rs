@@ -0,0 +1,931 @@
+//! Local GATT database description for the peripheral (GATT server) role.
+//!
+//! The types here are platform independent, each backend turns them into
+//! native services and calls back into `ServerState` for requests.
+
+use crate::advertising::AdvertisingError;
+use crate::gatt::{CharacteristicProperties, MAX_ATTRIBUTE_LEN};
+use crate::DeviceId;
+
+use std::sync::{mpsc::Sender, Arc, Mutex};
+
+/// Client Characteristic Configuration Descriptor, written by centrals to subscribe.
+pub const CCCD_UUID: &str = "00002902-0000-1000-8000-00805f9b34fb";
+/// Prepare writes a central may queue before executing them.
+const MAX_PREPARED_WRITES: usize = 128;
+
+/// Security level required to access an attribute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Access {
+    /// Access is not allowed at all.
+    None,
+    /// No security required.
+    Open,
+    /// Link must be encrypted.
+    Encrypted,
+    /// Link must be encrypted with an authenticated (MITM protected) key.
+    EncryptedMitm,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Permissions {
+    pub read: Access,
+    pub write: Access,
+}
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions {
+        read: Access::None,
+        write: Access::None,
+    };
+    pub const READ: Permissions = Permissions {
+        read: Access::Open,
+        write: Access::None,
+    };
+    pub const WRITE: Permissions = Permissions {
+        read: Access::None,
+        write: Access::Open,
+    };
+    pub const READ_WRITE: Permissions = Permissions {
+        read: Access::Open,
+        write: Access::Open,
+    };
+}
+
+/// ATT protocol error codes a request handler may answer with.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AttError {
+    InvalidHandle = 0x01,
+    ReadNotPermitted = 0x02,
+    WriteNotPermitted = 0x03,
+    InsufficientAuthentication = 0x05,
+    RequestNotSupported = 0x06,
+    InvalidOffset = 0x07,
+    PrepareQueueFull = 0x09,
+    InvalidAttributeValueLength = 0x0d,
+    UnlikelyError = 0x0e,
+    InsufficientEncryption = 0x0f,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LocalDescriptor {
+    pub uuid: String,
+    pub permissions: Permissions,
+    pub value: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LocalCharacteristic {
+    pub uuid: String,
+    pub read: bool,
+    pub write: bool,
+    pub write_without_response: bool,
+    pub notify: bool,
+    pub indicate: bool,
+    pub permissions: Permissions,
+    /// Initial value, served to reads when there is no read handler.
+    pub value: Vec<u8>,
+    /// Extra descriptors. CCCD is added automatically for notify/indicate characteristics.
+    pub descriptors: Vec<LocalDescriptor>,
+}
+
+impl LocalCharacteristic {
+    pub fn new(uuid: &str) -> LocalCharacteristic {
+        LocalCharacteristic {
+            uuid: uuid.to_string(),
+            read: false,
+            write: false,
+            write_without_response: false,
+            notify: false,
+            indicate: false,
+            permissions: Permissions::NONE,
+            value: vec![],
+            descriptors: vec![],
+        }
+    }
+
+    /// Properties a central discovers this characteristic with.
+    pub fn properties(&self) -> CharacteristicProperties {
+        let mut properties = CharacteristicProperties::empty();
+        for (set, property) in [
+            (self.read, CharacteristicProperties::READ),
+            (self.write, CharacteristicProperties::WRITE),
+            (
+                self.write_without_response,
+                CharacteristicProperties::WRITE_WITHOUT_RESPONSE,
+            ),
+            (self.notify, CharacteristicProperties::NOTIFY),
+            (self.indicate, CharacteristicProperties::INDICATE),
+        ] {
+            if set {
+                properties |= property;
+            }
+        }
+        properties
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LocalService {
+    pub uuid: String,
+    pub primary: bool,
+    pub characteristics: Vec<LocalCharacteristic>,
+}
+
+impl LocalService {
+    pub fn new(uuid: &str, characteristics: Vec<LocalCharacteristic>) -> LocalService {
+        LocalService {
+            uuid: uuid.to_string(),
+            primary: true,
+            characteristics,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReadRequest {
+    pub central: DeviceId,
+    pub service: String,
+    pub characteristic: String,
+    pub offset: usize,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WriteRequest {
+    pub central: DeviceId,
+    pub service: String,
+    pub characteristic: String,
+    pub value: Vec<u8>,
+    pub offset: usize,
+}
+
+/// Called for every read of a local characteristic, the returned value is sent to the central.
+/// Shared, requests arriving on other threads wait for it instead of missing it.
+pub type ReadHandler = Arc<Mutex<dyn FnMut(&ReadRequest) -> Result<Vec<u8>, AttError> + Send>>;
+/// Called for every write of a local characteristic, on `Ok` the value is stored.
+pub type WriteHandler = Arc<Mutex<dyn FnMut(&WriteRequest) -> Result<(), AttError> + Send>>;
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ServerEvent {
+    AdvertisingStarted,
+    AdvertisingFailed(AdvertisingError),
+    CentralConnected(DeviceId),
+    CentralDisconnected(DeviceId),
+    Subscribed {
+        central: DeviceId,
+        characteristic: String,
+    },
+    Unsubscribed {
+        central: DeviceId,
+        characteristic: String,
+    },
+    /// A write request was accepted and the value stored.
+    Written {
+        central: DeviceId,
+        characteristic: String,
+        value: Vec<u8>,
+    },
+}
+
+/// Compare UUIDs ignoring case and the 16/32 bit short forms of the Bluetooth base UUID,
+/// CoreBluetooth reports standard UUIDs in short form.
+pub(crate) fn uuid_eq(a: &str, b: &str) -> bool {
+    fn expand(uuid: &str) -> String {
+        match uuid.len() {
+            4 => format!("0000{}-0000-1000-8000-00805f9b34fb", uuid),
+            8 => format!("{}-0000-1000-8000-00805f9b34fb", uuid),
+            _ => uuid.to_string(),
+        }
+    }
+    expand(a).eq_ignore_ascii_case(&expand(b))
+}
+
+/// Backend independent part of a running GATT server: stored values,
+/// request handlers and subscribed centrals.
+pub(crate) struct ServerState {
+    pub services: Vec<LocalService>,
+    pub read_handler: Option<ReadHandler>,
+    pub write_handler: Option<WriteHandler>,
+    pub centrals: Vec<DeviceId>,
+    pub subscriptions: Vec<(DeviceId, String)>,
+    /// Prepare writes of every central, applied by `execute_writes`.
+    pub prepared: Vec<WriteRequest>,
+    pub tx: Sender<ServerEvent>,
+}
+
+impl ServerState {
+    pub fn new(services: Vec<LocalService>, tx: Sender<ServerEvent>) -> Arc<Mutex<ServerState>> {
+        Arc::new(Mutex::new(ServerState {
+            services,
+            read_handler: None,
+            write_handler: None,
+            centrals: vec![],
+            subscriptions: vec![],
+            prepared: vec![],
+            tx,
+        }))
+    }
+
+    pub fn set_read_handler<F>(&mut self, f: F)
+    where
+        F: FnMut(&ReadRequest) -> Result<Vec<u8>, AttError> + Send + 'static,
+    {
+        self.read_handler = Some(Arc::new(Mutex::new(f)));
+    }
+
+    pub fn set_write_handler<F>(&mut self, f: F)
+    where
+        F: FnMut(&WriteRequest) -> Result<(), AttError> + Send + 'static,
+    {
+        self.write_handler = Some(Arc::new(Mutex::new(f)));
+    }
+
+    pub fn send(&self, event: ServerEvent) {
+        // Nobody polls a dropped server, losing the event is fine
+        let _ = self.tx.send(event);
+    }
+
+    pub fn characteristic(&self, service: &str, uuid: &str) -> Option<&LocalCharacteristic> {
+        self.services
+            .iter()
+            .filter(|s| uuid_eq(&s.uuid, service))
+            .flat_map(|s| s.characteristics.iter())
+            .find(|c| uuid_eq(&c.uuid, uuid))
+    }
+
+    fn characteristic_mut(
+        &mut self,
+        service: &str,
+        uuid: &str,
+    ) -> Option<&mut LocalCharacteristic> {
+        self.services
+            .iter_mut()
+            .filter(|s| uuid_eq(&s.uuid, service))
+            .flat_map(|s| s.characteristics.iter_mut())
+            .find(|c| uuid_eq(&c.uuid, uuid))
+    }
+
+    /// Some platforms do not report incoming connections, so the first request
+    /// from an unknown central counts as one.
+    pub fn touch_central(&mut self, central: &DeviceId) {
+        if !self.centrals.contains(central) {
+            self.centrals.push(central.clone());
+            self.send(ServerEvent::CentralConnected(central.clone()));
+        }
+    }
+
+    #[cfg(not(any(target_os = "ios", target_os = "macos")))]
+    pub fn central_connected(&mut self, central: &DeviceId) {
+        self.touch_central(central);
+    }
+
+    #[cfg(not(any(target_os = "ios", target_os = "macos")))]
+    pub fn central_disconnected(&mut self, central: &DeviceId) {
+        self.centrals.retain(|c| c != central);
+        self.subscriptions.retain(|(c, _)| c != central);
+        self.prepared.retain(|request| request.central != *central);
+        self.send(ServerEvent::CentralDisconnected(central.clone()));
+    }
+
+    pub fn set_subscribed(&mut self, central: &DeviceId, characteristic: &str, subscribed: bool) {
+        self.touch_central(central);
+
+        let index = self
+            .subscriptions
+            .iter()
+            .position(|(c, uuid)| c == central && uuid_eq(uuid, characteristic));
+
+        match (index, subscribed) {
+            (None, true) => {
+                self.subscriptions
+                    .push((central.clone(), characteristic.to_string()));
+                self.send(ServerEvent::Subscribed {
+                    central: central.clone(),
+                    characteristic: characteristic.to_string(),
+                });
+            }
+            (Some(index), false) => {
+                self.subscriptions.remove(index);
+                self.send(ServerEvent::Unsubscribed {
+                    central: central.clone(),
+                    characteristic: characteristic.to_string(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Centrals subscribed to the given characteristic.
+    #[cfg(not(any(target_os = "ios", target_os = "macos")))]
+    pub fn subscribers(&self, characteristic: &str) -> Vec<DeviceId> {
+        self.subscriptions
+            .iter()
+            .filter(|(_, uuid)| uuid_eq(uuid, characteristic))
+            .map(|(central, _)| central.clone())
+            .collect()
+    }
+}
+
+/// Answer a read request: permission check, then the user handler or the stored value.
+pub(crate) fn handle_read(
+    state: &Mutex<ServerState>,
+    request: &ReadRequest,
+) -> Result<Vec<u8>, AttError> {
+    let handler = {
+        let mut state = state.lock().unwrap();
+        state.touch_central(&request.central);
+
+        let characteristic = state
+            .characteristic(&request.service, &request.characteristic)
+            .ok_or(AttError::InvalidHandle)?;
+        if !characteristic.read || characteristic.permissions.read == Access::None {
+            return Err(AttError::ReadNotPermitted);
+        }
+        if request.offset > characteristic.value.len() {
+            return Err(AttError::InvalidOffset);
+        }
+        match state.read_handler {
+            Some(ref handler) => handler.clone(),
+            None => return Ok(characteristic.value[request.offset..].to_vec()),
+        }
+    };
+
+    // the handler is called without the server lock held, it is free to take its time
+    let mut handler = handler.lock().unwrap();
+    (*handler)(request)
+}
+
+/// Apply a write request: permission check, the user handler, then store the value.
+pub(crate) fn handle_write(
+    state: &Mutex<ServerState>,
+    request: &WriteRequest,
+) -> Result<(), AttError> {
+    let handler = check_write(&mut state.lock().unwrap(), request)?;
+    call_write_handler(handler, request)?;
+    store_write(&mut state.lock().unwrap(), request);
+    Ok(())
+}
+
+/// Permissions and offset of the request, the write handler to call if it is fine.
+fn check_write(
+    state: &mut ServerState,
+    request: &WriteRequest,
+) -> Result<Option<WriteHandler>, AttError> {
+    let characteristic = check_write_access(state, request)?;
+    if request.offset > characteristic.value.len() {
+        return Err(AttError::InvalidOffset);
+    }
+    Ok(state.write_handler.clone())
+}
+
+fn check_write_access<'a>(
+    state: &'a mut ServerState,
+    request: &WriteRequest,
+) -> Result<&'a LocalCharacteristic, AttError> {
+    state.touch_central(&request.central);
+
+    let characteristic = state
+        .characteristic(&request.service, &request.characteristic)
+        .ok_or(AttError::InvalidHandle)?;
+    if !(characteristic.write || characteristic.write_without_response)
+        || characteristic.permissions.write == Access::None
+    {
+        return Err(AttError::WriteNotPermitted);
+    }
+    Ok(characteristic)
+}
+
+// called without the server lock held, it is free to take its time
+fn call_write_handler(
+    handler: Option<WriteHandler>,
+    request: &WriteRequest,
+) -> Result<(), AttError> {
+    match handler {
+        Some(handler) => {
+            let mut handler = handler.lock().unwrap();
+            (*handler)(request)
+        }
+        None => Ok(()),
+    }
+}
+
+fn store_write(state: &mut ServerState, request: &WriteRequest) {
+    if let Some(characteristic) =
+        state.characteristic_mut(&request.service, &request.characteristic)
+    {
+        characteristic.value.truncate(request.offset);
+        characteristic.value.extend_from_slice(&request.value);
+    }
+    state.send(ServerEvent::Written {
+        central: request.central.clone(),
+        characteristic: request.characteristic.clone(),
+        value: request.value.clone(),
+    });
+}
+
+/// Queue a part of a long or reliable write, nothing is stored before `execute_writes`.
+/// The offset may not leave a gap after the stored value or the parts queued before.
+pub(crate) fn prepare_write(
+    state: &Mutex<ServerState>,
+    request: &WriteRequest,
+) -> Result<(), AttError> {
+    let mut state = state.lock().unwrap();
+    let stored = check_write_access(&mut state, request)?.value.len();
+
+    let mut queued = 0;
+    let mut len = stored;
+    for part in state
+        .prepared
+        .iter()
+        .filter(|part| part.central == request.central)
+    {
+        queued += 1;
+        if part.service == request.service && part.characteristic == request.characteristic {
+            len = len.max(part.offset + part.value.len());
+        }
+    }
+    if queued >= MAX_PREPARED_WRITES {
+        return Err(AttError::PrepareQueueFull);
+    }
+    if request.offset > len {
+        return Err(AttError::InvalidOffset);
+    }
+    state.prepared.push(request.clone());
+    Ok(())
+}
+
+/// Apply the prepare writes of the central, or drop them if `execute` is false.
+/// Consecutive parts of a value are joined, the write handler sees every
+/// value once, as a single write. All or nothing: if any value is rejected,
+/// none is stored.
+pub(crate) fn execute_writes(
+    state: &Mutex<ServerState>,
+    central: &DeviceId,
+    execute: bool,
+) -> Result<(), AttError> {
+    let prepared = {
+        let mut state = state.lock().unwrap();
+        let (prepared, others) = std::mem::take(&mut state.prepared)
+            .into_iter()
+            .partition(|request| request.central == *central);
+        state.prepared = others;
+        prepared
+    };
+    if !execute {
+        return Ok(());
+    }
+
+    let mut writes: Vec<WriteRequest> = vec![];
+    for request in prepared {
+        match writes.last_mut() {
+            Some(write)
+                if write.service == request.service
+                    && write.characteristic == request.characteristic
+                    && write.offset + write.value.len() == request.offset =>
+            {
+                write.value.extend_from_slice(&request.value);
+            }
+            _ => writes.push(request),
+        }
+    }
+
+    for write in &writes {
+        if write.offset + write.value.len() > MAX_ATTRIBUTE_LEN {
+            return Err(AttError::InvalidAttributeValueLength);
+        }
+        let handler = check_write(&mut state.lock().unwrap(), write)?;
+        call_write_handler(handler, write)?;
+    }
+    let mut state = state.lock().unwrap();
+    for write in &writes {
+        store_write(&mut state, write);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver};
+
+    fn server(
+        characteristic: LocalCharacteristic,
+    ) -> (Arc<Mutex<ServerState>>, Receiver<ServerEvent>) {
+        let (tx, rx) = channel();
+        let services = vec![LocalService::new("180f", vec![characteristic])];
+        (ServerState::new(services, tx), rx)
+    }
+
+    fn read(state: &Mutex<ServerState>, offset: usize) -> Result<Vec<u8>, AttError> {
+        let request = ReadRequest {
+            central: DeviceId::new("central"),
+            service: "180f".to_string(),
+            characteristic: "2a19".to_string(),
+            offset,
+        };
+        handle_read(state, &request)
+    }
+
+    fn write(state: &Mutex<ServerState>, value: &[u8], offset: usize) -> Result<(), AttError> {
+        let request = WriteRequest {
+            central: DeviceId::new("central"),
+            service: "180f".to_string(),
+            characteristic: "2a19".to_string(),
+            value: value.to_vec(),
+            offset,
+        };
+        handle_write(state, &request)
+    }
+
+    fn stored(state: &Mutex<ServerState>) -> Vec<u8> {
+        let state = state.lock().unwrap();
+        state.characteristic("180f", "2a19").unwrap().value.clone()
+    }
+
+    #[test]
+    fn read_stored_value() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.read = true;
+        characteristic.permissions = Permissions::READ;
+        characteristic.value = vec![1, 2, 3];
+        let (state, rx) = server(characteristic);
+
+        assert_eq!(read(&state, 0), Ok(vec![1, 2, 3]));
+        assert_eq!(read(&state, 2), Ok(vec![3]));
+        assert_eq!(read(&state, 3), Ok(vec![]));
+        assert_eq!(read(&state, 4), Err(AttError::InvalidOffset));
+
+        // the first request counts as the central connecting
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerEvent::CentralConnected(_))
+        ));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn read_handler() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.read = true;
+        characteristic.permissions = Permissions::READ;
+        let (state, _rx) = server(characteristic);
+
+        state
+            .lock()
+            .unwrap()
+            .set_read_handler(|request| match request.offset {
+                0 => Ok(vec![42]),
+                _ => Err(AttError::InvalidOffset),
+            });
+        assert_eq!(read(&state, 0), Ok(vec![42]));
+        // the handler stays in place for the next request
+        assert_eq!(read(&state, 0), Ok(vec![42]));
+    }
+
+    #[test]
+    fn read_not_permitted() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.read = true;
+        let (state, _rx) = server(characteristic);
+        assert_eq!(read(&state, 0), Err(AttError::ReadNotPermitted));
+
+        let request = ReadRequest {
+            central: DeviceId::new("central"),
+            service: "180f".to_string(),
+            characteristic: "2a1a".to_string(),
+            offset: 0,
+        };
+        assert_eq!(handle_read(&state, &request), Err(AttError::InvalidHandle));
+    }
+
+    #[test]
+    fn write_stores_value() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.write = true;
+        characteristic.permissions = Permissions::WRITE;
+        let (state, rx) = server(characteristic);
+
+        assert_eq!(write(&state, &[1, 2, 3], 0), Ok(()));
+        assert_eq!(write(&state, &[4, 5], 2), Ok(()));
+        assert_eq!(stored(&state), vec![1, 2, 4, 5]);
+        assert_eq!(write(&state, &[6], 5), Err(AttError::InvalidOffset));
+
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerEvent::CentralConnected(_))
+        ));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerEvent::Written { value, .. }) if value == [1, 2, 3]
+        ));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerEvent::Written { value, .. }) if value == [4, 5]
+        ));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn write_handler_rejects() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.write = true;
+        characteristic.permissions = Permissions::WRITE;
+        characteristic.value = vec![7];
+        let (state, rx) = server(characteristic);
+
+        state
+            .lock()
+            .unwrap()
+            .set_write_handler(|request| match request.value.len() {
+                1 => Ok(()),
+                _ => Err(AttError::InvalidAttributeValueLength),
+            });
+        assert_eq!(
+            write(&state, &[1, 2], 0),
+            Err(AttError::InvalidAttributeValueLength)
+        );
+        assert_eq!(stored(&state), vec![7]);
+        assert_eq!(write(&state, &[8], 0), Ok(()));
+        assert_eq!(stored(&state), vec![8]);
+
+        let written = rx
+            .try_iter()
+            .filter(|event| matches!(event, ServerEvent::Written { .. }))
+            .count();
+        assert_eq!(written, 1);
+    }
+
+    #[test]
+    fn write_during_handler_call() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.write = true;
+        characteristic.permissions = Permissions::WRITE;
+        let (state, _rx) = server(characteristic);
+
+        let (entered, wait) = channel();
+        let entered = Mutex::new(entered);
+        state
+            .lock()
+            .unwrap()
+            .set_write_handler(move |request| match request.value[0] {
+                0 => Err(AttError::WriteNotPermitted),
+                _ => {
+                    entered.lock().unwrap().send(()).unwrap();
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    Ok(())
+                }
+            });
+
+        let slow = {
+            let state = state.clone();
+            std::thread::spawn(move || write(&state, &[1], 0))
+        };
+        wait.recv().unwrap();
+        // a second request waits for the handler instead of skipping it
+        assert_eq!(write(&state, &[0], 0), Err(AttError::WriteNotPermitted));
+        assert_eq!(slow.join().unwrap(), Ok(()));
+        assert_eq!(stored(&state), vec![1]);
+    }
+
+    #[test]
+    fn write_not_permitted() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.read = true;
+        characteristic.permissions = Permissions::READ_WRITE;
+        let (state, _rx) = server(characteristic);
+        assert_eq!(write(&state, &[1], 0), Err(AttError::WriteNotPermitted));
+    }
+
+    fn prepare(state: &Mutex<ServerState>, central: &str, value: &[u8], offset: usize) {
+        let request = WriteRequest {
+            central: DeviceId::new(central),
+            service: "180f".to_string(),
+            characteristic: "2a19".to_string(),
+            value: value.to_vec(),
+            offset,
+        };
+        prepare_write(state, &request).unwrap();
+    }
+
+    #[test]
+    fn prepared_writes() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.write = true;
+        characteristic.permissions = Permissions::WRITE;
+        let (state, rx) = server(characteristic);
+        let writes = Arc::new(Mutex::new(vec![]));
+        let handled = writes.clone();
+        state.lock().unwrap().set_write_handler(move |request| {
+            handled.lock().unwrap().push(request.value.clone());
+            Ok(())
+        });
+
+        prepare(&state, "central", &[1, 2], 0);
+        prepare(&state, "other", &[9], 0);
+        prepare(&state, "central", &[3, 4], 2);
+        // nothing is applied before the execute write
+        assert!(stored(&state).is_empty());
+
+        let central = DeviceId::new("central");
+        assert_eq!(execute_writes(&state, &central, true), Ok(()));
+        assert_eq!(stored(&state), vec![1, 2, 3, 4]);
+        assert_eq!(*writes.lock().unwrap(), vec![vec![1, 2, 3, 4]]);
+        let written: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                ServerEvent::Written { value, .. } => Some(value),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(written, vec![vec![1, 2, 3, 4]]);
+
+        // the other central's writes are still queued
+        assert_eq!(state.lock().unwrap().prepared.len(), 1);
+        assert_eq!(execute_writes(&state, &central, true), Ok(()));
+        assert_eq!(writes.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn aborted_prepared_writes() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.write = true;
+        characteristic.permissions = Permissions::WRITE;
+        characteristic.value = vec![7];
+        let (state, rx) = server(characteristic);
+
+        prepare(&state, "central", &[1, 2], 0);
+        prepare(&state, "central", &[3], 2);
+        let central = DeviceId::new("central");
+        assert_eq!(execute_writes(&state, &central, false), Ok(()));
+        assert_eq!(stored(&state), vec![7]);
+        assert!(state.lock().unwrap().prepared.is_empty());
+        assert!(!rx
+            .try_iter()
+            .any(|event| matches!(event, ServerEvent::Written { .. })));
+    }
+
+    #[test]
+    fn prepared_writes_too_long() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.write = true;
+        characteristic.permissions = Permissions::WRITE;
+        let (state, _rx) = server(characteristic);
+
+        prepare(&state, "central", &[0; 300], 0);
+        prepare(&state, "central", &[0; 300], 300);
+        assert_eq!(
+            execute_writes(&state, &DeviceId::new("central"), true),
+            Err(AttError::InvalidAttributeValueLength)
+        );
+        assert!(stored(&state).is_empty());
+    }
+
+    #[test]
+    fn executed_writes_all_or_nothing() {
+        let mut service = LocalService::new("180f", vec![]);
+        for uuid in ["2a19", "2a1a"] {
+            let mut characteristic = LocalCharacteristic::new(uuid);
+            characteristic.write = true;
+            characteristic.permissions = Permissions::WRITE;
+            service.characteristics.push(characteristic);
+        }
+        let (tx, rx) = channel();
+        let state = ServerState::new(vec![service], tx);
+        state
+            .lock()
+            .unwrap()
+            .set_write_handler(|request| match request.characteristic.as_str() {
+                "2a1a" => Err(AttError::WriteNotPermitted),
+                _ => Ok(()),
+            });
+
+        prepare(&state, "central", &[1], 0);
+        let request = WriteRequest {
+            central: DeviceId::new("central"),
+            service: "180f".to_string(),
+            characteristic: "2a1a".to_string(),
+            value: vec![2],
+            offset: 0,
+        };
+        prepare_write(&state, &request).unwrap();
+        assert_eq!(
+            execute_writes(&state, &DeviceId::new("central"), true),
+            Err(AttError::WriteNotPermitted)
+        );
+        // the accepted first write is not applied either
+        assert!(stored(&state).is_empty());
+        assert!(!rx
+            .try_iter()
+            .any(|event| matches!(event, ServerEvent::Written { .. })));
+    }
+
+    #[test]
+    fn prepare_queue_limits() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.write = true;
+        characteristic.permissions = Permissions::WRITE;
+        characteristic.value = vec![0; 4];
+        let (state, _rx) = server(characteristic);
+        let request = |offset| WriteRequest {
+            central: DeviceId::new("central"),
+            service: "180f".to_string(),
+            characteristic: "2a19".to_string(),
+            value: vec![1],
+            offset,
+        };
+
+        // past the stored value and the queued parts
+        assert_eq!(
+            prepare_write(&state, &request(5)),
+            Err(AttError::InvalidOffset)
+        );
+        for offset in 4..4 + MAX_PREPARED_WRITES {
+            prepare_write(&state, &request(offset)).unwrap();
+        }
+        assert_eq!(
+            prepare_write(&state, &request(4 + MAX_PREPARED_WRITES)),
+            Err(AttError::PrepareQueueFull)
+        );
+        // the limit is per central
+        prepare(&state, "other", &[1], 0);
+    }
+
+    #[test]
+    fn prepare_write_not_permitted() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.read = true;
+        characteristic.permissions = Permissions::READ;
+        let (state, _rx) = server(characteristic);
+        let request = WriteRequest {
+            central: DeviceId::new("central"),
+            service: "180f".to_string(),
+            characteristic: "2a19".to_string(),
+            value: vec![1],
+            offset: 0,
+        };
+        assert_eq!(
+            prepare_write(&state, &request),
+            Err(AttError::WriteNotPermitted)
+        );
+        assert!(state.lock().unwrap().prepared.is_empty());
+    }
+
+    #[test]
+    fn subscriptions() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.notify = true;
+        let (state, rx) = server(characteristic);
+        let mut state = state.lock().unwrap();
+        let central = DeviceId::new("central");
+
+        state.set_subscribed(&central, "2a19", true);
+        // subscribing twice changes nothing
+        state.set_subscribed(&central, "00002A19-0000-1000-8000-00805F9B34FB", true);
+        assert_eq!(state.subscriptions.len(), 1);
+        state.set_subscribed(&central, "2a19", false);
+        state.set_subscribed(&central, "2a19", false);
+        assert!(state.subscriptions.is_empty());
+
+        let events: Vec<_> = rx.try_iter().collect();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                ServerEvent::CentralConnected(_),
+                ServerEvent::Subscribed { .. },
+                ServerEvent::Unsubscribed { .. },
+            ]
+        ));
+    }
+
+    #[cfg(not(any(target_os = "ios", target_os = "macos")))]
+    #[test]
+    fn disconnect_drops_subscriptions() {
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.notify = true;
+        let (state, _rx) = server(characteristic);
+        let mut state = state.lock().unwrap();
+        let (first, second) = (DeviceId::new("first"), DeviceId::new("second"));
+
+        state.central_connected(&first);
+        state.set_subscribed(&first, "2a19", true);
+        state.set_subscribed(&second, "2a19", true);
+        assert_eq!(
+            state.subscribers("2a19"),
+            vec![first.clone(), second.clone()]
+        );
+
+        state.central_disconnected(&first);
+        assert_eq!(state.subscribers("2a19"), vec![second.clone()]);
+        assert_eq!(state.centrals, vec![second]);
+    }
+}
@@ -0,0 +1,168 @@
+//! Runtime permission flow, see `Adapter::request_permissions`.
+//!
+//! The platform side sits behind `PermissionProvider`, the flow on top of it
+//! is the same for Android runtime permissions and the simulated backend.
+
+#[cfg(not(any(target_os = "ios", target_os = "macos")))]
+use crate::BluetoothError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PermissionStatus {
+    /// Not requested yet, `Adapter::request_permissions` shows the system prompt.
+    NotDetermined,
+    Granted,
+    /// Denied, requesting again shows the prompt again.
+    Denied,
+    /// Denied for good, e.g. "don't ask again": only the app settings can grant it.
+    PermanentlyDenied,
+}
+
+// iOS asks on its own when the central manager is created
+#[cfg(not(any(target_os = "ios", target_os = "macos")))]
+pub(crate) trait PermissionProvider {
+    /// All the permissions Bluetooth needs are granted.
+    fn granted(&self) -> bool;
+    /// The user denied before, but may still be asked again.
+    fn should_show_rationale(&self) -> bool;
+    /// Show the system prompt, the answer is reported to `PermissionFlow::on_result`.
+    fn request(&mut self) -> Result<(), BluetoothError>;
+}
+
+#[cfg(not(any(target_os = "ios", target_os = "macos")))]
+pub(crate) struct PermissionFlow<P> {
+    pub provider: P,
+    // the platform can not tell "never asked" from "denied for good",
+    // only whether this process asked
+    requested: bool,
+    pending: bool,
+}
+
+#[cfg(not(any(target_os = "ios", target_os = "macos")))]
+impl<P: PermissionProvider> PermissionFlow<P> {
+    pub fn new(provider: P) -> PermissionFlow<P> {
+        PermissionFlow {
+            provider,
+            requested: false,
+            pending: false,
+        }
+    }
+
+    pub fn status(&self) -> PermissionStatus {
+        if self.provider.granted() {
+            PermissionStatus::Granted
+        } else if !self.requested {
+            PermissionStatus::NotDetermined
+        } else if self.provider.should_show_rationale() {
+            PermissionStatus::Denied
+        } else {
+            PermissionStatus::PermanentlyDenied
+        }
+    }
+
+    /// Show the prompt if it can change anything. `Some` is the result right
+    /// away, `None` if it follows with `on_result`.
+    pub fn request(&mut self) -> Result<Option<PermissionStatus>, BluetoothError> {
+        if self.pending {
+            return Err(BluetoothError::OperationInProgress);
+        }
+        match self.status() {
+            status @ (PermissionStatus::Granted | PermissionStatus::PermanentlyDenied) => {
+                Ok(Some(status))
+            }
+            PermissionStatus::NotDetermined | PermissionStatus::Denied => {
+                self.provider.request()?;
+                self.requested = true;
+                self.pending = true;
+                Ok(None)
+            }
+        }
+    }
+
+    /// The user answered the prompt.
+    pub fn on_result(&mut self) -> PermissionStatus {
+        self.pending = false;
+        self.status()
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "ios", target_os = "macos"))))]
+mod tests {
+    use super::*;
+
+    /// Answers whatever the test sets before calling `on_result`.
+    #[derive(Default)]
+    struct FakeProvider {
+        granted: bool,
+        rationale: bool,
+        prompts: usize,
+    }
+
+    impl PermissionProvider for FakeProvider {
+        fn granted(&self) -> bool {
+            self.granted
+        }
+
+        fn should_show_rationale(&self) -> bool {
+            self.rationale
+        }
+
+        fn request(&mut self) -> Result<(), BluetoothError> {
+            self.prompts += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn granted() {
+        let mut flow = PermissionFlow::new(FakeProvider::default());
+        assert_eq!(flow.status(), PermissionStatus::NotDetermined);
+        assert_eq!(flow.request(), Ok(None));
+        flow.provider.granted = true;
+        assert_eq!(flow.on_result(), PermissionStatus::Granted);
+
+        // nothing to ask for any more
+        assert_eq!(flow.request(), Ok(Some(PermissionStatus::Granted)));
+        assert_eq!(flow.provider.prompts, 1);
+    }
+
+    #[test]
+    fn denied() {
+        let mut flow = PermissionFlow::new(FakeProvider::default());
+        assert_eq!(flow.request(), Ok(None));
+        flow.provider.rationale = true;
+        assert_eq!(flow.on_result(), PermissionStatus::Denied);
+
+        // asking again shows the prompt again
+        assert_eq!(flow.request(), Ok(None));
+        flow.provider.granted = true;
+        assert_eq!(flow.on_result(), PermissionStatus::Granted);
+        assert_eq!(flow.provider.prompts, 2);
+    }
+
+    #[test]
+    fn permanently_denied() {
+        let mut flow = PermissionFlow::new(FakeProvider::default());
+        assert_eq!(flow.request(), Ok(None));
+        assert_eq!(flow.on_result(), PermissionStatus::PermanentlyDenied);
+
+        // the prompt would not show, answer right away
+        assert_eq!(
+            flow.request(),
+            Ok(Some(PermissionStatus::PermanentlyDenied))
+        );
+        assert_eq!(flow.provider.prompts, 1);
+    }
+
+    #[test]
+    fn request_while_pending() {
+        let mut flow = PermissionFlow::new(FakeProvider::default());
+        assert_eq!(flow.request(), Ok(None));
+        assert_eq!(flow.request(), Err(BluetoothError::OperationInProgress));
+        assert_eq!(flow.provider.prompts, 1);
+
+        flow.provider.granted = true;
+        assert_eq!(flow.on_result(), PermissionStatus::Granted);
+        assert_eq!(flow.request(), Ok(Some(PermissionStatus::Granted)));
+    }
+}
@@ -0,0 +1,241 @@
+//! Per-connection GATT operation queue.
+//!
+//! Android fails any operation issued while another one is outstanding and
+//! iOS drops writes without response once the link is out of buffers, so
+//! every backend funnels operations through this queue and starts the next
+//! one only once the platform is ready for it.
+
+use crate::gatt::WriteType;
+use crate::timeout;
+use crate::{BluetoothError, Characteristic};
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Operations queued by default before `BluetoothError::QueueFull`.
+pub const DEFAULT_QUEUE_LIMIT: usize = 64;
+
+#[derive(Clone)]
+pub(crate) enum Operation {
+    Read(Characteristic),
+    Write(Characteristic, Vec<u8>, WriteType),
+    /// Enable or disable notifications, or indications when `indicate` is set.
+    Subscribe {
+        characteristic: Characteristic,
+        enable: bool,
+        // CoreBluetooth picks notifications or indications by the properties,
+        // there it only tells the reconnect which one to restore
+        indicate: bool,
+    },
+    ReadRssi,
+    // CoreBluetooth negotiates the MTU on its own and has no reliable writes
+    #[cfg(not(any(target_os = "ios", target_os = "macos")))]
+    RequestMtu(usize),
+    #[cfg(not(any(target_os = "ios", target_os = "macos")))]
+    BeginReliableWrite,
+    #[cfg(not(any(target_os = "ios", target_os = "macos")))]
+    ExecuteReliableWrite,
+    #[cfg(not(any(target_os = "ios", target_os = "macos")))]
+    AbortReliableWrite,
+}
+
+impl Operation {
+    #[cfg(not(any(target_os = "android", target_os = "ios", target_os = "macos")))]
+    pub fn is_write_without_response(&self) -> bool {
+        matches!(self, Operation::Write(_, _, WriteType::WithoutResponse))
+    }
+
+    /// The characteristic the operation works on, if any.
+    pub fn characteristic(&self) -> Option<&Characteristic> {
+        match self {
+            Operation::Read(characteristic)
+            | Operation::Write(characteristic, _, _)
+            | Operation::Subscribe { characteristic, .. } => Some(characteristic),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct OperationQueue {
+    pending: VecDeque<Operation>,
+    /// Operation started and its completion not reported yet.
+    in_flight: Option<Operation>,
+    pub limit: usize,
+    // when the operation in flight was started
+    started: Option<Instant>,
+}
+
+impl OperationQueue {
+    pub fn new() -> OperationQueue {
+        OperationQueue {
+            pending: VecDeque::new(),
+            in_flight: None,
+            limit: DEFAULT_QUEUE_LIMIT,
+            started: None,
+        }
+    }
+
+    pub fn push(&mut self, operation: Operation) -> Result<(), BluetoothError> {
+        if self.depth() >= self.limit {
+            return Err(BluetoothError::QueueFull);
+        }
+        self.pending.push_back(operation);
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "android"))]
+    pub fn front(&self) -> Option<&Operation> {
+        self.pending.front()
+    }
+
+    /// Next operation to run, `None` while one is in flight.
+    pub fn start(&mut self) -> Option<Operation> {
+        if self.in_flight.is_some() {
+            return None;
+        }
+        let operation = self.pending.pop_front();
+        self.in_flight = operation.clone();
+        self.started = operation.as_ref().map(|_| Instant::now());
+        operation
+    }
+
+    #[cfg(any(target_os = "ios", target_os = "macos"))]
+    pub fn in_flight(&self) -> Option<&Operation> {
+        self.in_flight.as_ref()
+    }
+
+    pub fn complete(&mut self) {
+        self.in_flight = None;
+        self.started = None;
+    }
+
+    /// The operation in flight is running for longer than `timeout`.
+    pub fn overdue(&self, timeout: Option<Duration>) -> bool {
+        self.started
+            .is_some_and(|started| timeout::expired(started, timeout, Instant::now()))
+    }
+
+    /// An RSSI read is queued and not started yet.
+    pub fn rssi_queued(&self) -> bool {
+        self.pending
+            .iter()
+            .any(|operation| matches!(operation, Operation::ReadRssi))
+    }
+
+    /// Drop the queued operations on characteristics, their handles are stale
+    /// once the services changed. Returns how many were dropped.
+    pub fn drop_characteristic_operations(&mut self) -> usize {
+        let queued = self.pending.len();
+        self.pending
+            .retain(|operation| operation.characteristic().is_none());
+        queued - self.pending.len()
+    }
+
+    /// Operations queued or in flight.
+    pub fn depth(&self) -> usize {
+        self.pending.len() + self.in_flight.is_some() as usize
+    }
+
+    /// Drop everything, the connection is gone.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.in_flight = None;
+        self.started = None;
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "ios", target_os = "macos"))))]
+mod tests {
+    use super::*;
+
+    fn mtu(operation: Option<Operation>) -> Option<usize> {
+        match operation {
+            Some(Operation::RequestMtu(mtu)) => Some(mtu),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn limit() {
+        let mut queue = OperationQueue::new();
+        queue.limit = 2;
+        queue.push(Operation::RequestMtu(23)).unwrap();
+        queue.push(Operation::RequestMtu(24)).unwrap();
+        assert_eq!(
+            queue.push(Operation::ReadRssi).err(),
+            Some(BluetoothError::QueueFull)
+        );
+
+        // the operation in flight still counts
+        queue.start().unwrap();
+        assert_eq!(queue.depth(), 2);
+        assert!(queue.push(Operation::ReadRssi).is_err());
+        queue.complete();
+        assert_eq!(queue.depth(), 1);
+        queue.push(Operation::ReadRssi).unwrap();
+    }
+
+    #[test]
+    fn fifo() {
+        let mut queue = OperationQueue::new();
+        for value in [23, 185, 247] {
+            queue.push(Operation::RequestMtu(value)).unwrap();
+        }
+        let mut started = vec![];
+        while let Some(value) = mtu(queue.start()) {
+            started.push(value);
+            queue.complete();
+        }
+        assert_eq!(started, [23, 185, 247]);
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[test]
+    fn one_in_flight() {
+        let mut queue = OperationQueue::new();
+        queue.push(Operation::RequestMtu(23)).unwrap();
+        queue.push(Operation::ReadRssi).unwrap();
+        assert!(queue.rssi_queued());
+
+        assert_eq!(mtu(queue.start()), Some(23));
+        assert!(queue.start().is_none());
+        assert_eq!(queue.depth(), 2);
+
+        queue.complete();
+        assert!(matches!(queue.start(), Some(Operation::ReadRssi)));
+        assert!(!queue.rssi_queued());
+        queue.complete();
+        assert!(queue.start().is_none());
+    }
+
+    #[test]
+    fn clear() {
+        let mut queue = OperationQueue::new();
+        queue.push(Operation::RequestMtu(23)).unwrap();
+        queue.push(Operation::ReadRssi).unwrap();
+        queue.start().unwrap();
+
+        queue.clear();
+        assert_eq!(queue.depth(), 0);
+        assert!(!queue.overdue(Some(Duration::ZERO)));
+        assert!(queue.start().is_none());
+
+        queue.push(Operation::RequestMtu(185)).unwrap();
+        assert_eq!(mtu(queue.start()), Some(185));
+    }
+
+    #[test]
+    fn overdue() {
+        let mut queue = OperationQueue::new();
+        queue.push(Operation::ReadRssi).unwrap();
+        assert!(!queue.overdue(Some(Duration::ZERO)));
+
+        queue.start().unwrap();
+        assert!(queue.overdue(Some(Duration::ZERO)));
+        assert!(!queue.overdue(Some(Duration::from_secs(60))));
+        assert!(!queue.overdue(None));
+
+        queue.complete();
+        assert!(!queue.overdue(Some(Duration::ZERO)));
+    }
+}
@@ -0,0 +1,347 @@
+//! Opt-in automatic reconnection, see `Connection::set_reconnect_policy`.
+//!
+//! Backends feed their connection messages through a `Reconnector`: it hides
+//! unexpected disconnects, retries with exponential backoff and enables the
+//! notifications and indications again once the services are rediscovered.
+
+use crate::peripheral::uuid_eq;
+use crate::{device_seen_since, reconnect_device};
+use crate::{BluetoothError, Characteristic, DeviceId, Message};
+
+use std::collections::VecDeque;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReconnectPolicy {
+    /// Delay before the first attempt, doubled for every following one.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Give up after this many attempts, `None` retries forever.
+    pub max_attempts: Option<u32>,
+    /// Only attempt once a running scan saw the device again.
+    /// On iOS a pending connect waits for the device anyway, this is ignored.
+    pub wait_for_advertisement: bool,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_attempts: Some(5),
+            wait_for_advertisement: false,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Backoff before the given attempt, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+struct Attempt {
+    number: u32,
+    due: Instant,
+    // the device has to be seen after this to reconnect, see `wait_for_advertisement`
+    lost: Instant,
+    started: bool,
+}
+
+pub(crate) struct Reconnector {
+    policy: ReconnectPolicy,
+    // the link was up once, failures before that are not retried
+    connected: bool,
+    // characteristics with notifications or indications enabled,
+    // and `true` for indications
+    subscriptions: Vec<(String, bool)>,
+    rediscovered: Vec<Characteristic>,
+    attempt: Option<Attempt>,
+    outbox: VecDeque<Message>,
+}
+
+impl Reconnector {
+    pub(crate) fn new(policy: ReconnectPolicy, connected: bool) -> Reconnector {
+        Reconnector {
+            policy,
+            connected,
+            subscriptions: vec![],
+            rediscovered: vec![],
+            attempt: None,
+            outbox: VecDeque::new(),
+        }
+    }
+
+    /// `Connection::try_recv` with a policy set.
+    pub(crate) fn try_recv(
+        &mut self,
+        device_id: &DeviceId,
+        rx: &Receiver<Message>,
+    ) -> Result<Option<Message>, BluetoothError> {
+        self.start_attempt(device_id);
+
+        loop {
+            if let Some(message) = self.outbox.pop_front() {
+                return Ok(Some(message));
+            }
+            let message = match rx.try_recv() {
+                Ok(message) => message,
+                Err(_) => return Ok(None),
+            };
+            if let Some(message) = self.filter(message) {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    /// Drop the attempt waiting for its turn, `message` is reported in its place.
+    /// `false` if there was none.
+    pub(crate) fn stop(&mut self, message: Message) -> bool {
+        if self.attempt.take().is_none() {
+            return false;
+        }
+        self.outbox.push_back(message);
+        true
+    }
+
+    fn start_attempt(&mut self, device_id: &DeviceId) {
+        let attempt = match self.attempt {
+            Some(ref mut attempt) if !attempt.started && attempt.due <= Instant::now() => attempt,
+            _ => return,
+        };
+        if self.policy.wait_for_advertisement && !device_seen_since(device_id, attempt.lost) {
+            return;
+        }
+
+        attempt.started = true;
+        if let Err(err) = reconnect_device(device_id) {
+            let message = self.retry(err);
+            self.outbox.push_back(message);
+        }
+    }
+
+    /// `Message::Reconnecting` for the next attempt, the final
+    /// `Message::Disconnected` if there are no attempts left.
+    fn retry(&mut self, reason: BluetoothError) -> Message {
+        let (number, lost) = self
+            .attempt
+            .as_ref()
+            .map_or((1, Instant::now()), |attempt| {
+                (attempt.number + 1, attempt.lost)
+            });
+
+        if self.policy.max_attempts.is_some_and(|max| number > max) {
+            self.attempt = None;
+            return Message::Disconnected(Some(reason));
+        }
+        self.attempt = Some(Attempt {
+            number,
+            due: Instant::now() + self.policy.delay(number),
+            lost,
+            started: false,
+        });
+        Message::Reconnecting {
+            attempt: number,
+            reason,
+        }
+    }
+
+    fn filter(&mut self, message: Message) -> Option<Message> {
+        match message {
+            Message::Connected => self.connected = true,
+            Message::SubscriptionChanged {
+                ref characteristic,
+                enabled,
+                indication,
+            } => {
+                self.subscriptions
+                    .retain(|(id, _)| !uuid_eq(id, characteristic));
+                if enabled {
+                    self.subscriptions
+                        .push((characteristic.clone(), indication));
+                }
+            }
+            Message::CharacteristicDiscovered(ref characteristic)
+                if self.attempt.is_some()
+                    && self
+                        .subscriptions
+                        .iter()
+                        .any(|(id, _)| uuid_eq(id, &characteristic.id)) =>
+            {
+                self.rediscovered.push(characteristic.clone());
+            }
+            Message::ServicesDiscovered if self.attempt.take().is_some() => {
+                for characteristic in self.rediscovered.drain(..) {
+                    let indication = self
+                        .subscriptions
+                        .iter()
+                        .any(|(id, indication)| *indication && uuid_eq(id, &characteristic.id));
+                    let restored = if indication {
+                        characteristic.set_indication(true)
+                    } else {
+                        characteristic.set_notification(true)
+                    };
+                    if let Err(err) = restored {
+                        self.outbox.push_back(Message::Error(err));
+                    }
+                }
+                self.outbox.push_back(Message::Reconnected);
+            }
+            Message::ConnectFailed(BluetoothError::Cancelled) => self.attempt = None,
+            Message::Disconnected(Some(reason)) | Message::ConnectFailed(reason)
+                if self.connected =>
+            {
+                self.rediscovered.clear();
+                return Some(self.retry(reason));
+            }
+            Message::Disconnected(None) => self.attempt = None,
+            _ => {}
+        }
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reconnector(max_attempts: Option<u32>) -> Reconnector {
+        let policy = ReconnectPolicy {
+            max_attempts,
+            ..Default::default()
+        };
+        Reconnector::new(policy, true)
+    }
+
+    fn lost() -> Message {
+        Message::Disconnected(Some(BluetoothError::Timeout))
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let policy = ReconnectPolicy::default();
+        let delays: Vec<u64> = (1..=5).map(|n| policy.delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16]);
+        assert_eq!(policy.delay(0), policy.initial_delay);
+    }
+
+    #[test]
+    fn backoff_capped() {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.delay(6), policy.max_delay);
+        assert_eq!(policy.delay(40), policy.max_delay);
+        assert_eq!(policy.delay(u32::MAX), policy.max_delay);
+
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::MAX,
+            max_delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(2), policy.max_delay);
+    }
+
+    #[test]
+    fn retries_until_max_attempts() {
+        let mut reconnector = reconnector(Some(2));
+        assert!(matches!(
+            reconnector.filter(lost()),
+            Some(Message::Reconnecting { attempt: 1, .. })
+        ));
+        assert!(matches!(
+            reconnector.filter(Message::ConnectFailed(BluetoothError::Timeout)),
+            Some(Message::Reconnecting { attempt: 2, .. })
+        ));
+        assert!(matches!(
+            reconnector.filter(Message::ConnectFailed(BluetoothError::Timeout)),
+            Some(Message::Disconnected(Some(BluetoothError::Timeout)))
+        ));
+        assert!(reconnector.attempt.is_none());
+    }
+
+    #[test]
+    fn retries_forever_without_max_attempts() {
+        let mut reconnector = reconnector(None);
+        for number in 1..=20 {
+            let message = reconnector.filter(lost());
+            assert!(
+                matches!(message, Some(Message::Reconnecting { attempt, .. }) if attempt == number)
+            );
+        }
+    }
+
+    #[test]
+    fn first_connect_not_retried() {
+        let mut reconnector = Reconnector::new(ReconnectPolicy::default(), false);
+        assert!(matches!(
+            reconnector.filter(Message::ConnectFailed(BluetoothError::Timeout)),
+            Some(Message::ConnectFailed(_))
+        ));
+        assert!(reconnector.attempt.is_none());
+
+        reconnector.filter(Message::Connected);
+        assert!(matches!(
+            reconnector.filter(lost()),
+            Some(Message::Reconnecting { attempt: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn stop() {
+        let mut reconnector = reconnector(Some(5));
+        assert!(!reconnector.stop(Message::Disconnected(None)));
+        assert!(reconnector.outbox.is_empty());
+
+        reconnector.filter(lost());
+        assert!(reconnector.stop(Message::Disconnected(None)));
+        assert!(reconnector.attempt.is_none());
+        assert!(matches!(
+            reconnector.outbox.pop_front(),
+            Some(Message::Disconnected(None))
+        ));
+        assert!(!reconnector.stop(Message::Disconnected(None)));
+
+        // the next loss starts counting from the first attempt again
+        assert!(matches!(
+            reconnector.filter(lost()),
+            Some(Message::Reconnecting { attempt: 1, .. })
+        ));
+        reconnector.filter(Message::ConnectFailed(BluetoothError::Cancelled));
+        assert!(reconnector.attempt.is_none());
+    }
+
+    #[test]
+    fn subscription_mode_recorded() {
+        let mut reconnector = reconnector(Some(5));
+        for (characteristic, indication) in [("2a37", false), ("2a35", true)] {
+            reconnector.filter(Message::SubscriptionChanged {
+                characteristic: characteristic.to_string(),
+                enabled: true,
+                indication,
+            });
+        }
+        reconnector.filter(Message::SubscriptionChanged {
+            characteristic: "2a37".to_string(),
+            enabled: true,
+            indication: true,
+        });
+        assert_eq!(
+            reconnector.subscriptions,
+            [("2a35".to_string(), true), ("2a37".to_string(), true)]
+        );
+
+        reconnector.filter(Message::SubscriptionChanged {
+            characteristic: "2a35".to_string(),
+            enabled: false,
+            indication: true,
+        });
+        assert_eq!(reconnector.subscriptions, [("2a37".to_string(), true)]);
+    }
+}
@@ -0,0 +1,335 @@
+//! Devices the app knows from earlier sessions, kept in a file.
+//!
+//! Nothing here talks to Bluetooth: the app tells the registry what it
+//! connected to and reads back favourites and the last used device, e.g. to
+//! `Adapter::connect` on startup.
+
+use crate::peripheral::uuid_eq;
+use crate::DeviceId;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// First line of the file, bumped when the format changes.
+const HEADER: &str = "quad-bt registry 1";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KnownDevice {
+    pub id: DeviceId,
+    /// Name the device advertised, see `Adapter::get_device_name`.
+    pub name: Option<String>,
+    /// Name given by the user, shown instead of `name`.
+    pub alias: Option<String>,
+    pub favourite: bool,
+    /// Service UUIDs found on the last connection.
+    pub services: Vec<String>,
+    pub last_connected: Option<SystemTime>,
+}
+
+impl KnownDevice {
+    fn new(id: DeviceId) -> KnownDevice {
+        KnownDevice {
+            id,
+            name: None,
+            alias: None,
+            favourite: false,
+            services: vec![],
+            last_connected: None,
+        }
+    }
+
+    /// The alias, or the advertised name.
+    pub fn display_name(&self) -> Option<&str> {
+        self.alias.as_deref().or(self.name.as_deref())
+    }
+
+    pub fn has_service(&self, uuid: &str) -> bool {
+        self.services.iter().any(|service| uuid_eq(service, uuid))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DeviceRegistry {
+    // where `save` writes to, `None` for a registry kept in memory only
+    path: Option<PathBuf>,
+    devices: Vec<KnownDevice>,
+}
+
+impl DeviceRegistry {
+    /// A registry kept in memory only, `save` does nothing.
+    pub fn new() -> DeviceRegistry {
+        DeviceRegistry::default()
+    }
+
+    /// Load the registry from a file in the app's data directory,
+    /// empty if the file does not exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<DeviceRegistry> {
+        let path = path.as_ref().to_path_buf();
+        let devices = match fs::read_to_string(&path) {
+            Ok(text) => parse(&text)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        Ok(DeviceRegistry {
+            path: Some(path),
+            devices,
+        })
+    }
+
+    /// Write the registry back to its file. Written to a temporary file
+    /// first, a crash never leaves a truncated registry behind.
+    pub fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, format(&self.devices))?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn devices(&self) -> &[KnownDevice] {
+        &self.devices
+    }
+
+    pub fn get(&self, id: &DeviceId) -> Option<&KnownDevice> {
+        self.devices.iter().find(|device| device.id == *id)
+    }
+
+    fn get_or_insert(&mut self, id: &DeviceId) -> &mut KnownDevice {
+        match self.devices.iter().position(|device| device.id == *id) {
+            Some(i) => &mut self.devices[i],
+            None => {
+                self.devices.push(KnownDevice::new(id.clone()));
+                self.devices.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Add the device, or update its name. `None` keeps a name known before.
+    pub fn remember(&mut self, id: &DeviceId, name: Option<String>) -> &mut KnownDevice {
+        let device = self.get_or_insert(id);
+        if name.is_some() {
+            device.name = name;
+        }
+        device
+    }
+
+    /// Record a connection, typically on `Message::ServicesDiscovered`
+    /// with the services of the discovered characteristics.
+    pub fn connected(&mut self, id: &DeviceId, services: Vec<String>) {
+        let device = self.get_or_insert(id);
+        device.services = services;
+        device.last_connected = Some(SystemTime::now());
+    }
+
+    pub fn forget(&mut self, id: &DeviceId) {
+        self.devices.retain(|device| device.id != *id);
+    }
+
+    pub fn set_alias(&mut self, id: &DeviceId, alias: Option<String>) {
+        self.get_or_insert(id).alias = alias;
+    }
+
+    pub fn set_favourite(&mut self, id: &DeviceId, favourite: bool) {
+        self.get_or_insert(id).favourite = favourite;
+    }
+
+    pub fn favourites(&self) -> impl Iterator<Item = &KnownDevice> {
+        self.devices.iter().filter(|device| device.favourite)
+    }
+
+    /// The device connected to most recently.
+    pub fn last_used(&self) -> Option<&KnownDevice> {
+        self.devices
+            .iter()
+            .filter(|device| device.last_connected.is_some())
+            .max_by_key(|device| device.last_connected)
+    }
+}
+
+// One device per line, tab separated:
+// id, name, alias, favourite, last connected (milliseconds since the epoch), services.
+// Missing values are empty fields, services are separated by commas.
+fn format(devices: &[KnownDevice]) -> String {
+    let mut text = format!("{}\n", HEADER);
+    for device in devices {
+        let last_connected = device
+            .last_connected
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(String::new(), |since| since.as_millis().to_string());
+        let fields = [
+            escape(device.id.as_str()),
+            device.name.as_deref().map_or(String::new(), escape),
+            device.alias.as_deref().map_or(String::new(), escape),
+            (device.favourite as u8).to_string(),
+            last_connected,
+            escape(&device.services.join(",")),
+        ];
+        text.push_str(&fields.join("\t"));
+        text.push('\n');
+    }
+    text
+}
+
+fn parse(text: &str) -> io::Result<Vec<KnownDevice>> {
+    let invalid = |line: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid device registry line: {:?}", line),
+        )
+    };
+
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a device registry",
+        ));
+    }
+
+    let mut devices = vec![];
+    for line in lines.filter(|line| !line.is_empty()) {
+        let fields: Vec<String> = line.split('\t').map(unescape).collect();
+        let [id, name, alias, favourite, last_connected, services] = &fields[..] else {
+            return Err(invalid(line));
+        };
+        let last_connected = match last_connected.as_str() {
+            "" => None,
+            millis => {
+                Some(UNIX_EPOCH + Duration::from_millis(millis.parse().map_err(|_| invalid(line))?))
+            }
+        };
+        let optional = |field: &String| (!field.is_empty()).then(|| field.clone());
+
+        devices.push(KnownDevice {
+            id: DeviceId::new(id),
+            name: optional(name),
+            alias: optional(alias),
+            favourite: favourite == "1",
+            services: services
+                .split(',')
+                .filter(|uuid| !uuid.is_empty())
+                .map(str::to_string)
+                .collect(),
+            last_connected,
+        });
+    }
+    Ok(devices)
+}
+
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(field: &str) -> String {
+    let mut res = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => res.push('\t'),
+            Some('n') => res.push('\n'),
+            Some('r') => res.push('\r'),
+            Some(other) => res.push(other),
+            None => {}
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A registry file of its own for each test, removed again on drop.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let name = format!("quad-bt-{}-{}", std::process::id(), name);
+            TempFile(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let file = TempFile::new("save_and_load");
+        let (first, second) = (DeviceId::new("AA:BB"), DeviceId::new("CC:DD"));
+
+        let mut registry = DeviceRegistry::open(&file.0).unwrap();
+        assert!(registry.devices().is_empty());
+        registry.remember(&first, Some("Sensor\tone".to_string()));
+        registry.set_alias(&first, Some("kitchen\\hall\nupstairs\r".to_string()));
+        registry.set_favourite(&first, true);
+        registry.connected(&first, vec!["180f".to_string(), "180a".to_string()]);
+        registry.remember(&second, None);
+        registry.set_alias(&second, Some("\\t is not a tab\\".to_string()));
+        registry.save().unwrap();
+
+        let mut tmp = file.0.clone().into_os_string();
+        tmp.push(".tmp");
+        assert!(!Path::new(&tmp).exists());
+
+        let loaded = DeviceRegistry::open(&file.0).unwrap();
+        let mut expected = registry.devices().to_vec();
+        // stored with millisecond precision
+        let millis = expected[0]
+            .last_connected
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        expected[0].last_connected = Some(UNIX_EPOCH + Duration::from_millis(millis as u64));
+        assert_eq!(loaded.devices(), expected.as_slice());
+        assert_eq!(loaded.last_used().unwrap().id, first);
+        assert!(loaded
+            .get(&first)
+            .unwrap()
+            .has_service("0000180A-0000-1000-8000-00805F9B34FB"));
+    }
+
+    #[test]
+    fn save_replaces_file() {
+        let file = TempFile::new("save_replaces_file");
+        let id = DeviceId::new("AA:BB");
+
+        let mut registry = DeviceRegistry::open(&file.0).unwrap();
+        registry.remember(&id, Some("Sensor".to_string()));
+        registry.save().unwrap();
+        registry.forget(&id);
+        registry.save().unwrap();
+
+        assert!(DeviceRegistry::open(&file.0).unwrap().devices().is_empty());
+    }
+
+    #[test]
+    fn invalid_file() {
+        let file = TempFile::new("invalid_file");
+
+        fs::write(&file.0, "something else\n").unwrap();
+        let err = DeviceRegistry::open(&file.0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        fs::write(&file.0, format!("{}\nAA:BB\tSensor\n", HEADER)).unwrap();
+        let err = DeviceRegistry::open(&file.0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
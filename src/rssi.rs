@@ -0,0 +1,127 @@
+//! Periodic RSSI reads, see `Connection::set_rssi_interval`.
+//!
+//! No platform reports the RSSI of a connection on its own, backends check
+//! the monitor on every `Connection::try_recv` and read it when due.
+//! Single readings jump by several dBm, the monitor keeps a moving average.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Readings averaged by `Connection::average_rssi`.
+pub const RSSI_WINDOW: usize = 8;
+
+pub(crate) struct RssiMonitor {
+    interval: Duration,
+    next: Instant,
+    // the last `RSSI_WINDOW` readings, oldest first
+    readings: VecDeque<i16>,
+}
+
+impl RssiMonitor {
+    /// The first read is due right away.
+    pub fn new(interval: Duration) -> RssiMonitor {
+        RssiMonitor {
+            interval,
+            next: Instant::now(),
+            readings: VecDeque::with_capacity(RSSI_WINDOW),
+        }
+    }
+
+    /// True once per interval, missed periods are not caught up.
+    pub fn due(&mut self) -> bool {
+        self.due_at(Instant::now())
+    }
+
+    fn due_at(&mut self, now: Instant) -> bool {
+        if now < self.next {
+            return false;
+        }
+        self.next = now + self.interval;
+        true
+    }
+
+    pub fn record(&mut self, rssi: i16) {
+        if self.readings.len() == RSSI_WINDOW {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(rssi);
+    }
+
+    /// Mean of the readings in the window, rounded to the nearest dBm.
+    pub fn average(&self) -> Option<i16> {
+        if self.readings.is_empty() {
+            return None;
+        }
+        let sum: i32 = self.readings.iter().map(|&rssi| rssi as i32).sum();
+        Some((sum as f32 / self.readings.len() as f32).round() as i16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_read_due_right_away() {
+        let mut monitor = RssiMonitor::new(Duration::from_secs(1));
+        assert!(monitor.due());
+        assert!(!monitor.due());
+    }
+
+    #[test]
+    fn once_per_interval() {
+        let interval = Duration::from_secs(2);
+        let mut monitor = RssiMonitor::new(interval);
+        let start = monitor.next;
+        assert!(monitor.due_at(start));
+        assert!(!monitor.due_at(start + interval / 2));
+        assert!(!monitor.due_at(start + interval - Duration::from_millis(1)));
+        assert!(monitor.due_at(start + interval));
+        assert!(!monitor.due_at(start + interval));
+    }
+
+    #[test]
+    fn missed_periods_not_caught_up() {
+        let interval = Duration::from_secs(1);
+        let mut monitor = RssiMonitor::new(interval);
+        let start = monitor.next;
+        assert!(monitor.due_at(start));
+
+        let late = start + interval * 10;
+        assert!(monitor.due_at(late));
+        assert!(!monitor.due_at(late + interval / 2));
+        assert!(monitor.due_at(late + interval));
+    }
+
+    #[test]
+    fn average() {
+        let mut monitor = RssiMonitor::new(Duration::from_secs(1));
+        assert_eq!(monitor.average(), None);
+        monitor.record(-60);
+        assert_eq!(monitor.average(), Some(-60));
+        monitor.record(-65);
+        // -62.5 rounds away from zero
+        assert_eq!(monitor.average(), Some(-63));
+        monitor.record(-70);
+        assert_eq!(monitor.average(), Some(-65));
+    }
+
+    #[test]
+    fn average_window() {
+        let mut monitor = RssiMonitor::new(Duration::from_secs(1));
+        for _ in 0..RSSI_WINDOW {
+            monitor.record(-90);
+        }
+        assert_eq!(monitor.average(), Some(-90));
+
+        // every reading pushes out the oldest
+        for n in 1..=RSSI_WINDOW {
+            monitor.record(-50);
+            let expected = (-90 * (RSSI_WINDOW - n) as i32 - 50 * n as i32) as f32;
+            let expected = (expected / RSSI_WINDOW as f32).round() as i16;
+            assert_eq!(monitor.average(), Some(expected));
+        }
+        assert_eq!(monitor.readings.len(), RSSI_WINDOW);
+        assert_eq!(monitor.average(), Some(-50));
+    }
+}
@@ -0,0 +1,236 @@
+//! Connection lifecycle shared by all the backends.
+//!
+//! Backends drive the state machine from their callbacks, operations check it
+//! before touching the platform so they fail early in the wrong state.
+
+use crate::BluetoothError;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConnectionState {
+    /// `Adapter::connect` was called, the link is not up yet.
+    Connecting,
+    /// The link is up, service discovery is about to start.
+    Connected,
+    DiscoveringServices,
+    /// Services are discovered, GATT operations are allowed.
+    Ready,
+    /// `Connection::disconnect` was called, the link is not down yet.
+    Disconnecting,
+    /// `None` after `Connection::disconnect` or before connecting, the reason otherwise.
+    Disconnected(Option<BluetoothError>),
+}
+
+pub(crate) enum StateEvent {
+    Connect,
+    Connected,
+    DiscoveryStarted,
+    ServicesDiscovered,
+    Disconnect,
+    Disconnected(Option<BluetoothError>),
+}
+
+impl Default for ConnectionState {
+    fn default() -> ConnectionState {
+        ConnectionState::Disconnected(None)
+    }
+}
+
+impl ConnectionState {
+    pub fn is_ready(&self) -> bool {
+        *self == ConnectionState::Ready
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, ConnectionState::Disconnected(_))
+    }
+
+    /// `Adapter::connect` was called and the connection is not ready yet.
+    pub fn is_connecting(&self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Connecting
+                | ConnectionState::Connected
+                | ConnectionState::DiscoveringServices
+        )
+    }
+
+    /// The link is up or on its way.
+    pub fn is_active(&self) -> bool {
+        !matches!(
+            self,
+            ConnectionState::Disconnecting | ConnectionState::Disconnected(_)
+        )
+    }
+
+    /// GATT operations are only allowed once services are discovered.
+    pub(crate) fn require_ready(&self) -> Result<(), BluetoothError> {
+        match self {
+            ConnectionState::Ready => Ok(()),
+            ConnectionState::Disconnected(_) => Err(BluetoothError::DeviceDisconnected),
+            _ => Err(BluetoothError::InvalidState),
+        }
+    }
+
+    /// Apply an event, `BluetoothError::InvalidState` if it is not allowed
+    /// in the current state. A disconnect is allowed from any state.
+    pub(crate) fn transition(&mut self, event: StateEvent) -> Result<(), BluetoothError> {
+        use ConnectionState::*;
+
+        let next = match (&*self, event) {
+            (Disconnected(_), StateEvent::Connect) => Connecting,
+            (Connecting, StateEvent::Connected) => Connected,
+            (Connected | Ready, StateEvent::DiscoveryStarted) => DiscoveringServices,
+            (DiscoveringServices, StateEvent::ServicesDiscovered) => Ready,
+            (Connecting | Connected | DiscoveringServices | Ready, StateEvent::Disconnect) => {
+                Disconnecting
+            }
+            (_, StateEvent::Disconnected(reason)) => Disconnected(reason),
+            _ => return Err(BluetoothError::InvalidState),
+        };
+        *self = next;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_transitions() {
+        let mut state = ConnectionState::default();
+        for (event, next) in [
+            (StateEvent::Connect, ConnectionState::Connecting),
+            (StateEvent::Connected, ConnectionState::Connected),
+            (
+                StateEvent::DiscoveryStarted,
+                ConnectionState::DiscoveringServices,
+            ),
+            (StateEvent::ServicesDiscovered, ConnectionState::Ready),
+            // services changed, discovery runs again
+            (
+                StateEvent::DiscoveryStarted,
+                ConnectionState::DiscoveringServices,
+            ),
+            (StateEvent::ServicesDiscovered, ConnectionState::Ready),
+            (StateEvent::Disconnect, ConnectionState::Disconnecting),
+            (
+                StateEvent::Disconnected(None),
+                ConnectionState::Disconnected(None),
+            ),
+            (StateEvent::Connect, ConnectionState::Connecting),
+        ] {
+            state.transition(event).unwrap();
+            assert_eq!(state, next);
+        }
+    }
+
+    #[test]
+    fn invalid_transitions() {
+        let invalid = [
+            (ConnectionState::default(), StateEvent::Connected),
+            (ConnectionState::default(), StateEvent::ServicesDiscovered),
+            (ConnectionState::default(), StateEvent::Disconnect),
+            (ConnectionState::Connecting, StateEvent::Connect),
+            (ConnectionState::Connecting, StateEvent::ServicesDiscovered),
+            (ConnectionState::Connected, StateEvent::ServicesDiscovered),
+            (ConnectionState::Ready, StateEvent::Connect),
+            (ConnectionState::Ready, StateEvent::Connected),
+            (ConnectionState::Disconnecting, StateEvent::Connect),
+            (ConnectionState::Disconnecting, StateEvent::Disconnect),
+        ];
+        for (state, event) in invalid {
+            let mut next = state.clone();
+            assert_eq!(next.transition(event), Err(BluetoothError::InvalidState));
+            assert_eq!(next, state);
+        }
+    }
+
+    #[test]
+    fn disconnected_from_any_state() {
+        for state in [
+            ConnectionState::Connecting,
+            ConnectionState::Connected,
+            ConnectionState::DiscoveringServices,
+            ConnectionState::Ready,
+            ConnectionState::Disconnecting,
+        ] {
+            let mut state = state;
+            let reason = Some(BluetoothError::Timeout);
+            state
+                .transition(StateEvent::Disconnected(reason.clone()))
+                .unwrap();
+            assert_eq!(state, ConnectionState::Disconnected(reason));
+            assert_eq!(
+                state.require_ready(),
+                Err(BluetoothError::DeviceDisconnected)
+            );
+        }
+    }
+
+    #[test]
+    fn require_ready() {
+        assert_eq!(ConnectionState::Ready.require_ready(), Ok(()));
+        assert_eq!(
+            ConnectionState::DiscoveringServices.require_ready(),
+            Err(BluetoothError::InvalidState)
+        );
+        assert!(ConnectionState::Connected.is_connecting());
+        assert!(!ConnectionState::Disconnecting.is_active());
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "ios", target_os = "macos")))]
+    #[test]
+    fn simulated_connection() {
+        use crate::{Adapter, LocalCharacteristic, LocalService, Message};
+
+        let mut adapter = Adapter::new().unwrap();
+        let mut characteristic = LocalCharacteristic::new("2a19");
+        characteristic.read = true;
+        let mut server = adapter
+            .open_gatt_server(vec![LocalService::new("180f", vec![characteristic])])
+            .unwrap();
+        server.start_advertising().unwrap();
+
+        let mut connection = adapter.connect(server.device_id()).unwrap();
+        // the simulated link comes up and is discovered within `connect`
+        assert_eq!(connection.state(), ConnectionState::Ready);
+        assert!(adapter.connect(server.device_id()).is_err());
+
+        let mut messages = vec![];
+        while let Some(message) = connection.try_recv().unwrap() {
+            messages.push(message);
+        }
+        assert!(matches!(messages[0], Message::Connected));
+        assert!(matches!(messages.last(), Some(Message::ServicesDiscovered)));
+        let characteristic = messages
+            .into_iter()
+            .find_map(|message| match message {
+                Message::CharacteristicDiscovered(characteristic) => Some(characteristic),
+                _ => None,
+            })
+            .unwrap();
+
+        connection.disconnect().unwrap();
+        assert_eq!(connection.state(), ConnectionState::Disconnected(None));
+        assert!(matches!(
+            connection.try_recv().unwrap(),
+            Some(Message::Disconnected(None))
+        ));
+        assert_eq!(
+            characteristic.read_value(),
+            Err(BluetoothError::DeviceDisconnected)
+        );
+
+        // a lost link carries the reason
+        let mut connection = adapter.connect(server.device_id()).unwrap();
+        server.simulate_link_loss().unwrap();
+        assert_eq!(
+            connection.state(),
+            ConnectionState::Disconnected(Some(BluetoothError::DeviceDisconnected))
+        );
+        connection.disconnect().unwrap();
+        server.close().unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+//! Connect, discovery and operation timeouts, see `Adapter::set_timeouts`.
+//!
+//! Platforms give up inconsistently, CoreBluetooth never does on a connect,
+//! so backends check these deadlines on every `Connection::try_recv`.
+
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Timeouts {
+    /// From `Adapter::connect` until the link is up.
+    pub connect: Option<Duration>,
+    /// From the link coming up until `Message::ServicesDiscovered`.
+    pub discovery: Option<Duration>,
+    /// A single queued GATT operation. The link is dropped after one timed out,
+    /// the peripheral or the platform stack is stuck at that point.
+    pub operation: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: Some(Duration::from_secs(30)),
+            discovery: Some(Duration::from_secs(30)),
+            operation: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+/// `timeout` ran out between `started` and `now`, never without a timeout.
+pub(crate) fn expired(started: Instant, timeout: Option<Duration>, now: Instant) -> bool {
+    timeout.is_some_and(|timeout| now.saturating_duration_since(started) >= timeout)
+}
+
+/// End of the connect or discovery phase, whichever the connection is in.
+// the simulated backend connects and discovers right away
+#[cfg_attr(
+    not(any(target_os = "android", target_os = "ios", target_os = "macos")),
+    allow(dead_code)
+)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline {
+    started: Instant,
+    timeout: Option<Duration>,
+    /// Discovery again on a link that was ready before, see `rediscover_services`.
+    /// Giving up ends it with `Message::Disconnected` rather than `ConnectFailed`.
+    pub rediscovery: bool,
+}
+
+#[cfg_attr(
+    not(any(target_os = "android", target_os = "ios", target_os = "macos")),
+    allow(dead_code)
+)]
+impl Deadline {
+    pub fn new(timeout: Option<Duration>, rediscovery: bool) -> Deadline {
+        Deadline {
+            started: Instant::now(),
+            timeout,
+            rediscovery,
+        }
+    }
+
+    pub fn expired(&self, now: Instant) -> bool {
+        expired(self.started, self.timeout, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn connect_deadline() {
+        let deadline = Deadline::new(Timeouts::default().connect, false);
+        let end = deadline.started + Duration::from_secs(30);
+        assert!(!deadline.expired(deadline.started));
+        assert!(!deadline.expired(end - Duration::from_millis(1)));
+        assert!(deadline.expired(end));
+        assert!(deadline.expired(end + SECOND));
+        assert!(!deadline.rediscovery);
+    }
+
+    #[test]
+    fn no_deadline() {
+        let deadline = Deadline::new(None, true);
+        assert!(!deadline.expired(deadline.started + Duration::from_secs(3600)));
+        assert!(deadline.rediscovery);
+    }
+
+    #[test]
+    fn operation_overdue() {
+        let started = Instant::now();
+        let timeout = Some(SECOND);
+        assert!(!expired(started, timeout, started));
+        assert!(!expired(started, timeout, started + SECOND / 2));
+        assert!(expired(started, timeout, started + SECOND));
+        assert!(expired(started, timeout, started + SECOND * 2));
+        assert!(!expired(started, None, started + SECOND * 2));
+        // a clock reading from before the start is not past anything
+        assert!(!expired(started + SECOND * 2, timeout, started));
+        assert!(expired(started, Some(Duration::ZERO), started));
+    }
+}
@@ -0,0 +1,190 @@
+//! Nordic UART Service: a byte stream over BLE, the de-facto standard
+//! for serial-over-BLE devices.
+//!
+//! UUIDs are named from the peripheral point of view: the central writes to RX
+//! and receives notifications from TX.
+
+use crate::codec::{self, Codec};
+use crate::gatt::WriteType;
+use crate::peripheral::uuid_eq;
+use crate::{BluetoothError, Characteristic, Connection, Message};
+
+use std::io;
+
+pub const SERVICE_UUID: &str = "6e400001-b5a3-f393-e0a9-e50e24dcca9e";
+pub const RX_UUID: &str = "6e400002-b5a3-f393-e0a9-e50e24dcca9e";
+pub const TX_UUID: &str = "6e400003-b5a3-f393-e0a9-e50e24dcca9e";
+
+/// Wraps a `Connection` to a NUS peripheral.
+/// Polling is non-blocking, `io::Read`/`io::Write` report `WouldBlock`
+/// until the service is discovered or data arrives.
+///
+/// NUS devices only notify on TX, so every `Message::Data` is taken as
+/// received data.
+pub struct NordicUart {
+    connection: Connection,
+    rx: Option<Characteristic>,
+    tx: Option<Characteristic>,
+    received: Vec<u8>,
+    disconnected: bool,
+}
+
+impl NordicUart {
+    pub fn new(connection: Connection) -> NordicUart {
+        NordicUart {
+            connection,
+            rx: None,
+            tx: None,
+            received: vec![],
+            disconnected: false,
+        }
+    }
+
+    /// Process the pending connection messages.
+    /// Called by all the other methods, but should be called every frame
+    /// when nothing else is, to keep the receive buffer up to date.
+    pub fn poll(&mut self) -> Result<(), BluetoothError> {
+        while let Some(message) = self.connection.try_recv()? {
+            match message {
+                Message::CharacteristicDiscovered(characteristic) => {
+                    if uuid_eq(&characteristic.id, RX_UUID) {
+                        self.rx = Some(characteristic);
+                    } else if uuid_eq(&characteristic.id, TX_UUID) {
+                        self.tx = Some(characteristic);
+                    }
+                }
+                // the old handles are gone, discovery reports the new ones
+                Message::ServicesChanged => {
+                    self.rx = None;
+                    self.tx = None;
+                }
+                Message::ServicesDiscovered => {
+                    if let Some(ref tx) = self.tx {
+                        tx.set_notification(true)?;
+                    }
+                }
+                Message::Data(data) => self.received.extend_from_slice(&data),
+                // a failed connect ends the stream just like a lost link
+                Message::Disconnected(_) | Message::ConnectFailed(_) => self.disconnected = true,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Both NUS characteristics are discovered and TX notifications requested.
+    pub fn is_ready(&mut self) -> bool {
+        let _ = self.poll();
+        self.rx.is_some() && self.tx.is_some() && self.connection.state().is_ready()
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    /// Bytes received and not read yet.
+    pub fn available(&mut self) -> usize {
+        let _ = self.poll();
+        self.received.len()
+    }
+
+    /// Send the line followed by `\n`.
+    pub fn write_line(&mut self, line: &str) -> Result<(), BluetoothError> {
+        // newline framing never fails
+        let bytes = codec::Lines.encode(line.as_bytes()).unwrap_or_default();
+        self.send(&bytes)
+    }
+
+    /// Next complete line without its line ending, `None` until one was received.
+    pub fn read_line(&mut self) -> Result<Option<String>, BluetoothError> {
+        self.poll()?;
+
+        // newline framing never fails
+        let line = codec::Lines.decode(&mut self.received).unwrap_or(None);
+        Ok(line.map(|line| String::from_utf8_lossy(&line).into_owned()))
+    }
+
+    /// Queue all of `data`, in as many chunks as the MTU requires. Written
+    /// without response, or with response if RX only declares WRITE.
+    /// Nothing is queued and `BluetoothError::QueueFull` is returned if the
+    /// chunks do not fit into the operation queue.
+    /// Request a larger MTU with `Connection::request_mtu` for fewer writes.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), BluetoothError> {
+        self.poll()?;
+
+        let rx = self.rx.as_ref().ok_or(BluetoothError::DeviceUnavailable)?;
+        let write_type = WriteType::preferred(rx.properties);
+        let max_len = self.connection.max_write_len(write_type);
+        if data.len().div_ceil(max_len) > self.free_writes() {
+            return Err(BluetoothError::QueueFull);
+        }
+        for chunk in data.chunks(max_len) {
+            rx.send_bytes(chunk, write_type == WriteType::WithResponse)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the operation queue takes before it is full.
+    fn free_writes(&self) -> usize {
+        self.connection
+            .queue_limit()
+            .saturating_sub(self.connection.queue_depth())
+    }
+
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+
+    pub fn into_connection(self) -> Connection {
+        self.connection
+    }
+}
+
+fn io_error(err: BluetoothError) -> io::Error {
+    io::Error::other(err)
+}
+
+impl io::Read for NordicUart {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.poll().map_err(io_error)?;
+
+        if self.received.is_empty() {
+            if self.disconnected {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let len = buf.len().min(self.received.len());
+        for (dst, src) in buf.iter_mut().zip(self.received.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl io::Write for NordicUart {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.poll().map_err(io_error)?;
+
+        if self.disconnected {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let write_type = match self.rx {
+            Some(ref rx) => WriteType::preferred(rx.properties),
+            None => return Err(io::ErrorKind::WouldBlock.into()),
+        };
+        // backpressure: take as much as the queue has room for
+        let max_len = self.connection.max_write_len(write_type);
+        let len = buf.len().min(self.free_writes() * max_len);
+        if len == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.send(&buf[..len]).map_err(io_error)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}